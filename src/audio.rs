@@ -0,0 +1,554 @@
+use crate::builder::DeviceBuilder;
+use crate::error::BuilderError;
+use crate::usb::UsbEndpointDescriptor;
+use crate::EndpointInfo;
+use usb_device::endpoint::EndpointType;
+use usb_device::UsbDirection;
+
+pub const USB_CLASS_AUDIO: u8 = 0x01;
+pub const AUDIO_SUBCLASS_CONTROL: u8 = 0x01;
+pub const AUDIO_SUBCLASS_STREAMING: u8 = 0x02;
+pub const AUDIO_SUBCLASS_MIDISTREAMING: u8 = 0x03;
+
+pub(crate) const CS_INTERFACE: u8 = 0x24;
+pub(crate) const CS_ENDPOINT: u8 = 0x25;
+
+const AC_HEADER: u8 = 0x01;
+const AC_INPUT_TERMINAL: u8 = 0x02;
+const AC_OUTPUT_TERMINAL: u8 = 0x03;
+const AC_FEATURE_UNIT: u8 = 0x06;
+const AS_GENERAL: u8 = 0x01;
+const AS_FORMAT_TYPE: u8 = 0x02;
+const EP_GENERAL: u8 = 0x01;
+const FORMAT_TYPE_I: u8 = 0x01;
+const FORMAT_TAG_PCM: u16 = 0x0001;
+
+const ISO_SYNC_ASYNCHRONOUS: u8 = 0b01 << 2;
+const ISO_SYNC_ADAPTIVE: u8 = 0b10 << 2;
+
+/// Terminal types from the USB Audio Terminal Types specification.
+pub mod terminal_type {
+    pub const USB_STREAMING: u16 = 0x0101;
+    pub const MICROPHONE: u16 = 0x0201;
+    pub const DESKTOP_MICROPHONE: u16 = 0x0202;
+    pub const HEADSET_MICROPHONE: u16 = 0x0204;
+    pub const SPEAKER: u16 = 0x0301;
+    pub const HEADPHONES: u16 = 0x0302;
+    pub const DESKTOP_SPEAKER: u16 = 0x0304;
+    pub const HEADSET: u16 = 0x0402;
+    pub const ANALOG_CONNECTOR: u16 = 0x0601;
+    pub const LINE_CONNECTOR: u16 = 0x0603;
+    pub const SPDIF_INTERFACE: u16 = 0x0605;
+}
+
+/// Spatial locations of logical channels, used in `wChannelConfig`.
+pub mod channel {
+    pub const LEFT_FRONT: u16 = 1 << 0;
+    pub const RIGHT_FRONT: u16 = 1 << 1;
+    pub const CENTER_FRONT: u16 = 1 << 2;
+    pub const LOW_FREQUENCY_ENHANCEMENT: u16 = 1 << 3;
+    pub const LEFT_SURROUND: u16 = 1 << 4;
+    pub const RIGHT_SURROUND: u16 = 1 << 5;
+}
+
+/// Feature unit controls, used in `bmaControls`.
+pub mod feature_control {
+    pub const MUTE: u16 = 1 << 0;
+    pub const VOLUME: u16 = 1 << 1;
+    pub const BASS: u16 = 1 << 2;
+    pub const MID: u16 = 1 << 3;
+    pub const TREBLE: u16 = 1 << 4;
+    pub const GRAPHIC_EQUALIZER: u16 = 1 << 5;
+    pub const AUTOMATIC_GAIN: u16 = 1 << 6;
+    pub const DELAY: u16 = 1 << 7;
+    pub const BASS_BOOST: u16 = 1 << 8;
+    pub const LOUDNESS: u16 = 1 << 9;
+}
+
+/// Identifies a terminal or unit of an `AudioTopology`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EntityId(pub u8);
+
+#[derive(Clone, Debug)]
+enum Entity {
+    InputTerminal {
+        terminal_type: u16,
+        channels: u8,
+        channel_config: u16,
+    },
+    OutputTerminal {
+        terminal_type: u16,
+        source: EntityId,
+    },
+    FeatureUnit {
+        source: EntityId,
+        master_controls: u16,
+        channel_controls: u16,
+    },
+}
+
+/// The terminals and units of an audio function and how they are connected.
+///
+/// Entity IDs are assigned from 1 in the order the entities are added. An entity can only take
+/// its input from an entity added before it.
+#[derive(Clone, Debug, Default)]
+pub struct AudioTopology {
+    entities: Vec<Entity>,
+}
+
+impl AudioTopology {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn add(&mut self, entity: Entity) -> EntityId {
+        self.entities.push(entity);
+        EntityId(self.entities.len() as u8)
+    }
+
+    fn get(&self, id: EntityId) -> Option<&Entity> {
+        self.entities.get((id.0 as usize).wrapping_sub(1))
+    }
+
+    /// Adds an input terminal, i.e. a source of audio data like a microphone, or the audio
+    /// stream from the host for `terminal_type::USB_STREAMING`.
+    pub fn input_terminal(
+        &mut self,
+        terminal_type: u16,
+        channels: u8,
+        channel_config: u16,
+    ) -> EntityId {
+        self.add(Entity::InputTerminal {
+            terminal_type,
+            channels,
+            channel_config,
+        })
+    }
+
+    /// Adds an output terminal, i.e. a sink of audio data like a speaker, or the audio stream to
+    /// the host for `terminal_type::USB_STREAMING`.
+    pub fn output_terminal(&mut self, terminal_type: u16, source: EntityId) -> EntityId {
+        self.add(Entity::OutputTerminal {
+            terminal_type,
+            source,
+        })
+    }
+
+    /// Adds a feature unit with the given `feature_control` bits for the master channel and for
+    /// each logical channel of its input.
+    pub fn feature_unit(
+        &mut self,
+        source: EntityId,
+        master_controls: u16,
+        channel_controls: u16,
+    ) -> EntityId {
+        self.add(Entity::FeatureUnit {
+            source,
+            master_controls,
+            channel_controls,
+        })
+    }
+
+    /// Returns the number of channels of the input terminal feeding the given entity.
+    fn channels(&self, id: EntityId) -> Result<u8, BuilderError> {
+        let mut id = id;
+        loop {
+            match self.get(id) {
+                Some(Entity::InputTerminal { channels, .. }) => return Ok(*channels),
+                Some(Entity::OutputTerminal { source, .. })
+                | Some(Entity::FeatureUnit { source, .. }) => id = *source,
+                None => return Err(invalid_topology(id, "entity doesn't exist")),
+            }
+        }
+    }
+
+    fn validate(&self) -> Result<(), BuilderError> {
+        if self.entities.len() > 255 {
+            return Err(invalid_topology(EntityId(0), "too many entities"));
+        }
+        for (i, entity) in self.entities.iter().enumerate() {
+            let id = EntityId(i as u8 + 1);
+            let source = match entity {
+                Entity::InputTerminal { .. } => continue,
+                Entity::OutputTerminal { source, .. } | Entity::FeatureUnit { source, .. } => {
+                    *source
+                }
+            };
+            if source.0 == 0 || source.0 >= id.0 {
+                return Err(invalid_topology(
+                    id,
+                    "source must be added before the entity",
+                ));
+            }
+            if let Some(Entity::OutputTerminal { .. }) = self.get(source) {
+                return Err(invalid_topology(id, "source is an output terminal"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the class-specific AC interface descriptors following the header.
+    fn descriptors(&self) -> Result<Vec<Vec<u8>>, BuilderError> {
+        let mut descriptors = Vec::new();
+        for (i, entity) in self.entities.iter().enumerate() {
+            let id = i as u8 + 1;
+            let descriptor = match *entity {
+                Entity::InputTerminal {
+                    terminal_type,
+                    channels,
+                    channel_config,
+                } => vec![
+                    AC_INPUT_TERMINAL,
+                    id, // bTerminalID
+                    terminal_type as u8,
+                    (terminal_type >> 8) as u8, // wTerminalType
+                    0,                          // bAssocTerminal
+                    channels,                   // bNrChannels
+                    channel_config as u8,
+                    (channel_config >> 8) as u8, // wChannelConfig
+                    0,                           // iChannelNames
+                    0,                           // iTerminal
+                ],
+                Entity::OutputTerminal {
+                    terminal_type,
+                    source,
+                } => vec![
+                    AC_OUTPUT_TERMINAL,
+                    id, // bTerminalID
+                    terminal_type as u8,
+                    (terminal_type >> 8) as u8, // wTerminalType
+                    0,                          // bAssocTerminal
+                    source.0,                   // bSourceID
+                    0,                          // iTerminal
+                ],
+                Entity::FeatureUnit {
+                    source,
+                    master_controls,
+                    channel_controls,
+                } => {
+                    let channels = self.channels(source)?;
+                    let control_size = if (master_controls | channel_controls) > 0xff {
+                        2
+                    } else {
+                        1
+                    };
+                    let mut descriptor = vec![
+                        AC_FEATURE_UNIT,
+                        id,           // bUnitID
+                        source.0,     // bSourceID
+                        control_size, // bControlSize
+                    ];
+                    for i in 0..=channels {
+                        let controls = if i == 0 {
+                            master_controls
+                        } else {
+                            channel_controls
+                        };
+                        descriptor
+                            .extend_from_slice(&controls.to_le_bytes()[..control_size as usize]);
+                        // bmaControls
+                    }
+                    descriptor.push(0); // iFeature
+                    descriptor
+                }
+            };
+            descriptors.push(descriptor);
+        }
+        Ok(descriptors)
+    }
+}
+
+fn invalid_topology(entity: EntityId, reason: &'static str) -> BuilderError {
+    BuilderError::InvalidAudioTopology {
+        entity: entity.0,
+        reason,
+    }
+}
+
+/// Type I PCM format of an audio stream.
+#[derive(Clone, Debug)]
+pub struct AudioFormat {
+    pub channels: u8,
+    pub subframe_size: u8,
+    pub bit_resolution: u8,
+    pub sample_rates: Vec<u32>,
+}
+
+impl AudioFormat {
+    /// Creates a PCM format with the given number of channels and bits per sample. Samples are
+    /// stored in the smallest number of bytes that holds them.
+    pub fn pcm(channels: u8, bit_resolution: u8, sample_rates: &[u32]) -> Self {
+        Self {
+            channels,
+            subframe_size: subslot_size(bit_resolution),
+            bit_resolution,
+            sample_rates: sample_rates.to_vec(),
+        }
+    }
+
+    fn max_frame_size(&self) -> usize {
+        let max_rate = self.sample_rates.iter().copied().max().unwrap_or(0);
+        max_frame_size(max_rate, self.subframe_size, self.channels)
+    }
+}
+
+/// Returns the number of bytes holding a sample of `bit_resolution` bits, the subframe size of
+/// UAC 1.0 or the subslot size of UAC 2.0.
+pub(crate) fn subslot_size(bit_resolution: u8) -> u8 {
+    bit_resolution.div_ceil(8)
+}
+
+/// Returns the largest number of bytes transferred in one 1 ms frame.
+pub(crate) fn max_frame_size(max_rate: u32, subslot_size: u8, channels: u8) -> usize {
+    (max_rate as usize).div_ceil(1000) * usize::from(subslot_size) * usize::from(channels)
+}
+
+/// An audio streaming interface connected to a `terminal_type::USB_STREAMING` terminal of the
+/// topology.
+///
+/// Streams from the host use an isochronous OUT endpoint with adaptive synchronization, streams
+/// to the host use an isochronous IN endpoint with asynchronous synchronization.
+#[derive(Clone, Debug)]
+pub struct AudioStream {
+    pub terminal: EntityId,
+    pub format: AudioFormat,
+    pub endpoint: UsbEndpointDescriptor,
+}
+
+impl AudioStream {
+    pub fn new(terminal: EntityId, format: AudioFormat, endpoint: impl EndpointInfo) -> Self {
+        Self {
+            terminal,
+            format,
+            endpoint: endpoint.descriptor().clone(),
+        }
+    }
+}
+
+/// Adds a USB Audio Class 1.0 function: an audio control interface describing `topology`, and
+/// an audio streaming interface for each of `streams`. Each streaming interface has an empty
+/// alternate setting 0 and an alternate setting 1 with the isochronous endpoint.
+pub fn create_audio_function(
+    device: &mut DeviceBuilder,
+    topology: &AudioTopology,
+    streams: &[AudioStream],
+) {
+    if let Err(e) = add_audio_function(device, topology, streams) {
+        device.defer_error(e);
+    }
+}
+
+fn add_audio_function(
+    device: &mut DeviceBuilder,
+    topology: &AudioTopology,
+    streams: &[AudioStream],
+) -> Result<(), BuilderError> {
+    topology.validate()?;
+    let entities = topology.descriptors()?;
+
+    let control_if = device.alloc_interface();
+    let first_stream_if = control_if.descriptor.interface_number + 1;
+
+    // The header lists the streaming interfaces and the total length of the class-specific
+    // descriptors, each of which is 2 bytes longer than its data
+    let header_length = 8 + streams.len();
+    let total_length = header_length + entities.iter().map(|d| d.len() + 2).sum::<usize>();
+    let mut header = vec![
+        AC_HEADER,
+        0x00,
+        0x01, // bcdADC
+        total_length as u8,
+        (total_length >> 8) as u8, // wTotalLength
+        streams.len() as u8,       // bInCollection
+    ];
+    header.extend((0..streams.len()).map(|i| first_stream_if + i as u8)); // baInterfaceNr
+
+    let mut control_if = control_if
+        .interface_class(USB_CLASS_AUDIO)
+        .interface_sub_class(AUDIO_SUBCLASS_CONTROL)
+        .descriptor(CS_INTERFACE, &header);
+    for entity in &entities {
+        control_if = control_if.descriptor(CS_INTERFACE, entity);
+    }
+    control_if.save(device);
+
+    for stream in streams {
+        let direction = match topology.get(stream.terminal) {
+            Some(Entity::InputTerminal {
+                terminal_type: terminal_type::USB_STREAMING,
+                ..
+            }) => UsbDirection::Out,
+            Some(Entity::OutputTerminal {
+                terminal_type: terminal_type::USB_STREAMING,
+                ..
+            }) => UsbDirection::In,
+            _ => {
+                return Err(invalid_topology(
+                    stream.terminal,
+                    "audio stream must link to a USB streaming terminal",
+                ))
+            }
+        };
+        device.check_endpoint(&stream.endpoint, EndpointType::Isochronous, direction);
+
+        let format = &stream.format;
+        if usize::from(stream.endpoint.max_packet_size) < format.max_frame_size() {
+            return Err(BuilderError::InvalidMaxPacketSize {
+                address: stream.endpoint.address.into(),
+                max_packet_size: stream.endpoint.max_packet_size,
+            });
+        }
+
+        let mut format_type = vec![
+            AS_FORMAT_TYPE,
+            FORMAT_TYPE_I,
+            format.channels,                 // bNrChannels
+            format.subframe_size,            // bSubframeSize
+            format.bit_resolution,           // bBitResolution
+            format.sample_rates.len() as u8, // bSamFreqType
+        ];
+        for rate in &format.sample_rates {
+            format_type.extend_from_slice(&rate.to_le_bytes()[..3]); // tSamFreq
+        }
+
+        // Sampling frequency control is needed to switch between several sample rates
+        let endpoint_attributes = if format.sample_rates.len() > 1 {
+            0x01
+        } else {
+            0x00
+        };
+        let sync = match direction {
+            UsbDirection::Out => ISO_SYNC_ADAPTIVE,
+            UsbDirection::In => ISO_SYNC_ASYNCHRONOUS,
+        };
+        let mut endpoint = stream
+            .endpoint
+            .clone()
+            .extra_fields(&[
+                0, // bRefresh
+                0, // bSynchAddress
+            ])
+            .descriptor(
+                CS_ENDPOINT,
+                &[
+                    EP_GENERAL,
+                    endpoint_attributes, // bmAttributes
+                    0,                   // bLockDelayUnits
+                    0,
+                    0, // wLockDelay
+                ],
+            );
+        endpoint.attributes = (endpoint.attributes & 0b11) | sync;
+
+        let zero_bandwidth_if = device.alloc_interface();
+        let streaming_if = device.alloc_alternate_setting(&zero_bandwidth_if);
+        zero_bandwidth_if
+            .interface_class(USB_CLASS_AUDIO)
+            .interface_sub_class(AUDIO_SUBCLASS_STREAMING)
+            .save(device);
+        streaming_if
+            .interface_class(USB_CLASS_AUDIO)
+            .interface_sub_class(AUDIO_SUBCLASS_STREAMING)
+            .descriptor(
+                CS_INTERFACE,
+                &[
+                    AS_GENERAL,
+                    stream.terminal.0, // bTerminalLink
+                    1,                 // bDelay
+                    FORMAT_TAG_PCM as u8,
+                    (FORMAT_TAG_PCM >> 8) as u8, // wFormatTag
+                ],
+            )
+            .descriptor(CS_INTERFACE, &format_type)
+            .endpoint(endpoint)
+            .save(device);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::UsbVidPid;
+    use crate::test_util::endpoint;
+
+    fn speaker() -> (AudioTopology, EntityId) {
+        let mut topology = AudioTopology::new();
+        let stream = topology.input_terminal(
+            terminal_type::USB_STREAMING,
+            2,
+            channel::LEFT_FRONT | channel::RIGHT_FRONT,
+        );
+        let volume = topology.feature_unit(stream, feature_control::MUTE, feature_control::VOLUME);
+        topology.output_terminal(terminal_type::SPEAKER, volume);
+        (topology, stream)
+    }
+
+    #[test]
+    fn speaker_function() {
+        let (topology, stream) = speaker();
+        let format = AudioFormat::pcm(2, 16, &[48000]);
+        let streams = [AudioStream::new(
+            stream,
+            format,
+            endpoint(1, UsbDirection::Out, EndpointType::Isochronous, 192, 1),
+        )];
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        create_audio_function(&mut device, &topology, &streams);
+        let config = device.build();
+
+        let expected: &[u8] = &[
+            0x09, 0x02, 0x6e, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32, // configuration
+            0x09, 0x04, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00, // audio control interface
+            0x09, 0x24, 0x01, 0x00, 0x01, 0x28, 0x00, 0x01, 0x01, // header
+            0x0c, 0x24, 0x02, 0x01, 0x01, 0x01, 0x00, 0x02, 0x03, 0x00, 0x00, 0x00, // input
+            0x0a, 0x24, 0x06, 0x02, 0x01, 0x01, 0x01, 0x02, 0x02, 0x00, // feature unit
+            0x09, 0x24, 0x03, 0x03, 0x01, 0x03, 0x00, 0x02, 0x00, // output terminal
+            0x09, 0x04, 0x01, 0x00, 0x00, 0x01, 0x02, 0x00, 0x00, // zero bandwidth setting
+            0x09, 0x04, 0x01, 0x01, 0x01, 0x01, 0x02, 0x00, 0x00, // streaming setting
+            0x07, 0x24, 0x01, 0x01, 0x01, 0x01, 0x00, // general
+            0x0b, 0x24, 0x02, 0x01, 0x02, 0x02, 0x10, 0x01, 0x80, 0xbb, 0x00, // format type
+            0x09, 0x05, 0x01, 0x09, 0xc0, 0x00, 0x01, 0x00, 0x00, // isochronous endpoint
+            0x07, 0x25, 0x01, 0x00, 0x00, 0x00, 0x00, // class-specific endpoint
+        ];
+        assert_eq!(config.configuration_descriptors[0], expected);
+    }
+
+    #[test]
+    fn invalid_topology() {
+        let mut topology = AudioTopology::new();
+        let stream = topology.input_terminal(terminal_type::USB_STREAMING, 1, 0);
+        topology.feature_unit(EntityId(3), feature_control::MUTE, 0);
+        topology.output_terminal(terminal_type::SPEAKER, stream);
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        create_audio_function(&mut device, &topology, &[]);
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::InvalidAudioTopology {
+                entity: 2,
+                reason: "source must be added before the entity",
+            }
+        );
+    }
+
+    #[test]
+    fn max_packet_size_too_small() {
+        let (topology, stream) = speaker();
+        let format = AudioFormat::pcm(2, 24, &[44100, 48000]);
+        let streams = [AudioStream::new(
+            stream,
+            format,
+            endpoint(1, UsbDirection::Out, EndpointType::Isochronous, 192, 1),
+        )];
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        create_audio_function(&mut device, &topology, &streams);
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::InvalidMaxPacketSize {
+                address: 0x01,
+                max_packet_size: 192,
+            }
+        );
+    }
+}
@@ -0,0 +1,848 @@
+use crate::audio::{
+    max_frame_size, subslot_size, EntityId, AUDIO_SUBCLASS_CONTROL, AUDIO_SUBCLASS_STREAMING,
+    CS_ENDPOINT, CS_INTERFACE, USB_CLASS_AUDIO,
+};
+use crate::builder::DeviceBuilder;
+use crate::control::{ControlRequest, ControlResponse, Recipient, RequestType};
+use crate::error::BuilderError;
+use crate::usb::UsbEndpointDescriptor;
+use crate::EndpointInfo;
+use usb_device::endpoint::EndpointType;
+use usb_device::UsbDirection;
+
+pub use crate::audio::terminal_type;
+
+const AUDIO_FUNCTION_SUBCLASS_UNDEFINED: u8 = 0x00;
+const AUDIO_PROTOCOL_IP_VERSION_02_00: u8 = 0x20;
+
+const AC_HEADER: u8 = 0x01;
+const AC_INPUT_TERMINAL: u8 = 0x02;
+const AC_OUTPUT_TERMINAL: u8 = 0x03;
+const AC_FEATURE_UNIT: u8 = 0x06;
+const AC_CLOCK_SOURCE: u8 = 0x0a;
+const AC_CLOCK_SELECTOR: u8 = 0x0b;
+const AS_GENERAL: u8 = 0x01;
+const AS_FORMAT_TYPE: u8 = 0x02;
+const EP_GENERAL: u8 = 0x01;
+const FORMAT_TYPE_I: u8 = 0x01;
+const FORMAT_PCM: u32 = 0x0000_0001;
+
+const REQUEST_CUR: u8 = 0x01;
+const REQUEST_RANGE: u8 = 0x02;
+const CS_SAM_FREQ_CONTROL: u16 = 0x01;
+const CS_CLOCK_VALID_CONTROL: u16 = 0x02;
+const CX_CLOCK_SELECTOR_CONTROL: u16 = 0x01;
+
+const ISO_SYNC_ASYNCHRONOUS: u8 = 0b01 << 2;
+const ISO_SYNC_ADAPTIVE: u8 = 0b10 << 2;
+const ISO_USAGE_FEEDBACK: u8 = 0b01 << 4;
+
+/// Function categories used in `bCategory` of the class-specific AC header.
+pub mod category {
+    pub const DESKTOP_SPEAKER: u8 = 0x01;
+    pub const HOME_THEATER: u8 = 0x02;
+    pub const MICROPHONE: u8 = 0x03;
+    pub const HEADSET: u8 = 0x04;
+    pub const TELEPHONE: u8 = 0x05;
+    pub const CONVERTER: u8 = 0x06;
+    pub const SOUND_RECORDER: u8 = 0x07;
+    pub const IO_BOX: u8 = 0x08;
+    pub const MUSICAL_INSTRUMENT: u8 = 0x09;
+    pub const PRO_AUDIO: u8 = 0x0a;
+    pub const AUDIO_VIDEO: u8 = 0x0b;
+    pub const CONTROL_PANEL: u8 = 0x0c;
+    pub const OTHER: u8 = 0xff;
+}
+
+/// Host programmable feature unit controls, used in `bmaControls`. A read-only control has only
+/// the lower bit of its pair set.
+pub mod feature_control {
+    pub const MUTE: u32 = 0b11;
+    pub const VOLUME: u32 = 0b11 << 2;
+    pub const BASS: u32 = 0b11 << 4;
+    pub const MID: u32 = 0b11 << 6;
+    pub const TREBLE: u32 = 0b11 << 8;
+    pub const GRAPHIC_EQUALIZER: u32 = 0b11 << 10;
+    pub const AUTOMATIC_GAIN: u32 = 0b11 << 12;
+    pub const DELAY: u32 = 0b11 << 14;
+    pub const BASS_BOOST: u32 = 0b11 << 16;
+    pub const LOUDNESS: u32 = 0b11 << 18;
+    pub const INPUT_GAIN: u32 = 0b11 << 20;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClockType {
+    External = 0b00,
+    InternalFixed = 0b01,
+    InternalVariable = 0b10,
+    InternalProgrammable = 0b11,
+}
+
+/// Logical channels of a terminal: the number of channels and their spatial locations
+/// (`bmChannelConfig`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelCluster {
+    pub channels: u8,
+    pub channel_config: u32,
+}
+
+impl ChannelCluster {
+    pub fn new(channels: u8, channel_config: u32) -> Self {
+        Self {
+            channels,
+            channel_config,
+        }
+    }
+
+    pub fn mono() -> Self {
+        Self::new(1, 0)
+    }
+
+    /// Front left and front right channels.
+    pub fn stereo() -> Self {
+        Self::new(2, 0b11)
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Entity {
+    ClockSource {
+        clock_type: ClockType,
+        sample_rates: Vec<u32>,
+    },
+    ClockSelector {
+        sources: Vec<EntityId>,
+    },
+    InputTerminal {
+        terminal_type: u16,
+        clock: EntityId,
+        cluster: ChannelCluster,
+    },
+    OutputTerminal {
+        terminal_type: u16,
+        source: EntityId,
+        clock: EntityId,
+    },
+    FeatureUnit {
+        source: EntityId,
+        master_controls: u32,
+        channel_controls: u32,
+    },
+}
+
+impl Entity {
+    fn is_clock(&self) -> bool {
+        matches!(
+            self,
+            Entity::ClockSource { .. } | Entity::ClockSelector { .. }
+        )
+    }
+}
+
+/// The clock entities, terminals and units of a USB Audio Class 2.0 function and how they are
+/// connected.
+///
+/// Entity IDs are assigned from 1 in the order the entities are added. An entity can only refer
+/// to entities added before it.
+#[derive(Clone, Debug)]
+pub struct Uac2Topology {
+    category: u8,
+    entities: Vec<Entity>,
+}
+
+impl Default for Uac2Topology {
+    fn default() -> Self {
+        Self {
+            category: category::IO_BOX,
+            entities: Vec::new(),
+        }
+    }
+}
+
+impl Uac2Topology {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the primary use of the function.
+    ///
+    /// Default: `category::IO_BOX`
+    pub fn category(mut self, category: u8) -> Self {
+        self.category = category;
+        self
+    }
+
+    fn add(&mut self, entity: Entity) -> EntityId {
+        self.entities.push(entity);
+        EntityId(self.entities.len() as u8)
+    }
+
+    fn get(&self, id: EntityId) -> Option<&Entity> {
+        self.entities.get((id.0 as usize).wrapping_sub(1))
+    }
+
+    /// Adds a clock source running at one of the given sample rates in Hz. The sample rate is
+    /// host programmable if more than one is given.
+    pub fn clock_source(&mut self, clock_type: ClockType, sample_rates: &[u32]) -> EntityId {
+        self.add(Entity::ClockSource {
+            clock_type,
+            sample_rates: sample_rates.to_vec(),
+        })
+    }
+
+    /// Adds a clock selector choosing one of the given clock sources or selectors.
+    pub fn clock_selector(&mut self, sources: &[EntityId]) -> EntityId {
+        self.add(Entity::ClockSelector {
+            sources: sources.to_vec(),
+        })
+    }
+
+    /// Adds an input terminal clocked by the given clock entity.
+    pub fn input_terminal(
+        &mut self,
+        terminal_type: u16,
+        clock: EntityId,
+        cluster: ChannelCluster,
+    ) -> EntityId {
+        self.add(Entity::InputTerminal {
+            terminal_type,
+            clock,
+            cluster,
+        })
+    }
+
+    /// Adds an output terminal clocked by the given clock entity.
+    pub fn output_terminal(
+        &mut self,
+        terminal_type: u16,
+        source: EntityId,
+        clock: EntityId,
+    ) -> EntityId {
+        self.add(Entity::OutputTerminal {
+            terminal_type,
+            source,
+            clock,
+        })
+    }
+
+    /// Adds a feature unit like `audio::AudioTopology::feature_unit`, with the bit pairs of this
+    /// module's `feature_control`.
+    pub fn feature_unit(
+        &mut self,
+        source: EntityId,
+        master_controls: u32,
+        channel_controls: u32,
+    ) -> EntityId {
+        self.add(Entity::FeatureUnit {
+            source,
+            master_controls,
+            channel_controls,
+        })
+    }
+
+    /// Returns the channel cluster of the input terminal feeding the given entity.
+    fn cluster(&self, id: EntityId) -> Result<ChannelCluster, BuilderError> {
+        let mut id = id;
+        loop {
+            match self.get(id) {
+                Some(Entity::InputTerminal { cluster, .. }) => return Ok(*cluster),
+                Some(Entity::OutputTerminal { source, .. })
+                | Some(Entity::FeatureUnit { source, .. }) => id = *source,
+                _ => return Err(invalid_topology(id, "entity is not part of an audio path")),
+            }
+        }
+    }
+
+    /// Returns the highest sample rate of a clock source, or of the sources of a clock selector.
+    fn max_sample_rate(&self, clock: EntityId) -> u32 {
+        match self.get(clock) {
+            Some(Entity::ClockSource { sample_rates, .. }) => {
+                sample_rates.iter().copied().max().unwrap_or(0)
+            }
+            Some(Entity::ClockSelector { sources }) => sources
+                .iter()
+                .map(|&source| self.max_sample_rate(source))
+                .max()
+                .unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    fn validate(&self) -> Result<(), BuilderError> {
+        if self.entities.len() > 255 {
+            return Err(invalid_topology(EntityId(0), "too many entities"));
+        }
+        for (i, entity) in self.entities.iter().enumerate() {
+            let id = EntityId(i as u8 + 1);
+            let earlier = |other: EntityId| other.0 != 0 && other.0 < id.0;
+            let (clocks, sources) = match entity {
+                Entity::ClockSource { sample_rates, .. } => {
+                    if sample_rates.is_empty() || sample_rates.len() > 255 {
+                        return Err(invalid_topology(
+                            id,
+                            "clock source needs 1 to 255 sample rates",
+                        ));
+                    }
+                    continue;
+                }
+                Entity::ClockSelector { sources } => (sources.clone(), Vec::new()),
+                Entity::InputTerminal { clock, .. } => (vec![*clock], Vec::new()),
+                Entity::OutputTerminal { source, clock, .. } => (vec![*clock], vec![*source]),
+                Entity::FeatureUnit { source, .. } => (Vec::new(), vec![*source]),
+            };
+            for clock in clocks {
+                if !earlier(clock) || !self.get(clock).unwrap().is_clock() {
+                    return Err(invalid_topology(
+                        id,
+                        "clock must be a clock entity added before the entity",
+                    ));
+                }
+            }
+            for source in sources {
+                if !earlier(source) {
+                    return Err(invalid_topology(
+                        id,
+                        "source must be added before the entity",
+                    ));
+                }
+                match self.get(source).unwrap() {
+                    Entity::OutputTerminal { .. } => {
+                        return Err(invalid_topology(id, "source is an output terminal"))
+                    }
+                    source if source.is_clock() => {
+                        return Err(invalid_topology(id, "source is a clock entity"))
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the clock entity, terminal and unit descriptors, in the order they were added.
+    fn descriptors(&self) -> Result<Vec<Vec<u8>>, BuilderError> {
+        let mut descriptors = Vec::new();
+        for (i, entity) in self.entities.iter().enumerate() {
+            let id = i as u8 + 1;
+            let descriptor = match entity {
+                Entity::ClockSource {
+                    clock_type,
+                    sample_rates,
+                } => {
+                    // The frequency is read-only unless the host can choose between several, the
+                    // validity is read-only
+                    let frequency_control = if sample_rates.len() > 1 { 0b11 } else { 0b01 };
+                    vec![
+                        AC_CLOCK_SOURCE,
+                        id,                              // bClockID
+                        *clock_type as u8,               // bmAttributes
+                        frequency_control | (0b01 << 2), // bmControls
+                        0,                               // bAssocTerminal
+                        0,                               // iClockSource
+                    ]
+                }
+                Entity::ClockSelector { sources } => {
+                    let mut descriptor = vec![
+                        AC_CLOCK_SELECTOR,
+                        id,                  // bClockID
+                        sources.len() as u8, // bNrInPins
+                    ];
+                    descriptor.extend(sources.iter().map(|s| s.0)); // baCSourceID
+
+                    // The selector is read-only, the generated code always reports input pin 1
+                    descriptor.push(0b01); // bmControls
+                    descriptor.push(0); // iClockSelector
+                    descriptor
+                }
+                Entity::InputTerminal {
+                    terminal_type,
+                    clock,
+                    cluster,
+                } => {
+                    let mut descriptor = vec![
+                        AC_INPUT_TERMINAL,
+                        id, // bTerminalID
+                        *terminal_type as u8,
+                        (*terminal_type >> 8) as u8, // wTerminalType
+                        0,                           // bAssocTerminal
+                        clock.0,                     // bCSourceID
+                        cluster.channels,            // bNrChannels
+                    ];
+                    descriptor.extend_from_slice(&cluster.channel_config.to_le_bytes()); // bmChannelConfig
+                    descriptor.extend_from_slice(&[
+                        0, // iChannelNames
+                        0, 0, // bmControls
+                        0, // iTerminal
+                    ]);
+                    descriptor
+                }
+                Entity::OutputTerminal {
+                    terminal_type,
+                    source,
+                    clock,
+                } => vec![
+                    AC_OUTPUT_TERMINAL,
+                    id, // bTerminalID
+                    *terminal_type as u8,
+                    (*terminal_type >> 8) as u8, // wTerminalType
+                    0,                           // bAssocTerminal
+                    source.0,                    // bSourceID
+                    clock.0,                     // bCSourceID
+                    0,
+                    0, // bmControls
+                    0, // iTerminal
+                ],
+                Entity::FeatureUnit {
+                    source,
+                    master_controls,
+                    channel_controls,
+                } => {
+                    let cluster = self.cluster(*source)?;
+                    let mut descriptor = vec![
+                        AC_FEATURE_UNIT,
+                        id,       // bUnitID
+                        source.0, // bSourceID
+                    ];
+                    for i in 0..=cluster.channels {
+                        let controls = if i == 0 {
+                            master_controls
+                        } else {
+                            channel_controls
+                        };
+                        descriptor.extend_from_slice(&controls.to_le_bytes()); // bmaControls
+                    }
+                    descriptor.push(0); // iFeature
+                    descriptor
+                }
+            };
+            descriptors.push(descriptor);
+        }
+        Ok(descriptors)
+    }
+
+    /// Returns the clock requests answered from static tables: the sample rate range, the
+    /// current sample rate of fixed-rate clocks, the clock validity and the selected input pin of
+    /// clock selectors, which is always the first one.
+    fn clock_requests(&self, interface_number: u8) -> Vec<ControlRequest> {
+        let mut requests = Vec::new();
+        for (i, entity) in self.entities.iter().enumerate() {
+            let index = ((i as u16 + 1) << 8) | u16::from(interface_number);
+            let request = |request, control: u16, data| {
+                ControlRequest::new(
+                    RequestType::Class,
+                    Recipient::Interface,
+                    request,
+                    ControlResponse::Data(data),
+                )
+                .value(control << 8)
+                .index(index)
+            };
+            let sample_rates = match entity {
+                Entity::ClockSource { sample_rates, .. } => sample_rates,
+                Entity::ClockSelector { .. } => {
+                    requests.push(request(REQUEST_CUR, CX_CLOCK_SELECTOR_CONTROL, vec![1]));
+                    continue;
+                }
+                _ => continue,
+            };
+
+            // Layout 3 parameter block with one subrange per sample rate
+            let mut range = (sample_rates.len() as u16).to_le_bytes().to_vec(); // wNumSubRanges
+            for rate in sample_rates {
+                range.extend_from_slice(&rate.to_le_bytes()); // dMIN
+                range.extend_from_slice(&rate.to_le_bytes()); // dMAX
+                range.extend_from_slice(&0u32.to_le_bytes()); // dRES
+            }
+            requests.push(request(REQUEST_RANGE, CS_SAM_FREQ_CONTROL, range));
+
+            // The current rate of a programmable clock is only known at runtime
+            if sample_rates.len() == 1 {
+                requests.push(request(
+                    REQUEST_CUR,
+                    CS_SAM_FREQ_CONTROL,
+                    sample_rates[0].to_le_bytes().to_vec(),
+                ));
+            }
+
+            requests.push(request(REQUEST_CUR, CS_CLOCK_VALID_CONTROL, vec![1]));
+        }
+        requests
+    }
+}
+
+fn invalid_topology(entity: EntityId, reason: &'static str) -> BuilderError {
+    BuilderError::InvalidAudioTopology {
+        entity: entity.0,
+        reason,
+    }
+}
+
+/// A UAC 2.0 counterpart of `audio::AudioStream`, carrying Type I PCM data with the channel cluster of
+/// its `terminal_type::USB_STREAMING` terminal.
+///
+/// Streams from the host use an isochronous OUT endpoint, which is asynchronous if an explicit
+/// feedback endpoint is given and adaptive otherwise. Streams to the host use an asynchronous
+/// isochronous IN endpoint.
+#[derive(Clone, Debug)]
+pub struct Uac2Stream {
+    pub terminal: EntityId,
+    pub subslot_size: u8,
+    pub bit_resolution: u8,
+    pub endpoint: UsbEndpointDescriptor,
+    pub feedback_endpoint: Option<UsbEndpointDescriptor>,
+}
+
+impl Uac2Stream {
+    /// Creates a PCM stream with the given bits per sample. Samples are stored in the smallest
+    /// number of bytes that holds them.
+    pub fn new(terminal: EntityId, bit_resolution: u8, endpoint: impl EndpointInfo) -> Self {
+        Self {
+            terminal,
+            subslot_size: subslot_size(bit_resolution),
+            bit_resolution,
+            endpoint: endpoint.descriptor().clone(),
+            feedback_endpoint: None,
+        }
+    }
+
+    /// Adds an explicit feedback endpoint, an isochronous IN endpoint reporting the sample rate
+    /// of an asynchronous stream from the host.
+    ///
+    /// Default: (none)
+    pub fn feedback_endpoint(mut self, endpoint: impl EndpointInfo) -> Self {
+        self.feedback_endpoint = Some(endpoint.descriptor().clone());
+        self
+    }
+}
+
+/// Adds a USB Audio Class 2.0 function: an interface association, an audio control interface
+/// describing `topology`, and an audio streaming interface for each of `streams`. Each
+/// streaming interface has an empty alternate setting 0 and an alternate setting 1 with the
+/// isochronous endpoints.
+///
+/// The generated code answers the sample rate RANGE and clock validity CUR requests of all clock
+/// sources, and the sample rate CUR requests of clock sources with a single sample rate. Clock
+/// selectors are declared read-only and always report their first input pin.
+pub fn create_uac2_function(
+    device: &mut DeviceBuilder,
+    topology: &Uac2Topology,
+    streams: &[Uac2Stream],
+) {
+    if let Err(e) = add_uac2_function(device, topology, streams) {
+        device.defer_error(e);
+    }
+}
+
+fn add_uac2_function(
+    device: &mut DeviceBuilder,
+    topology: &Uac2Topology,
+    streams: &[Uac2Stream],
+) -> Result<(), BuilderError> {
+    topology.validate()?;
+    let entities = topology.descriptors()?;
+
+    let function = device.alloc_function();
+    let control_if = device.alloc_interface();
+    let control_if_id = control_if.descriptor.interface_number;
+
+    // The total length includes the header and the 2 byte prefix of each descriptor
+    let total_length = 9 + entities.iter().map(|d| d.len() + 2).sum::<usize>();
+    let header = [
+        AC_HEADER,
+        0x00,
+        0x02,              // bcdADC
+        topology.category, // bCategory
+        total_length as u8,
+        (total_length >> 8) as u8, // wTotalLength
+        0,                         // bmControls
+    ];
+
+    let mut control_if = control_if
+        .interface_class(USB_CLASS_AUDIO)
+        .interface_sub_class(AUDIO_SUBCLASS_CONTROL)
+        .interface_protocol(AUDIO_PROTOCOL_IP_VERSION_02_00)
+        .descriptor(CS_INTERFACE, &header);
+    for entity in &entities {
+        control_if = control_if.descriptor(CS_INTERFACE, entity);
+    }
+    control_if.save(device);
+
+    for stream in streams {
+        let (direction, clock) = match topology.get(stream.terminal) {
+            Some(Entity::InputTerminal {
+                terminal_type: terminal_type::USB_STREAMING,
+                clock,
+                ..
+            }) => (UsbDirection::Out, *clock),
+            Some(Entity::OutputTerminal {
+                terminal_type: terminal_type::USB_STREAMING,
+                clock,
+                ..
+            }) => (UsbDirection::In, *clock),
+            _ => {
+                return Err(invalid_topology(
+                    stream.terminal,
+                    "audio stream must link to a USB streaming terminal",
+                ))
+            }
+        };
+        let cluster = topology.cluster(stream.terminal)?;
+        device.check_endpoint(&stream.endpoint, EndpointType::Isochronous, direction);
+
+        let max_rate = topology.max_sample_rate(clock);
+        if usize::from(stream.endpoint.max_packet_size)
+            < max_frame_size(max_rate, stream.subslot_size, cluster.channels)
+        {
+            return Err(BuilderError::InvalidMaxPacketSize {
+                address: stream.endpoint.address.into(),
+                max_packet_size: stream.endpoint.max_packet_size,
+            });
+        }
+
+        let mut as_general = vec![
+            AS_GENERAL,
+            stream.terminal.0, // bTerminalLink
+            0,                 // bmControls
+            FORMAT_TYPE_I,     // bFormatType
+        ];
+        as_general.extend_from_slice(&FORMAT_PCM.to_le_bytes()); // bmFormats
+        as_general.push(cluster.channels); // bNrChannels
+        as_general.extend_from_slice(&cluster.channel_config.to_le_bytes()); // bmChannelConfig
+        as_general.push(0); // iChannelNames
+
+        let sync = match (direction, &stream.feedback_endpoint) {
+            (UsbDirection::Out, None) => ISO_SYNC_ADAPTIVE,
+            _ => ISO_SYNC_ASYNCHRONOUS,
+        };
+        let mut endpoint = stream.endpoint.clone().descriptor(
+            CS_ENDPOINT,
+            &[
+                EP_GENERAL, 0, // bmAttributes
+                0, // bmControls
+                0, // bLockDelayUnits
+                0, 0, // wLockDelay
+            ],
+        );
+        endpoint.attributes = (endpoint.attributes & 0b11) | sync;
+
+        let zero_bandwidth_if = device.alloc_interface();
+        let streaming_if = device.alloc_alternate_setting(&zero_bandwidth_if);
+        zero_bandwidth_if
+            .interface_class(USB_CLASS_AUDIO)
+            .interface_sub_class(AUDIO_SUBCLASS_STREAMING)
+            .interface_protocol(AUDIO_PROTOCOL_IP_VERSION_02_00)
+            .save(device);
+        let mut streaming_if = streaming_if
+            .interface_class(USB_CLASS_AUDIO)
+            .interface_sub_class(AUDIO_SUBCLASS_STREAMING)
+            .interface_protocol(AUDIO_PROTOCOL_IP_VERSION_02_00)
+            .descriptor(CS_INTERFACE, &as_general)
+            .descriptor(
+                CS_INTERFACE,
+                &[
+                    AS_FORMAT_TYPE,
+                    FORMAT_TYPE_I,
+                    stream.subslot_size,
+                    stream.bit_resolution,
+                ],
+            )
+            .endpoint(endpoint);
+        if let Some(feedback) = &stream.feedback_endpoint {
+            if direction != UsbDirection::Out {
+                return Err(invalid_topology(
+                    stream.terminal,
+                    "only streams from the host need a feedback endpoint",
+                ));
+            }
+            device.check_endpoint(feedback, EndpointType::Isochronous, UsbDirection::In);
+            let mut feedback = feedback.clone();
+            feedback.attributes = (feedback.attributes & 0b11) | ISO_USAGE_FEEDBACK;
+            streaming_if = streaming_if.endpoint(feedback);
+        }
+        streaming_if.save(device);
+    }
+
+    function
+        .function_class(USB_CLASS_AUDIO)
+        .function_sub_class(AUDIO_FUNCTION_SUBCLASS_UNDEFINED)
+        .function_protocol(AUDIO_PROTOCOL_IP_VERSION_02_00)
+        .save(device);
+
+    for request in topology.clock_requests(control_if_id) {
+        device.add_control_request(request);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::UsbVidPid;
+    use crate::test_util::{endpoint, response};
+
+    /// Matches the requests to the control `value` of the clock source with ID 1.
+    fn clock_request(request: u8, value: u16) -> impl Fn(&ControlRequest) -> bool {
+        move |r| r.request == request && r.value == Some(value) && r.index == Some(0x0100)
+    }
+
+    #[test]
+    fn asynchronous_speaker() {
+        let mut topology = Uac2Topology::new().category(category::DESKTOP_SPEAKER);
+        let clock = topology.clock_source(ClockType::InternalFixed, &[48000]);
+        let stream = topology.input_terminal(
+            terminal_type::USB_STREAMING,
+            clock,
+            ChannelCluster::stereo(),
+        );
+        let volume = topology.feature_unit(stream, feature_control::MUTE, feature_control::VOLUME);
+        topology.output_terminal(terminal_type::SPEAKER, volume, clock);
+        let data_ep = endpoint(1, UsbDirection::Out, EndpointType::Isochronous, 192, 1);
+        let feedback_ep = endpoint(1, UsbDirection::In, EndpointType::Isochronous, 4, 1);
+        let streams = [Uac2Stream::new(stream, 16, data_ep).feedback_endpoint(feedback_ep)];
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        create_uac2_function(&mut device, &topology, &streams);
+        let config = device.build();
+
+        let expected: &[u8] = &[
+            0x09, 0x02, 0x98, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32, // configuration
+            0x08, 0x0b, 0x00, 0x02, 0x01, 0x00, 0x20, 0x00, // interface association
+            0x09, 0x04, 0x00, 0x00, 0x00, 0x01, 0x01, 0x20, 0x00, // audio control interface
+            0x09, 0x24, 0x01, 0x00, 0x02, 0x01, 0x40, 0x00, 0x00, // header
+            0x08, 0x24, 0x0a, 0x01, 0x01, 0x05, 0x00, 0x00, // clock source
+            0x11, 0x24, 0x02, 0x02, 0x01, 0x01, 0x00, 0x01, 0x02, // input terminal
+            0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // bmChannelConfig, ...
+            0x12, 0x24, 0x06, 0x03, 0x02, 0x03, 0x00, 0x00, 0x00, // feature unit
+            0x0c, 0x00, 0x00, 0x00, 0x0c, 0x00, 0x00, 0x00, 0x00, // bmaControls, iFeature
+            0x0c, 0x24, 0x03, 0x04, 0x01, 0x03, 0x00, 0x03, 0x01, 0x00, 0x00, 0x00, // output
+            0x09, 0x04, 0x01, 0x00, 0x00, 0x01, 0x02, 0x20, 0x00, // zero bandwidth setting
+            0x09, 0x04, 0x01, 0x01, 0x02, 0x01, 0x02, 0x20, 0x00, // streaming setting
+            0x10, 0x24, 0x01, 0x02, 0x00, 0x01, 0x01, 0x00, 0x00, 0x00, // general
+            0x02, 0x03, 0x00, 0x00, 0x00, 0x00, // bNrChannels, bmChannelConfig, ...
+            0x06, 0x24, 0x02, 0x01, 0x02, 0x10, // format type
+            0x07, 0x05, 0x01, 0x05, 0xc0, 0x00, 0x01, // asynchronous data endpoint
+            0x08, 0x25, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, // class-specific endpoint
+            0x07, 0x05, 0x81, 0x11, 0x04, 0x00, 0x01, // feedback endpoint
+        ];
+        assert_eq!(config.configuration_descriptors[0], expected);
+
+        assert_eq!(
+            response(&config, clock_request(REQUEST_RANGE, 0x0100)),
+            [
+                0x01, 0x00, // wNumSubRanges
+                0x80, 0xbb, 0x00, 0x00, 0x80, 0xbb, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            ]
+        );
+        assert_eq!(
+            response(&config, clock_request(REQUEST_CUR, 0x0100)),
+            [0x80, 0xbb, 0x00, 0x00]
+        );
+        assert_eq!(
+            response(&config, clock_request(REQUEST_CUR, 0x0200)),
+            [0x01]
+        );
+    }
+
+    #[test]
+    fn programmable_clock_has_no_current_sample_rate() {
+        let mut topology = Uac2Topology::new();
+        topology.clock_source(ClockType::InternalProgrammable, &[44100, 48000]);
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        create_uac2_function(&mut device, &topology, &[]);
+        let config = device.build();
+
+        assert_eq!(config.control_requests.len(), 2);
+        assert_eq!(
+            response(&config, clock_request(REQUEST_RANGE, 0x0100))[..2],
+            [0x02, 0x00]
+        );
+    }
+
+    #[test]
+    fn invalid_topology() {
+        let mut topology = Uac2Topology::new();
+        let clock = topology.clock_source(ClockType::InternalFixed, &[48000]);
+        let stream =
+            topology.input_terminal(terminal_type::USB_STREAMING, clock, ChannelCluster::mono());
+        topology.output_terminal(terminal_type::SPEAKER, stream, stream);
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        create_uac2_function(&mut device, &topology, &[]);
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::InvalidAudioTopology {
+                entity: 3,
+                reason: "clock must be a clock entity added before the entity",
+            }
+        );
+    }
+
+    #[test]
+    fn feedback_endpoint_of_stream_to_the_host() {
+        let mut topology = Uac2Topology::new();
+        let clock = topology.clock_source(ClockType::InternalFixed, &[48000]);
+        let microphone =
+            topology.input_terminal(terminal_type::MICROPHONE, clock, ChannelCluster::mono());
+        let stream = topology.output_terminal(terminal_type::USB_STREAMING, microphone, clock);
+        let data_ep = endpoint(1, UsbDirection::In, EndpointType::Isochronous, 96, 1);
+        let feedback_ep = endpoint(1, UsbDirection::In, EndpointType::Isochronous, 4, 1);
+        let streams = [Uac2Stream::new(stream, 16, data_ep).feedback_endpoint(feedback_ep)];
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        create_uac2_function(&mut device, &topology, &streams);
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::InvalidAudioTopology {
+                entity: 3,
+                reason: "only streams from the host need a feedback endpoint",
+            }
+        );
+    }
+
+    #[test]
+    fn max_packet_size_too_small_for_highest_sample_rate() {
+        let mut topology = Uac2Topology::new();
+        let low = topology.clock_source(ClockType::InternalFixed, &[44100]);
+        let high = topology.clock_source(ClockType::InternalProgrammable, &[48000, 96000]);
+        let clock = topology.clock_selector(&[low, high]);
+        let stream = topology.input_terminal(
+            terminal_type::USB_STREAMING,
+            clock,
+            ChannelCluster::stereo(),
+        );
+        topology.output_terminal(terminal_type::SPEAKER, stream, clock);
+
+        // 96 samples of 2 channels with 3 bytes each need 576 bytes per frame
+        let streams = [Uac2Stream::new(
+            stream,
+            24,
+            endpoint(1, UsbDirection::Out, EndpointType::Isochronous, 288, 1),
+        )];
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        create_uac2_function(&mut device, &topology, &streams);
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::InvalidMaxPacketSize {
+                address: 0x01,
+                max_packet_size: 288,
+            }
+        );
+    }
+
+    #[test]
+    fn clock_selector_reports_first_input() {
+        let mut topology = Uac2Topology::new();
+        let low = topology.clock_source(ClockType::InternalFixed, &[44100]);
+        let high = topology.clock_source(ClockType::InternalFixed, &[48000]);
+        topology.clock_selector(&[low, high]);
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        create_uac2_function(&mut device, &topology, &[]);
+        let config = device.build();
+
+        let selector: &[u8] = &[0x09, 0x24, 0x0b, 0x03, 0x02, 0x01, 0x02, 0x01, 0x00];
+        assert!(config.configuration_descriptors[0].ends_with(selector));
+        // The generated code answers the CUR request of the selector with the first input pin
+        let cur = |r: &ControlRequest| {
+            r.request == REQUEST_CUR && r.value == Some(0x0100) && r.index == Some(0x0300)
+        };
+        assert_eq!(response(&config, cur), [0x01]);
+    }
+}
@@ -1,11 +1,17 @@
+use crate::control::{ControlRequest, ControlResponse, Recipient, RequestType};
+use crate::error::BuilderError;
+use crate::msos::{self, MsOs20Options, MsOsFeature, RegistryValue};
 use crate::usb::{
-    UsbConfigurationDescriptor, UsbCustomDescriptor, UsbDescriptorType, UsbDescriptorWriter,
-    UsbDeviceDescriptor, UsbEndpointDescriptor, UsbInterfaceDescriptor, UsbString,
-    UsbStringAllocator,
+    lang_id, UsbConfigurationDescriptor, UsbCustomDescriptor, UsbDescriptorType,
+    UsbDescriptorWriter, UsbDeviceCapability, UsbDeviceDescriptor, UsbEndpointDescriptor,
+    UsbInterfaceAssociationDescriptor, UsbInterfaceDescriptor, UsbString, UsbStringAllocator,
+    MS_OS_STRING_INDEX, USB_MAX_ENDPOINTS,
 };
+use crate::webusb::{self, WebUsbOptions};
+use crate::EndpointInfo;
 use bit_field::BitField;
 use std::collections::HashMap;
-use usb_device::descriptor::lang_id;
+use std::convert::TryFrom;
 use usb_device::endpoint::{EndpointAddress, EndpointType};
 use usb_device::UsbDirection;
 
@@ -28,22 +34,37 @@ macro_rules! generate_field_setters {
 pub struct DeviceConfig {
     pub ep0_max_packet_size: u8,
     pub device_descriptor: Vec<u8>,
-    pub configuration_descriptor: Vec<u8>,
+    pub configuration_descriptors: Vec<Vec<u8>>,
+    pub bos_descriptor: Option<Vec<u8>>,
     pub string_descriptors: HashMap<u8, Vec<u8>>,
+    pub translated_string_descriptors: HashMap<(u16, u8), Vec<u8>>,
     pub custom_strings: HashMap<u8, usize>,
     pub endpoints: Vec<UsbEndpointDescriptor>,
+    pub control_requests: Vec<ControlRequest>,
+    pub generated_code: Vec<String>,
 }
 
 pub struct DeviceBuilder {
     pub descriptor: UsbDeviceDescriptor,
-    pub configuration_desc: UsbConfigurationDescriptor,
-    pub interfaces: Vec<InterfaceBuilder>,
+    pub configurations: Vec<ConfigurationBuilder>,
+    pub capabilities: Vec<UsbDeviceCapability>,
+    pub languages: Vec<u16>,
+    pub translations: HashMap<(u16, String), String>,
+    pub ms_os_20_vendor_code: Option<u8>,
+    pub ms_os_20_windows_version: u32,
+    pub ms_os_20_alt_enum_code: u8,
+    pub ms_os_10_vendor_code: Option<u8>,
+    pub webusb: Option<WebUsbOptions>,
+    pub control_requests: Vec<ControlRequest>,
+    pub generated_code: Vec<String>,
+    errors: Vec<BuilderError>,
 }
 
 impl DeviceBuilder {
     pub fn new(vid_pid: UsbVidPid) -> Self {
         Self {
             descriptor: UsbDeviceDescriptor {
+                usb_release: 0x0200,
                 device_class: 0,
                 device_sub_class: 0,
                 device_protocol: 0,
@@ -55,17 +76,30 @@ impl DeviceBuilder {
                 product: UsbString::None,
                 serial_number: UsbString::None,
             },
-            configuration_desc: UsbConfigurationDescriptor {
-                configuration_value: 1,
-                configuration_string: UsbString::None,
-                attributes: 0x80,
-                max_power: 50,
-            },
-            interfaces: Vec::new(),
+            configurations: vec![ConfigurationBuilder::new(1)],
+            capabilities: Vec::new(),
+            languages: vec![lang_id::ENGLISH_US],
+            translations: HashMap::new(),
+            ms_os_20_vendor_code: None,
+            ms_os_20_windows_version: msos::WINDOWS_VERSION_8_1,
+            ms_os_20_alt_enum_code: 0,
+            ms_os_10_vendor_code: None,
+            webusb: None,
+            control_requests: Vec::new(),
+            generated_code: Vec::new(),
+            errors: Vec::new(),
         }
     }
 
     generate_field_setters! {
+        /// Sets the USB specification release number in BCD, for example `0x0210` for USB 2.1.
+        ///
+        /// If any device capabilities are added, values below `0x0201` are raised to `0x0201` so
+        /// that the host requests the BOS descriptor.
+        ///
+        /// Default: `0x0200` ("2.0")
+        usb_release: u16,
+
         /// Sets the device class code assigned by USB.org. Set to `0xff` for vendor-specific
         /// devices that do not conform to any class.
         ///
@@ -96,52 +130,49 @@ impl DeviceBuilder {
     ///
     /// Default: 8 bytes
     pub fn max_packet_size_0(mut self, max_packet_size_0: u8) -> Self {
-        match max_packet_size_0 {
-            8 | 16 | 32 | 64 => {}
-            _ => panic!("invalid max_packet_size_0"),
-        }
-
         self.descriptor.max_packet_size_0 = max_packet_size_0;
         self
     }
 
-    /// Sets whether the device may have an external power source.
+    /// Sets whether the device may have an external power source in the first configuration.
     ///
     /// This should be set to `true` even if the device is sometimes self-powered and may not
     /// always draw power from the USB bus.
     ///
     /// Default: `false`
     ///
-    /// See also: `max_power`
+    /// See also: `max_power`, `ConfigurationBuilder::self_powered`
     pub fn self_powered(mut self, self_powered: bool) -> Self {
-        self.configuration_desc.attributes.set_bit(6, self_powered);
+        self.configurations[0]
+            .descriptor
+            .attributes
+            .set_bit(6, self_powered);
         self
     }
 
-    /// Sets whether the device supports remotely waking up the host is requested.
+    /// Sets whether the device supports remotely waking up the host is requested in the first
+    /// configuration.
     ///
     /// Default: `false`
     pub fn supports_remote_wakeup(mut self, supports_remote_wakeup: bool) -> Self {
-        self.configuration_desc
+        self.configurations[0]
+            .descriptor
             .attributes
             .set_bit(5, supports_remote_wakeup);
         self
     }
 
-    /// Sets the maximum current drawn from the USB bus by the device in milliamps.
+    /// Sets the maximum current drawn from the USB bus by the device in milliamps in the first
+    /// configuration.
     ///
     /// The default is 100 mA. If your device always uses an external power source and never draws
     /// power from the USB bus, this can be set to 0.
     ///
-    /// See also: `self_powered`
+    /// See also: `self_powered`, `ConfigurationBuilder::max_power`
     ///
     /// Default: 100mA
     pub fn max_power(mut self, max_power_ma: usize) -> Self {
-        if max_power_ma > 500 {
-            panic!("max_power is too much")
-        }
-
-        self.configuration_desc.max_power = (max_power_ma / 2) as u8;
+        self.configurations[0].max_power_ma = max_power_ma;
         self
     }
 
@@ -169,76 +200,503 @@ impl DeviceBuilder {
         self
     }
 
-    /// Sets the configuration string descriptor.
+    /// Adds a device capability to the Binary Object Store (BOS) descriptor.
+    ///
+    /// Default: (none, no BOS descriptor is generated)
+    pub fn capability(mut self, capability: UsbDeviceCapability) -> Self {
+        self.capabilities.push(capability);
+        self
+    }
+
+    /// Sets the language of the strings given to the builders. It is listed first in the string
+    /// descriptor 0 and used when the host requests a language without a translation.
+    ///
+    /// Default: `lang_id::ENGLISH_US`
+    pub fn primary_language(mut self, lang_id: u16) -> Self {
+        // Keep every language with translations, including the previous primary language
+        let translations = &self.translations;
+        let translated = self.languages.iter().filter(|&&l| {
+            l != lang_id && translations.keys().any(|&(translated, _)| translated == l)
+        });
+        self.languages = std::iter::once(lang_id)
+            .chain(translated.cloned())
+            .collect();
+        self
+    }
+
+    /// Adds a translation of a string to another language. `string` is the text given to the
+    /// builders in the primary language, the language is added to the list of supported
+    /// languages.
+    ///
+    /// Strings without a translation fall back to the primary language. The text of the primary
+    /// language always comes from the builders, a translation to the primary language (set by
+    /// `primary_language` before or after this call) defers an error until `try_build`.
+    pub fn translation(
+        mut self,
+        lang_id: u16,
+        string: impl Into<String>,
+        translation: impl Into<String>,
+    ) -> Self {
+        if !self.languages.contains(&lang_id) {
+            self.languages.push(lang_id);
+        }
+        self.translations
+            .insert((lang_id, string.into()), translation.into());
+        self
+    }
+
+    /// Enables the MS OS 2.0 descriptor set built from the MS OS features of the interfaces, see
+    /// `InterfaceBuilder::ms_os_compatible_id`. The set is returned by the generated code for
+    /// vendor requests with the given request code, which is announced to the host in a BOS
+    /// platform capability.
+    ///
+    /// Default: (disabled)
+    pub fn ms_os_20_descriptors(mut self, vendor_code: u8) -> Self {
+        self.ms_os_20_vendor_code = Some(vendor_code);
+        self
+    }
+
+    /// Sets the minimum Windows version the MS OS 2.0 descriptor set applies to
+    /// (`dwWindowsVersion`), in the format of `msos::WINDOWS_VERSION_8_1`.
+    ///
+    /// Default: `msos::WINDOWS_VERSION_8_1`
+    pub fn ms_os_20_windows_version(mut self, windows_version: u32) -> Self {
+        self.ms_os_20_windows_version = windows_version;
+        self
+    }
+
+    /// Sets the alternate enumeration code announced in the MS OS 2.0 platform capability. A
+    /// non-zero code makes Windows send SET_ALT_ENUMERATION, which the application has to handle.
+    ///
+    /// Default: `0` (no alternate enumeration)
+    pub fn ms_os_20_alt_enum_code(mut self, alt_enum_code: u8) -> Self {
+        self.ms_os_20_alt_enum_code = alt_enum_code;
+        self
+    }
+
+    /// Enables the MS OS 1.0 descriptors for hosts that don't support MS OS 2.0: the `MSFT100`
+    /// string descriptor at index `0xee`, and the Extended Compat ID and Extended Properties OS
+    /// feature descriptors built from the MS OS features of the interfaces in the first
+    /// configuration. The feature descriptors are returned by the generated code for vendor
+    /// requests with the given request code.
+    ///
+    /// Default: (disabled)
+    pub fn ms_os_10_descriptors(mut self, vendor_code: u8) -> Self {
+        self.ms_os_10_vendor_code = Some(vendor_code);
+        self
+    }
+
+    /// Enables WebUSB: the landing page and allowed origins are returned by the generated code
+    /// for vendor requests with the vendor code of `options`, which is announced to the host in a
+    /// BOS platform capability. `usb_release` is raised to `0x0210` if it is lower.
+    ///
+    /// Default: (disabled)
+    pub fn webusb(mut self, options: WebUsbOptions) -> Self {
+        self.webusb = Some(options);
+        self
+    }
+
+    /// Adds a control request answered by the generated code.
+    pub fn add_control_request(&mut self, request: ControlRequest) {
+        if let ControlResponse::Accept = request.response {
+            if request.is_standard_in_request() {
+                self.defer_error(BuilderError::AcceptedInRequest {
+                    request: request.request,
+                });
+            }
+        }
+        self.control_requests.push(request);
+    }
+
+    /// Adds code to the `generated` module, e.g. a class that answers control requests which need
+    /// more than a static response.
+    pub fn add_generated_code(&mut self, code: String) {
+        self.generated_code.push(code);
+    }
+
+    /// Defers an error found by a class helper until `try_build`, which returns the first deferred
+    /// error.
+    pub fn defer_error(&mut self, error: BuilderError) {
+        self.errors.push(error);
+    }
+
+    /// Checks the type and direction of an endpoint passed to a class helper, deferring an
+    /// `UnexpectedEndpointType` error if they don't match.
+    pub fn check_endpoint(
+        &mut self,
+        endpoint: &(impl EndpointInfo + ?Sized),
+        ep_type: EndpointType,
+        direction: UsbDirection,
+    ) {
+        if endpoint.ep_type() != ep_type || endpoint.direction() != direction {
+            self.defer_error(BuilderError::UnexpectedEndpointType {
+                address: endpoint.address().into(),
+                ep_type,
+                direction,
+            });
+        }
+    }
+
+    /// Sets the configuration string descriptor of the first configuration.
     ///
     /// Default: (none)
     pub fn configuration(mut self, configuration: impl Into<String>) -> Self {
-        self.configuration_desc.configuration_string = UsbString::Const(configuration.into());
+        self.configurations[0].descriptor.configuration_string =
+            UsbString::Const(configuration.into());
         self
     }
 
+    fn add_configuration(&mut self, configuration: ConfigurationBuilder) {
+        let value = configuration.descriptor.configuration_value;
+        match self
+            .configurations
+            .get_mut((value as usize).wrapping_sub(1))
+        {
+            Some(slot) => {
+                slot.descriptor = configuration.descriptor;
+                slot.max_power_ma = configuration.max_power_ma;
+            }
+            None => self.errors.push(BuilderError::ConfigurationNotAllocated {
+                configuration: value,
+            }),
+        }
+    }
+
+    /// Allocates a new configuration. Interfaces allocated after this call belong to the new
+    /// configuration.
+    pub fn alloc_configuration(&mut self) -> ConfigurationBuilder {
+        let value = self.configurations.len() + 1;
+        let builder = ConfigurationBuilder::new(value as u8);
+        self.configurations.push(builder.clone());
+        builder
+    }
+
     fn add_interface(&mut self, interface: InterfaceBuilder) {
-        let index = interface.descriptor.interface_number as usize;
-        assert!(index < self.interfaces.len());
-        assert_eq!(interface.descriptor.alternate_setting, 0); // Alternate settings are not supported yet
-        assert!(!interface.endpoints.is_empty());
+        let configuration = &mut self.configurations[interface.configuration_index];
+        let slot = configuration.interfaces.iter_mut().find(|i| {
+            i.descriptor.interface_number == interface.descriptor.interface_number
+                && i.descriptor.alternate_setting == interface.descriptor.alternate_setting
+        });
+
+        match slot {
+            Some(slot) => *slot = interface,
+            None => self.errors.push(BuilderError::InterfaceNotAllocated {
+                configuration: configuration.descriptor.configuration_value,
+                interface: interface.descriptor.interface_number,
+                alternate_setting: interface.descriptor.alternate_setting,
+            }),
+        }
+    }
+
+    fn add_function(&mut self, function: FunctionBuilder) {
+        let configuration = &mut self.configurations[function.configuration_index];
+        let mut descriptor = function.descriptor;
+        descriptor.interface_count = configuration.interface_count() - descriptor.first_interface;
+
+        if descriptor.interface_count > 0 {
+            configuration.functions.push(descriptor);
+        } else {
+            self.errors.push(BuilderError::FunctionWithoutInterfaces {
+                configuration: configuration.descriptor.configuration_value,
+                first_interface: descriptor.first_interface,
+            });
+        }
+    }
 
-        self.interfaces[index] = interface;
+    /// Starts a new function in the most recently allocated configuration.
+    ///
+    /// All interfaces allocated between this call and `FunctionBuilder::save` are grouped with an
+    /// Interface Association Descriptor. When any function is present, the device class is set to
+    /// `0xef`/`0x02`/`0x01` (Miscellaneous, Interface Association Descriptor); other device classes
+    /// than `0x00` are reported as an error by `try_build`.
+    pub fn alloc_function(&mut self) -> FunctionBuilder {
+        let configuration_index = self.configurations.len() - 1;
+        let first_interface = self.configurations[configuration_index].interface_count();
+        FunctionBuilder::new(configuration_index, first_interface)
+    }
+
+    /// Returns the number of the interface allocated next by `alloc_interface`, e.g. to report an
+    /// error in the settings of a function before allocating its interfaces.
+    pub fn next_interface_number(&self) -> u8 {
+        self.configurations.last().unwrap().interface_count()
     }
 
+    /// Allocates a new interface in the most recently allocated configuration.
     pub fn alloc_interface(&mut self) -> InterfaceBuilder {
-        let index = self.interfaces.len();
-        let builder = InterfaceBuilder::new(index as u8);
-        self.interfaces.push(builder.clone());
+        let configuration_index = self.configurations.len() - 1;
+        let configuration = &mut self.configurations[configuration_index];
+        let index = configuration.interface_count();
+        let builder = InterfaceBuilder::new(configuration_index, index, 0);
+        configuration.interfaces.push(builder.clone());
         builder
     }
 
+    /// Allocates the next alternate setting of an already allocated interface.
+    ///
+    /// Alternate settings are written to the configuration descriptor right after the interface
+    /// they belong to, in the order of allocation. An interface has at most 256 alternate
+    /// settings, allocating more defers an error until `try_build`.
+    ///
+    /// Alternate settings may reuse the endpoints of the interface with a different max packet
+    /// size, the endpoint is then configured with the largest one. The endpoint type has to be
+    /// the same in all of them.
+    pub fn alloc_alternate_setting(&mut self, interface: &InterfaceBuilder) -> InterfaceBuilder {
+        let configuration_index = interface.configuration_index;
+        let interface_number = interface.descriptor.interface_number;
+        let configuration = &mut self.configurations[configuration_index];
+        let count = configuration
+            .interfaces
+            .iter()
+            .filter(|i| i.descriptor.interface_number == interface_number)
+            .count();
+        match u8::try_from(count) {
+            Ok(alternate_setting) => {
+                let builder =
+                    InterfaceBuilder::new(configuration_index, interface_number, alternate_setting);
+                configuration.interfaces.push(builder.clone());
+                builder
+            }
+            Err(_) => {
+                let error = BuilderError::TooManyAlternateSettings {
+                    configuration: configuration.descriptor.configuration_value,
+                    interface: interface_number,
+                };
+                self.errors.push(error);
+                InterfaceBuilder::new(configuration_index, interface_number, u8::MAX)
+            }
+        }
+    }
+
+    /// Builds the device configuration.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the device is misconfigured. See `try_build` for a non-panicking version.
     pub fn build(self) -> DeviceConfig {
-        assert!(!self.interfaces.is_empty());
+        self.try_build().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Builds the device configuration, reporting the first error found in the device
+    /// specification, including the errors deferred by `save` methods of other builders.
+    pub fn try_build(mut self) -> Result<DeviceConfig, BuilderError> {
+        if !self.errors.is_empty() {
+            return Err(self.errors.remove(0));
+        }
+
+        match self.descriptor.max_packet_size_0 {
+            8 | 16 | 32 | 64 => {}
+            size => return Err(BuilderError::InvalidEp0Size(size)),
+        }
+
+        for configuration in &mut self.configurations {
+            configuration.validate()?;
+        }
+
+        for capability in &self.capabilities {
+            if let UsbDeviceCapability::Usb20Extension {
+                baseline_besl,
+                deep_besl,
+                ..
+            } = capability
+            {
+                for &besl in baseline_besl.iter().chain(deep_besl) {
+                    if besl > 15 {
+                        return Err(BuilderError::InvalidBesl(besl));
+                    }
+                }
+            }
+        }
+
+        let primary_language = self.languages[0];
+        if self
+            .translations
+            .keys()
+            .any(|&(l, _)| l == primary_language)
+        {
+            return Err(BuilderError::TranslationToPrimaryLanguage {
+                lang_id: primary_language,
+            });
+        }
+
+        // Compatible IDs are only reported to the host in MS OS descriptors
+        if self.ms_os_20_vendor_code.is_none() && self.ms_os_10_vendor_code.is_none() {
+            for interface in self.configurations.iter().flat_map(|c| &c.interfaces) {
+                if interface
+                    .ms_os_features
+                    .iter()
+                    .any(|feature| matches!(feature, MsOsFeature::CompatibleId { .. }))
+                {
+                    return Err(BuilderError::MsOsDescriptorsDisabled {
+                        interface: interface.descriptor.interface_number,
+                    });
+                }
+            }
+        }
+
+        // Generate MS OS 2.0 descriptor set
+        if let Some(vendor_code) = self.ms_os_20_vendor_code {
+            let options = MsOs20Options {
+                vendor_code,
+                windows_version: self.ms_os_20_windows_version,
+                alt_enum_code: self.ms_os_20_alt_enum_code,
+            };
+            let set = msos::descriptor_set(&options, &self.configurations)?;
+            self.capabilities
+                .push(msos::platform_capability(&options, set.len()));
+            self.control_requests.push(
+                ControlRequest::new(
+                    RequestType::Vendor,
+                    Recipient::Device,
+                    options.vendor_code,
+                    ControlResponse::Data(set),
+                )
+                .index(msos::MS_OS_20_DESCRIPTOR_INDEX),
+            );
+        }
+
+        // Generate MS OS 1.0 feature descriptors
+        if let Some(vendor_code) = self.ms_os_10_vendor_code {
+            let requests = msos::ms_os_10_requests(vendor_code, &self.configurations[0])?;
+            self.control_requests.extend(requests);
+        }
+
+        // Generate WebUSB capability and URL descriptors
+        if let Some(options) = &self.webusb {
+            let (capability, requests) = webusb::webusb_descriptors(options, &self.configurations)?;
+            self.capabilities.push(capability);
+            self.control_requests.extend(requests);
+            if self.descriptor.usb_release < 0x0210 {
+                self.descriptor.usb_release = 0x0210;
+            }
+        }
+
+        if !self.capabilities.is_empty() && self.descriptor.usb_release < 0x0201 {
+            self.descriptor.usb_release = 0x0201;
+        }
+
+        if self.configurations.iter().any(|c| !c.functions.is_empty()) {
+            let descriptor = &mut self.descriptor;
+            match (
+                descriptor.device_class,
+                descriptor.device_sub_class,
+                descriptor.device_protocol,
+            ) {
+                (0x00, _, _) | (0xef, 0x02, 0x01) => {
+                    descriptor.device_class = 0xef;
+                    descriptor.device_sub_class = 0x02;
+                    descriptor.device_protocol = 0x01;
+                }
+                (device_class, device_sub_class, device_protocol) => {
+                    return Err(BuilderError::DeviceClassWithFunctions {
+                        device_class,
+                        device_sub_class,
+                        device_protocol,
+                    })
+                }
+            }
+        }
 
         // Allocate strings
-        let mut str_alloc = UsbStringAllocator::new();
+        let mut str_alloc =
+            UsbStringAllocator::new().reserve_ms_os_string(self.ms_os_10_vendor_code.is_some());
         str_alloc.alloc(&self.descriptor.manufacturer);
         str_alloc.alloc(&self.descriptor.product);
         str_alloc.alloc(&self.descriptor.serial_number);
-        str_alloc.alloc(&self.configuration_desc.configuration_string);
-        for interface in &self.interfaces {
-            str_alloc.alloc(&interface.descriptor.interface_string);
+        for configuration in &self.configurations {
+            str_alloc.alloc(&configuration.descriptor.configuration_string);
+            for function in &configuration.functions {
+                str_alloc.alloc(&function.function_string);
+            }
+            for interface in &configuration.interfaces {
+                str_alloc.alloc(&interface.descriptor.interface_string);
+                let endpoint_descriptors = interface
+                    .endpoints
+                    .iter()
+                    .flat_map(|ep| ep.custom_descriptors.iter());
+                for custom in interface
+                    .custom_descriptors
+                    .iter()
+                    .chain(endpoint_descriptors)
+                {
+                    for (_, string) in &custom.strings {
+                        str_alloc.alloc(string);
+                    }
+                }
+            }
         }
 
         // Generate device descriptor
         let mut w = UsbDescriptorWriter::new();
-        w.device(&self.descriptor, 1, &str_alloc);
+        w.device(
+            &self.descriptor,
+            self.configurations.len() as u8,
+            &str_alloc,
+        );
         let device_descriptor = w.finish();
 
-        // Generate configuration descriptor
-        let mut w = UsbDescriptorWriter::new();
-        w.configuration(&self.configuration_desc, &str_alloc);
-        for interface in &self.interfaces {
-            w.interface(&interface.descriptor, &str_alloc);
-            for custom in &interface.custom_descriptors {
-                w.custom_descriptor(custom);
-            }
-            for endpoint in &interface.endpoints {
-                w.endpoint(&endpoint);
+        // Generate configuration descriptors
+        let mut configuration_descriptors = Vec::new();
+        for configuration in &self.configurations {
+            let mut w = UsbDescriptorWriter::new();
+            w.configuration(&configuration.descriptor, &str_alloc);
+            for interface in &configuration.interfaces {
+                if interface.descriptor.alternate_setting == 0 {
+                    let number = interface.descriptor.interface_number;
+                    for function in &configuration.functions {
+                        if function.first_interface == number {
+                            w.interface_association(function, &str_alloc);
+                        }
+                    }
+                }
+                w.interface(&interface.descriptor, &str_alloc);
+                for custom in &interface.custom_descriptors {
+                    w.custom_descriptor(custom, &str_alloc);
+                }
+                for endpoint in &interface.endpoints {
+                    w.endpoint(endpoint, &str_alloc);
+                }
             }
+            configuration_descriptors.push(w.finish());
         }
-        let configuration_descriptor = w.finish();
+
+        // Generate BOS descriptor
+        let bos_descriptor = if self.capabilities.is_empty() {
+            None
+        } else {
+            let mut w = UsbDescriptorWriter::new();
+            w.bos(&self.capabilities);
+            Some(w.finish())
+        };
 
         // Generate string descriptors
         let mut string_descriptors = HashMap::new();
+        let mut translated_string_descriptors = HashMap::new();
         let mut custom_strings = HashMap::new();
         let strings = str_alloc.into_inner();
         for (i, s) in strings.into_iter().enumerate() {
             match s {
+                UsbString::None if i != 0 => {
+                    // reserved index
+                }
                 UsbString::None => {
                     let mut w = UsbDescriptorWriter::new();
                     // list of supported languages
-                    let supported_languages = lang_id::ENGLISH_US.to_le_bytes();
+                    let mut supported_languages = Vec::new();
+                    for lang_id in &self.languages {
+                        supported_languages.extend_from_slice(&lang_id.to_le_bytes());
+                    }
                     w.write(UsbDescriptorType::String as u8, &supported_languages);
                     string_descriptors.insert(i as u8, w.finish());
                 }
                 UsbString::Const(s) => {
+                    for &lang_id in &self.languages[1..] {
+                        if let Some(translation) = self.translations.get(&(lang_id, s.clone())) {
+                            let mut w = UsbDescriptorWriter::new();
+                            w.string(translation);
+                            translated_string_descriptors.insert((lang_id, i as u8), w.finish());
+                        }
+                    }
+
                     let mut w = UsbDescriptorWriter::new();
                     w.string(&s);
                     string_descriptors.insert(i as u8, w.finish());
@@ -248,51 +706,273 @@ impl DeviceBuilder {
                 }
             }
         }
+        if let Some(vendor_code) = self.ms_os_10_vendor_code {
+            string_descriptors.insert(MS_OS_STRING_INDEX, msos::os_string_descriptor(vendor_code));
+        }
 
         // Generate endpoint list
         let mut endpoints = Vec::new();
         endpoints.push(UsbEndpointDescriptor {
-            address: EndpointAddress::from_parts(0, UsbDirection::Out).into(),
+            address: EndpointAddress::from_parts(0, UsbDirection::Out),
             attributes: EndpointType::Control as u8,
             max_packet_size: u16::from(self.descriptor.max_packet_size_0),
             interval: 0,
+            extra_fields: Vec::new(),
+            custom_descriptors: Vec::new(),
         });
         endpoints.push(UsbEndpointDescriptor {
-            address: EndpointAddress::from_parts(0, UsbDirection::In).into(),
+            address: EndpointAddress::from_parts(0, UsbDirection::In),
             attributes: EndpointType::Control as u8,
             max_packet_size: u16::from(self.descriptor.max_packet_size_0),
             interval: 0,
+            extra_fields: Vec::new(),
+            custom_descriptors: Vec::new(),
         });
-        for interface in self.interfaces {
-            for endpoint in interface.endpoints {
-                endpoints.push(endpoint);
+        for configuration in self.configurations {
+            let value = configuration.descriptor.configuration_value;
+            // Alternate settings may reuse an endpoint, it is configured with the largest size
+            let mut configuration_endpoints: Vec<UsbEndpointDescriptor> = Vec::new();
+            for interface in configuration.interfaces {
+                for endpoint in interface.endpoints {
+                    let existing = configuration_endpoints
+                        .iter_mut()
+                        .find(|ep| ep.address == endpoint.address);
+                    match existing {
+                        None => configuration_endpoints.push(endpoint),
+                        Some(ep) if ep.attributes != endpoint.attributes => {
+                            return Err(BuilderError::ConflictingEndpoint {
+                                configuration: value,
+                                address: endpoint.address.into(),
+                            });
+                        }
+                        Some(ep) => {
+                            ep.max_packet_size = ep.max_packet_size.max(endpoint.max_packet_size);
+                        }
+                    }
+                }
+            }
+            for endpoint in configuration_endpoints {
+                // The same endpoint may be used in several configurations, but it is configured
+                // once, so it has to be the same in all of them
+                match endpoints.iter().find(|ep| ep.address == endpoint.address) {
+                    None => endpoints.push(endpoint),
+                    Some(ep)
+                        if ep.attributes != endpoint.attributes
+                            || ep.max_packet_size != endpoint.max_packet_size =>
+                    {
+                        return Err(BuilderError::ConflictingEndpoint {
+                            configuration: value,
+                            address: endpoint.address.into(),
+                        });
+                    }
+                    Some(_) => {}
+                }
             }
         }
 
-        DeviceConfig {
+        Ok(DeviceConfig {
             ep0_max_packet_size: self.descriptor.max_packet_size_0,
             device_descriptor,
-            configuration_descriptor,
+            configuration_descriptors,
+            bos_descriptor,
             string_descriptors,
+            translated_string_descriptors,
             custom_strings,
             endpoints,
+            control_requests: self.control_requests,
+            generated_code: self.generated_code,
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct ConfigurationBuilder {
+    pub descriptor: UsbConfigurationDescriptor,
+    pub max_power_ma: usize,
+    pub functions: Vec<UsbInterfaceAssociationDescriptor>,
+    pub interfaces: Vec<InterfaceBuilder>,
+}
+
+impl ConfigurationBuilder {
+    fn new(configuration_value: u8) -> Self {
+        Self {
+            descriptor: UsbConfigurationDescriptor {
+                configuration_value,
+                configuration_string: UsbString::None,
+                attributes: 0x80,
+                max_power: 50,
+            },
+            max_power_ma: 100,
+            functions: Vec::new(),
+            interfaces: Vec::new(),
         }
     }
+
+    fn interface_count(&self) -> u8 {
+        self.interfaces
+            .iter()
+            .filter(|i| i.descriptor.alternate_setting == 0)
+            .count() as u8
+    }
+
+    fn validate(&mut self) -> Result<(), BuilderError> {
+        let configuration = self.descriptor.configuration_value;
+
+        if self.max_power_ma > 500 {
+            return Err(BuilderError::MaxPowerTooHigh {
+                configuration,
+                max_power_ma: self.max_power_ma,
+            });
+        }
+        self.descriptor.max_power = (self.max_power_ma / 2) as u8;
+
+        if self.interfaces.is_empty() {
+            return Err(BuilderError::ConfigurationWithoutInterfaces { configuration });
+        }
+
+        self.interfaces.sort_by_key(|i| {
+            (
+                i.descriptor.interface_number,
+                i.descriptor.alternate_setting,
+            )
+        });
+
+        for (i, interface) in self.interfaces.iter().enumerate() {
+            let number = interface.descriptor.interface_number;
+
+            // Alternate settings of one interface may share endpoints, different interfaces may not
+            for (j, endpoint) in interface.endpoints.iter().enumerate() {
+                let address = endpoint.address;
+                let used = interface.endpoints[..j]
+                    .iter()
+                    .any(|ep| ep.address == address)
+                    || self.interfaces[..i]
+                        .iter()
+                        .filter(|other| other.descriptor.interface_number != number)
+                        .any(|other| other.endpoints.iter().any(|ep| ep.address == address));
+                if used {
+                    return Err(BuilderError::EndpointUsedTwice {
+                        configuration,
+                        interface: number,
+                        address: address.into(),
+                    });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets whether the device may have an external power source in this configuration.
+    ///
+    /// Default: `false`
+    ///
+    /// See also: `max_power`
+    pub fn self_powered(mut self, self_powered: bool) -> Self {
+        self.descriptor.attributes.set_bit(6, self_powered);
+        self
+    }
+
+    /// Sets whether the device supports remotely waking up the host is requested in this
+    /// configuration.
+    ///
+    /// Default: `false`
+    pub fn supports_remote_wakeup(mut self, supports_remote_wakeup: bool) -> Self {
+        self.descriptor
+            .attributes
+            .set_bit(5, supports_remote_wakeup);
+        self
+    }
+
+    /// Sets the maximum current drawn from the USB bus by the device in milliamps in this
+    /// configuration.
+    ///
+    /// See also: `self_powered`
+    ///
+    /// Default: 100mA
+    pub fn max_power(mut self, max_power_ma: usize) -> Self {
+        self.max_power_ma = max_power_ma;
+        self
+    }
+
+    /// Sets the configuration string descriptor.
+    ///
+    /// Default: (none)
+    pub fn configuration(mut self, configuration: impl Into<String>) -> Self {
+        self.descriptor.configuration_string = UsbString::Const(configuration.into());
+        self
+    }
+
+    pub fn save(self, device: &mut DeviceBuilder) {
+        device.add_configuration(self)
+    }
+}
+
+pub struct FunctionBuilder {
+    pub configuration_index: usize,
+    pub descriptor: UsbInterfaceAssociationDescriptor,
+}
+
+impl FunctionBuilder {
+    fn new(configuration_index: usize, first_interface: u8) -> Self {
+        Self {
+            configuration_index,
+            descriptor: UsbInterfaceAssociationDescriptor {
+                first_interface,
+                interface_count: 0,
+                function_class: 0,
+                function_sub_class: 0,
+                function_protocol: 0,
+                function_string: UsbString::None,
+            },
+        }
+    }
+
+    generate_field_setters! {
+        function_class: u8,
+        function_sub_class: u8,
+        function_protocol: u8,
+    }
+
+    /// Sets the function string descriptor.
+    ///
+    /// Default: (none)
+    pub fn function_string(mut self, function_string: impl Into<String>) -> Self {
+        self.descriptor.function_string = UsbString::Const(function_string.into());
+        self
+    }
+
+    /// Sets the function string descriptor to a string provided at runtime by
+    /// `CustomStringDescriptorProvider` with the given `id`.
+    ///
+    /// Default: (none)
+    pub fn custom_function_string(mut self, id: usize) -> Self {
+        self.descriptor.function_string = UsbString::Custom(id);
+        self
+    }
+
+    /// Groups the interfaces allocated since `DeviceBuilder::alloc_function` into this function.
+    pub fn save(self, device: &mut DeviceBuilder) {
+        device.add_function(self)
+    }
 }
 
 #[derive(Clone)]
 pub struct InterfaceBuilder {
+    pub configuration_index: usize,
     pub descriptor: UsbInterfaceDescriptor,
     pub custom_descriptors: Vec<UsbCustomDescriptor>,
     pub endpoints: Vec<UsbEndpointDescriptor>,
+    pub ms_os_features: Vec<MsOsFeature>,
 }
 
 impl InterfaceBuilder {
-    fn new(interface_number: u8) -> Self {
+    fn new(configuration_index: usize, interface_number: u8, alternate_setting: u8) -> Self {
         Self {
+            configuration_index,
             descriptor: UsbInterfaceDescriptor {
                 interface_number,
-                alternate_setting: 0,
+                alternate_setting,
                 interface_class: 0,
                 interface_sub_class: 0,
                 interface_protocol: 0,
@@ -300,25 +980,76 @@ impl InterfaceBuilder {
             },
             custom_descriptors: Vec::new(),
             endpoints: Vec::new(),
+            ms_os_features: Vec::new(),
         }
     }
 
     generate_field_setters! {
-        alternate_setting: u8,
         interface_class: u8,
         interface_sub_class: u8,
         interface_protocol: u8,
     }
 
+    /// Sets the interface string descriptor.
+    ///
+    /// Default: (none)
+    pub fn interface_string(mut self, interface_string: impl Into<String>) -> Self {
+        self.descriptor.interface_string = UsbString::Const(interface_string.into());
+        self
+    }
+
+    /// Sets the interface string descriptor to a string provided at runtime by
+    /// `CustomStringDescriptorProvider` with the given `id`.
+    ///
+    /// Default: (none)
+    pub fn custom_interface_string(mut self, id: usize) -> Self {
+        self.descriptor.interface_string = UsbString::Custom(id);
+        self
+    }
+
+    /// Adds a class-specific descriptor written after the interface descriptor, before the
+    /// endpoints. See `UsbEndpointDescriptor::descriptor` for descriptors following an endpoint.
     pub fn descriptor(mut self, descriptor_type: u8, descriptor: &[u8]) -> Self {
-        let custom_descriptor = UsbCustomDescriptor {
-            descriptor_type,
-            data: descriptor.to_vec(),
-        };
+        let custom_descriptor = UsbCustomDescriptor::new(descriptor_type, descriptor);
         self.custom_descriptors.push(custom_descriptor);
         self
     }
 
+    /// Adds a class-specific descriptor that may reference string descriptors.
+    pub fn custom_descriptor(mut self, descriptor: UsbCustomDescriptor) -> Self {
+        self.custom_descriptors.push(descriptor);
+        self
+    }
+
+    /// Sets the Microsoft OS compatible ID of the interface, for example `"WINUSB"` to bind the
+    /// WinUSB driver without an INF file. MS OS 1.0 or 2.0 descriptors have to be enabled on the
+    /// device.
+    pub fn ms_os_compatible_id(
+        mut self,
+        compatible_id: impl Into<String>,
+        sub_compatible_id: impl Into<String>,
+    ) -> Self {
+        self.ms_os_features.push(MsOsFeature::CompatibleId {
+            compatible_id: compatible_id.into(),
+            sub_compatible_id: sub_compatible_id.into(),
+        });
+        self
+    }
+
+    /// Adds a Microsoft OS registry property of the interface, for example
+    /// `DeviceInterfaceGUIDs`.
+    pub fn ms_os_registry_property(
+        mut self,
+        name: impl Into<String>,
+        value: RegistryValue,
+    ) -> Self {
+        self.ms_os_features.push(MsOsFeature::RegistryProperty {
+            name: name.into(),
+            value,
+        });
+        self
+    }
+
     pub fn endpoint(mut self, endpoint: UsbEndpointDescriptor) -> Self {
         self.endpoints.push(endpoint);
         self
@@ -335,6 +1066,13 @@ pub struct EndpointBuilder {
     pub ep_type: Option<EndpointType>,
     pub max_packet_size: Option<u16>,
     pub interval: u8,
+    pub custom_descriptors: Vec<UsbCustomDescriptor>,
+}
+
+impl Default for EndpointBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl EndpointBuilder {
@@ -345,30 +1083,26 @@ impl EndpointBuilder {
             ep_type: None,
             max_packet_size: None,
             interval: 0,
+            custom_descriptors: Vec::new(),
         }
     }
 
     pub fn number(mut self, number: u8) -> Self {
-        assert!(number < 16);
-        assert!(self.number.is_none());
         self.number = Some(number);
         self
     }
 
     pub fn direction(mut self, direction: UsbDirection) -> Self {
-        assert!(self.direction.is_none());
         self.direction = Some(direction);
         self
     }
 
     pub fn ep_type(mut self, ep_type: EndpointType) -> Self {
-        assert!(self.ep_type.is_none());
         self.ep_type = Some(ep_type);
         self
     }
 
     pub fn max_packet_size(mut self, max_packet_size: u16) -> Self {
-        assert!(self.max_packet_size.is_none());
         self.max_packet_size = Some(max_packet_size);
         self
     }
@@ -378,15 +1112,588 @@ impl EndpointBuilder {
         self
     }
 
+    /// Adds a class-specific descriptor written right after the endpoint descriptor.
+    pub fn descriptor(mut self, descriptor_type: u8, descriptor: &[u8]) -> Self {
+        let custom_descriptor = UsbCustomDescriptor::new(descriptor_type, descriptor);
+        self.custom_descriptors.push(custom_descriptor);
+        self
+    }
+
+    /// Builds the endpoint descriptor.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a field is missing or invalid. See `try_build` for a non-panicking version.
     pub fn build(self) -> UsbEndpointDescriptor {
-        UsbEndpointDescriptor {
-            address: EndpointAddress::from_parts(
-                self.number.unwrap() as usize,
-                self.direction.unwrap(),
-            ),
-            attributes: self.ep_type.unwrap() as u8,
-            max_packet_size: self.max_packet_size.unwrap(),
+        self.try_build().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    pub fn try_build(self) -> Result<UsbEndpointDescriptor, BuilderError> {
+        let number = self.number.ok_or_else(|| self.missing_field("number"))?;
+        if number as usize >= USB_MAX_ENDPOINTS {
+            return Err(BuilderError::InvalidEndpointNumber(number));
+        }
+        let direction = self
+            .direction
+            .ok_or_else(|| self.missing_field("direction"))?;
+        let ep_type = self.ep_type.ok_or_else(|| self.missing_field("ep_type"))?;
+        let max_packet_size = self
+            .max_packet_size
+            .ok_or_else(|| self.missing_field("max_packet_size"))?;
+
+        Ok(UsbEndpointDescriptor {
+            address: EndpointAddress::from_parts(number as usize, direction),
+            attributes: ep_type as u8,
+            max_packet_size,
             interval: self.interval,
+            extra_fields: Vec::new(),
+            custom_descriptors: self.custom_descriptors,
+        })
+    }
+
+    /// Returns the error for a missing `field`, naming the endpoint as far as it is known.
+    pub(crate) fn missing_field(&self, field: &'static str) -> BuilderError {
+        BuilderError::MissingEndpointField {
+            field,
+            number: self.number,
+            direction: self.direction,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::endpoint;
+
+    #[test]
+    fn multiple_configurations() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        device
+            .alloc_interface()
+            .interface_class(0xff)
+            .endpoint(endpoint(1, UsbDirection::In, EndpointType::Bulk, 64, 0))
+            .save(&mut device);
+        device
+            .alloc_configuration()
+            .self_powered(true)
+            .max_power(500)
+            .configuration("Full feature")
+            .save(&mut device);
+        device
+            .alloc_interface()
+            .interface_class(0xff)
+            .endpoint(endpoint(1, UsbDirection::In, EndpointType::Bulk, 64, 0))
+            .endpoint(endpoint(2, UsbDirection::Out, EndpointType::Bulk, 64, 0))
+            .save(&mut device);
+        let config = device.build();
+
+        assert_eq!(
+            config.device_descriptor,
+            [
+                0x12, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00, 0x08, 0x09, 0x12, 0x01, 0x00, 0x10, 0x00,
+                0x00, 0x00, 0x00, 0x02,
+            ]
+        );
+        assert_eq!(
+            config.configuration_descriptors,
+            [
+                vec![
+                    0x09, 0x02, 0x19, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, // configuration 1
+                    0x09, 0x04, 0x00, 0x00, 0x01, 0xff, 0x00, 0x00, 0x00, // interface 0
+                    0x07, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00, // endpoint 0x81
+                ],
+                vec![
+                    0x09, 0x02, 0x20, 0x00, 0x01, 0x02, 0x01, 0xc0, 0xfa, // configuration 2
+                    0x09, 0x04, 0x00, 0x00, 0x02, 0xff, 0x00, 0x00, 0x00, // interface 0
+                    0x07, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00, // endpoint 0x81
+                    0x07, 0x05, 0x02, 0x02, 0x40, 0x00, 0x00, // endpoint 0x02
+                ],
+            ]
+        );
+        assert_eq!(
+            config.string_descriptors[&1],
+            b"\x1a\x03F\0u\0l\0l\0 \0f\0e\0a\0t\0u\0r\0e\0"
+        );
+        // Endpoints shared by both configurations are configured once
+        assert_eq!(config.endpoints.len(), 4);
+    }
+
+    #[test]
+    fn alternate_settings() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let first = device.alloc_interface();
+        let second = device.alloc_interface();
+        let streaming = device.alloc_alternate_setting(&first);
+        second
+            .interface_class(0xff)
+            .endpoint(endpoint(2, UsbDirection::Out, EndpointType::Bulk, 64, 0))
+            .save(&mut device);
+        streaming
+            .interface_class(0xff)
+            .endpoint(endpoint(1, UsbDirection::In, EndpointType::Bulk, 64, 0))
+            .save(&mut device);
+        first.interface_class(0xff).save(&mut device);
+        let config = device.build();
+
+        // Alternate settings follow the interface they belong to, whatever the allocation order
+        assert_eq!(
+            config.configuration_descriptors[0],
+            [
+                0x09, 0x02, 0x32, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32, // configuration
+                0x09, 0x04, 0x00, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00, // interface 0, alt 0
+                0x09, 0x04, 0x00, 0x01, 0x01, 0xff, 0x00, 0x00, 0x00, // interface 0, alt 1
+                0x07, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00, // endpoint 0x81
+                0x09, 0x04, 0x01, 0x00, 0x01, 0xff, 0x00, 0x00, 0x00, // interface 1, alt 0
+                0x07, 0x05, 0x02, 0x02, 0x40, 0x00, 0x00, // endpoint 0x02
+            ][..]
+        );
+    }
+
+    #[test]
+    fn too_many_alternate_settings() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let interface = device.alloc_interface();
+        for _ in 0..255 {
+            device.alloc_alternate_setting(&interface);
+        }
+        device.alloc_alternate_setting(&interface);
+        interface
+            .endpoint(endpoint(1, UsbDirection::In, EndpointType::Bulk, 64, 0))
+            .save(&mut device);
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::TooManyAlternateSettings {
+                configuration: 1,
+                interface: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn interface_and_function_strings() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let function = device.alloc_function();
+        device
+            .alloc_interface()
+            .interface_class(0xff)
+            .interface_string("Debug console")
+            .endpoint(endpoint(1, UsbDirection::In, EndpointType::Bulk, 64, 0))
+            .save(&mut device);
+        device
+            .alloc_interface()
+            .interface_class(0xff)
+            .custom_interface_string(7)
+            .endpoint(endpoint(2, UsbDirection::In, EndpointType::Bulk, 64, 0))
+            .save(&mut device);
+        function
+            .function_class(0xff)
+            .function_string("Debug port")
+            .save(&mut device);
+        let config = device.build();
+
+        assert_eq!(
+            config.configuration_descriptors[0],
+            [
+                0x09, 0x02, 0x31, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32, // configuration
+                0x08, 0x0b, 0x00, 0x02, 0xff, 0x00, 0x00, 0x01, // interface association
+                0x09, 0x04, 0x00, 0x00, 0x01, 0xff, 0x00, 0x00, 0x02, // interface 0
+                0x07, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00, // endpoint 0x81
+                0x09, 0x04, 0x01, 0x00, 0x01, 0xff, 0x00, 0x00, 0x03, // interface 1
+                0x07, 0x05, 0x82, 0x02, 0x40, 0x00, 0x00, // endpoint 0x82
+            ][..]
+        );
+        assert_eq!(
+            config.string_descriptors[&1],
+            b"\x16\x03D\0e\0b\0u\0g\0 \0p\0o\0r\0t\0"
+        );
+        assert_eq!(
+            config.string_descriptors[&2],
+            b"\x1c\x03D\0e\0b\0u\0g\0 \0c\0o\0n\0s\0o\0l\0e\0"
+        );
+        assert_eq!(config.custom_strings[&3], 7);
+    }
+
+    #[test]
+    fn interface_without_endpoints() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        device
+            .alloc_interface()
+            .interface_class(0xff)
+            .save(&mut device);
+        let config = device.build();
+
+        assert_eq!(
+            config.configuration_descriptors[0],
+            [
+                0x09, 0x02, 0x12, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, // configuration
+                0x09, 0x04, 0x00, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00, // interface 0
+            ][..]
+        );
+    }
+
+    #[test]
+    fn device_errors() {
+        let device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001)).max_packet_size_0(9);
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::InvalidEp0Size(9)
+        );
+
+        let device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001)).max_power(510);
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::MaxPowerTooHigh {
+                configuration: 1,
+                max_power_ma: 510,
+            }
+        );
+
+        let device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::ConfigurationWithoutInterfaces { configuration: 1 }
+        );
+
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let ep = endpoint(1, UsbDirection::In, EndpointType::Bulk, 64, 0);
+        device
+            .alloc_interface()
+            .endpoint(ep.clone())
+            .save(&mut device);
+        device.alloc_interface().endpoint(ep).save(&mut device);
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::EndpointUsedTwice {
+                configuration: 1,
+                interface: 1,
+                address: 0x81,
+            }
+        );
+    }
+
+    #[test]
+    fn endpoint_errors() {
+        let builder = EndpointBuilder::new()
+            .number(1)
+            .direction(UsbDirection::Out)
+            .ep_type(EndpointType::Bulk);
+        let error = builder.try_build().unwrap_err();
+        assert_eq!(
+            error,
+            BuilderError::MissingEndpointField {
+                field: "max_packet_size",
+                number: Some(1),
+                direction: Some(UsbDirection::Out),
+            }
+        );
+        assert_eq!(error.to_string(), "endpoint 1 Out: max_packet_size is not set");
+
+        let builder = EndpointBuilder::new()
+            .number(16)
+            .direction(UsbDirection::Out)
+            .ep_type(EndpointType::Bulk)
+            .max_packet_size(64);
+        assert_eq!(
+            builder.try_build().unwrap_err(),
+            BuilderError::InvalidEndpointNumber(16)
+        );
+    }
+
+    #[test]
+    fn endpoint_descriptors() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let jack = UsbCustomDescriptor::new(0x25, &[0x02, 0x00])
+            .string(1, UsbString::Const("Jack".to_string()));
+        device
+            .alloc_interface()
+            .interface_class(0xff)
+            .descriptor(0x24, &[0x01])
+            .endpoint(
+                EndpointBuilder::new()
+                    .number(1)
+                    .direction(UsbDirection::In)
+                    .ep_type(EndpointType::Bulk)
+                    .max_packet_size(64)
+                    .descriptor(0x25, &[0x01])
+                    .build()
+                    .superspeed_companion(15, 0, 0),
+            )
+            .endpoint(
+                endpoint(2, UsbDirection::Out, EndpointType::Bulk, 64, 0).custom_descriptor(jack),
+            )
+            .save(&mut device);
+        let config = device.build();
+
+        // Interface descriptors precede the endpoints, endpoint descriptors follow their endpoint
+        assert_eq!(
+            config.configuration_descriptors[0],
+            [
+                0x09, 0x02, 0x30, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, // configuration
+                0x09, 0x04, 0x00, 0x00, 0x02, 0xff, 0x00, 0x00, 0x00, // interface 0
+                0x03, 0x24, 0x01, // interface descriptor
+                0x07, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00, // endpoint 0x81
+                0x03, 0x25, 0x01, // endpoint descriptor
+                0x06, 0x30, 0x0f, 0x00, 0x00, 0x00, // SuperSpeed endpoint companion
+                0x07, 0x05, 0x02, 0x02, 0x40, 0x00, 0x00, // endpoint 0x02
+                0x04, 0x25, 0x02, 0x01, // endpoint descriptor with a string
+            ][..]
+        );
+        assert_eq!(config.string_descriptors[&1], b"\x0a\x03J\0a\0c\0k\0");
+    }
+
+    #[test]
+    fn bos_descriptor() {
+        let container_id = [
+            0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+            0xee, 0xff,
+        ];
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001))
+            .capability(UsbDeviceCapability::Usb20Extension {
+                lpm: true,
+                baseline_besl: Some(4),
+                deep_besl: None,
+            })
+            .capability(UsbDeviceCapability::ContainerId(container_id));
+        device
+            .alloc_interface()
+            .interface_class(0xff)
+            .save(&mut device);
+        let config = device.build();
+
+        // bcdUSB is raised to 2.01 so that the host requests the BOS descriptor
+        assert_eq!(config.device_descriptor[2..4], [0x01, 0x02]);
+        let mut expected = vec![
+            0x05, 0x0f, 0x20, 0x00, 0x02, // BOS
+            0x07, 0x10, 0x02, 0x0e, 0x04, 0x00, 0x00, // USB 2.0 extension
+            0x14, 0x10, 0x04, 0x00, // container ID
+        ];
+        expected.extend_from_slice(&container_id);
+        assert_eq!(config.bos_descriptor, Some(expected));
+    }
+
+    #[test]
+    fn invalid_besl() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001)).capability(
+            UsbDeviceCapability::Usb20Extension {
+                lpm: true,
+                baseline_besl: Some(2),
+                deep_besl: Some(16),
+            },
+        );
+        device
+            .alloc_interface()
+            .interface_class(0xff)
+            .save(&mut device);
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::InvalidBesl(16)
+        );
+    }
+
+    #[test]
+    fn translated_strings() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001))
+            .product("Mouse")
+            .translation(lang_id::GERMAN, "Mouse", "Maus")
+            .translation(lang_id::JAPANESE, "Mouse", "\u{30de}\u{30a6}\u{30b9}");
+        device
+            .alloc_interface()
+            .interface_class(0xff)
+            .save(&mut device);
+        let config = device.build();
+
+        assert_eq!(
+            config.string_descriptors[&0],
+            [0x08, 0x03, 0x09, 0x04, 0x07, 0x04, 0x11, 0x04]
+        );
+        assert_eq!(config.string_descriptors[&1], b"\x0c\x03M\0o\0u\0s\0e\0");
+        assert_eq!(
+            config.translated_string_descriptors[&(lang_id::GERMAN, 1)],
+            b"\x0a\x03M\0a\0u\0s\0"
+        );
+        assert_eq!(
+            config.translated_string_descriptors[&(lang_id::JAPANESE, 1)],
+            [0x08, 0x03, 0xde, 0x30, 0xa6, 0x30, 0xb9, 0x30]
+        );
+    }
+
+    #[test]
+    fn primary_language() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001))
+            .translation(lang_id::ENGLISH_US, "Maus", "Mouse")
+            .primary_language(lang_id::GERMAN);
+        device
+            .alloc_interface()
+            .interface_class(0xff)
+            .save(&mut device);
+        let config = device.build();
+
+        assert_eq!(
+            config.string_descriptors[&0],
+            [0x06, 0x03, 0x07, 0x04, 0x09, 0x04]
+        );
+    }
+
+    #[test]
+    fn accepted_in_request() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        device
+            .alloc_interface()
+            .interface_class(0xff)
+            .save(&mut device);
+        device.add_control_request(ControlRequest::new(
+            RequestType::Vendor,
+            Recipient::Device,
+            0x01,
+            ControlResponse::Accept,
+        ));
+        device.add_control_request(ControlRequest::new(
+            RequestType::Standard,
+            Recipient::Interface,
+            0x0a,
+            ControlResponse::Accept,
+        ));
+        let error = device.try_build().unwrap_err();
+        assert_eq!(error, BuilderError::AcceptedInRequest { request: 0x0a });
+        assert_eq!(
+            error.to_string(),
+            "control request 0x0a: IN requests have to be answered with data"
+        );
+    }
+
+    #[test]
+    fn compatible_id_without_ms_os_descriptors() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        device
+            .alloc_interface()
+            .interface_class(0xff)
+            .save(&mut device);
+        device
+            .alloc_interface()
+            .interface_class(0xff)
+            .ms_os_compatible_id("WINUSB", "")
+            .save(&mut device);
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::MsOsDescriptorsDisabled { interface: 1 }
+        );
+    }
+
+    #[test]
+    fn device_class_with_functions() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001)).device_class(0xff);
+        let function = device.alloc_function();
+        device
+            .alloc_interface()
+            .interface_class(0xff)
+            .save(&mut device);
+        function.function_class(0xff).save(&mut device);
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::DeviceClassWithFunctions {
+                device_class: 0xff,
+                device_sub_class: 0x00,
+                device_protocol: 0x00,
+            }
+        );
+    }
+
+    #[test]
+    fn conflicting_endpoint_in_configurations() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        device
+            .alloc_interface()
+            .interface_class(0xff)
+            .endpoint(endpoint(1, UsbDirection::In, EndpointType::Bulk, 64, 0))
+            .save(&mut device);
+        device.alloc_configuration().save(&mut device);
+        device
+            .alloc_interface()
+            .interface_class(0xff)
+            .endpoint(endpoint(1, UsbDirection::In, EndpointType::Interrupt, 64, 0))
+            .save(&mut device);
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::ConflictingEndpoint {
+                configuration: 2,
+                address: 0x81,
+            }
+        );
+    }
+
+    #[test]
+    fn endpoint_in_alternate_settings() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let interface = device.alloc_interface();
+        let large = device.alloc_alternate_setting(&interface);
+        let small = device.alloc_alternate_setting(&interface);
+        let mut ep = endpoint(1, UsbDirection::In, EndpointType::Isochronous, 64, 0);
+        interface.interface_class(0xff).save(&mut device);
+        ep.max_packet_size = 1023;
+        large
+            .interface_class(0xff)
+            .endpoint(ep.clone())
+            .save(&mut device);
+        ep.max_packet_size = 512;
+        small.interface_class(0xff).endpoint(ep).save(&mut device);
+        let config = device.build();
+
+        // The endpoint is configured for the largest alternate setting
+        assert_eq!(config.endpoints.len(), 3);
+        assert_eq!(config.endpoints[2].address, 0x81.into());
+        assert_eq!(config.endpoints[2].max_packet_size, 1023);
+
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let interface = device.alloc_interface();
+        let bulk = device.alloc_alternate_setting(&interface);
+        let interrupt = device.alloc_alternate_setting(&interface);
+        interface.interface_class(0xff).save(&mut device);
+        bulk.interface_class(0xff)
+            .endpoint(endpoint(1, UsbDirection::In, EndpointType::Bulk, 64, 0))
+            .save(&mut device);
+        interrupt
+            .interface_class(0xff)
+            .endpoint(endpoint(1, UsbDirection::In, EndpointType::Interrupt, 64, 0))
+            .save(&mut device);
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::ConflictingEndpoint {
+                configuration: 1,
+                address: 0x81,
+            }
+        );
+    }
+
+    #[test]
+    fn translation_to_primary_language() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001))
+            .product("Mouse")
+            .translation(lang_id::ENGLISH_US, "Mouse", "Pointer");
+        device
+            .alloc_interface()
+            .interface_class(0xff)
+            .save(&mut device);
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::TranslationToPrimaryLanguage {
+                lang_id: lang_id::ENGLISH_US,
+            }
+        );
+
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001))
+            .product("Maus")
+            .translation(lang_id::GERMAN, "Maus", "Maus")
+            .primary_language(lang_id::GERMAN);
+        device
+            .alloc_interface()
+            .interface_class(0xff)
+            .save(&mut device);
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::TranslationToPrimaryLanguage {
+                lang_id: lang_id::GERMAN,
+            }
+        );
+    }
+}
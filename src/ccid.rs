@@ -0,0 +1,438 @@
+use crate::builder::DeviceBuilder;
+use crate::control::{ControlRequest, ControlResponse, Recipient, RequestType};
+use crate::endpoint::DeviceAllocator;
+use crate::error::BuilderError;
+use crate::function::{FunctionEndpoints, UsbFunction};
+use crate::EndpointInfo;
+use usb_device::endpoint::EndpointType;
+use usb_device::UsbDirection;
+
+pub const USB_CLASS_CCID: u8 = 0x0b;
+const CCID_SUBCLASS: u8 = 0x00;
+const CCID_PROTOCOL_BULK: u8 = 0x00;
+
+const CCID_DESCRIPTOR_TYPE: u8 = 0x21;
+
+/// Maximum size of an interrupt message, the max packet size of a full speed interrupt endpoint
+const MAX_NOTIFICATION_SIZE: u16 = 64;
+
+pub const CCID_REQUEST_ABORT: u8 = 0x01;
+pub const CCID_REQUEST_GET_CLOCK_FREQUENCIES: u8 = 0x02;
+pub const CCID_REQUEST_GET_DATA_RATES: u8 = 0x03;
+
+/// Card voltages, used in `bVoltageSupport`.
+pub mod voltage {
+    pub const V5_0: u8 = 0x01;
+    pub const V3_0: u8 = 0x02;
+    pub const V1_8: u8 = 0x04;
+}
+
+/// Card protocols, used in `dwProtocols`.
+pub mod protocol {
+    pub const T0: u32 = 0x0001;
+    pub const T1: u32 = 0x0002;
+}
+
+/// Reader features, used in `dwFeatures`.
+pub mod feature {
+    pub const AUTO_PARAMETER_CONFIGURATION: u32 = 0x0000_0002;
+    pub const AUTO_ACTIVATION: u32 = 0x0000_0004;
+    pub const AUTO_VOLTAGE: u32 = 0x0000_0008;
+    pub const AUTO_CLOCK: u32 = 0x0000_0010;
+    pub const AUTO_BAUD_RATE: u32 = 0x0000_0020;
+    pub const AUTO_PPS_NEGOTIATION: u32 = 0x0000_0040;
+    pub const AUTO_PPS_ACTIVE: u32 = 0x0000_0080;
+    pub const CAN_STOP_CLOCK: u32 = 0x0000_0100;
+    pub const NAD_OTHER_THAN_ZERO: u32 = 0x0000_0200;
+    pub const AUTO_IFSD: u32 = 0x0000_0400;
+    pub const TPDU_EXCHANGE: u32 = 0x0001_0000;
+    pub const SHORT_APDU_EXCHANGE: u32 = 0x0002_0000;
+    pub const EXTENDED_APDU_EXCHANGE: u32 = 0x0004_0000;
+    pub const USB_WAKEUP: u32 = 0x0010_0000;
+}
+
+/// Settings of a CCID interface.
+#[derive(Clone, Debug)]
+pub struct CcidOptions {
+    pub slot_count: u8,
+    pub voltage_support: u8,
+    pub protocols: u32,
+    pub clock_frequencies: Vec<u32>,
+    pub data_rates: Vec<u32>,
+    pub max_ifsd: u32,
+    pub features: u32,
+    pub max_message_length: u32,
+    pub interface_string: Option<String>,
+}
+
+impl Default for CcidOptions {
+    fn default() -> Self {
+        Self {
+            slot_count: 1,
+            voltage_support: voltage::V5_0 | voltage::V3_0 | voltage::V1_8,
+            protocols: protocol::T0 | protocol::T1,
+            clock_frequencies: vec![4000],
+            data_rates: vec![10752],
+            max_ifsd: 254,
+            features: feature::AUTO_PARAMETER_CONFIGURATION
+                | feature::AUTO_VOLTAGE
+                | feature::AUTO_CLOCK
+                | feature::AUTO_BAUD_RATE
+                | feature::AUTO_PPS_NEGOTIATION
+                | feature::SHORT_APDU_EXCHANGE,
+            max_message_length: 271,
+            interface_string: None,
+        }
+    }
+}
+
+impl CcidOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the number of card slots. A slot change notification has 2 bits per slot, so at most
+    /// 252 slots are supported.
+    ///
+    /// Default: 1
+    pub fn slot_count(mut self, slot_count: u8) -> Self {
+        self.slot_count = slot_count;
+        self
+    }
+
+    /// Sets the supported `voltage` bits.
+    ///
+    /// Default: `V5_0 | V3_0 | V1_8`
+    pub fn voltage_support(mut self, voltage_support: u8) -> Self {
+        self.voltage_support = voltage_support;
+        self
+    }
+
+    /// Sets the supported `protocol` bits.
+    ///
+    /// Default: `T0 | T1`
+    pub fn protocols(mut self, protocols: u32) -> Self {
+        self.protocols = protocols;
+        self
+    }
+
+    /// Sets the supported card clock frequencies in kHz. The first one is the default clock.
+    ///
+    /// Default: `[4000]`
+    pub fn clock_frequencies(mut self, clock_frequencies: &[u32]) -> Self {
+        self.clock_frequencies = clock_frequencies.to_vec();
+        self
+    }
+
+    /// Sets the supported data rates in bps. The first one is the default data rate.
+    ///
+    /// Default: `[10752]`
+    pub fn data_rates(mut self, data_rates: &[u32]) -> Self {
+        self.data_rates = data_rates.to_vec();
+        self
+    }
+
+    /// Sets the maximum IFSD supported for the T=1 protocol.
+    ///
+    /// Default: 254
+    pub fn max_ifsd(mut self, max_ifsd: u32) -> Self {
+        self.max_ifsd = max_ifsd;
+        self
+    }
+
+    /// Sets the `feature` bits.
+    ///
+    /// Default: `AUTO_PARAMETER_CONFIGURATION | AUTO_VOLTAGE | AUTO_CLOCK | AUTO_BAUD_RATE |
+    /// AUTO_PPS_NEGOTIATION | SHORT_APDU_EXCHANGE`
+    pub fn features(mut self, features: u32) -> Self {
+        self.features = features;
+        self
+    }
+
+    /// Sets the maximum length of a CCID message, including its 10 byte header.
+    ///
+    /// Default: 271
+    pub fn max_message_length(mut self, max_message_length: u32) -> Self {
+        self.max_message_length = max_message_length;
+        self
+    }
+
+    /// Sets the interface string descriptor.
+    ///
+    /// Default: (none)
+    pub fn interface_string(mut self, interface_string: impl Into<String>) -> Self {
+        self.interface_string = Some(interface_string.into());
+        self
+    }
+
+    fn validate(&self, interface: u8) -> Result<(), BuilderError> {
+        if self.slot_count == 0 {
+            return Err(BuilderError::InvalidCcidOptions {
+                interface,
+                reason: "at least one slot is required",
+            });
+        }
+        if self.notification_size() > MAX_NOTIFICATION_SIZE {
+            return Err(BuilderError::InvalidCcidOptions {
+                interface,
+                reason: "slot change notifications don't fit in 64 bytes",
+            });
+        }
+        if self.clock_frequencies.is_empty() || self.clock_frequencies.len() > 255 {
+            return Err(BuilderError::InvalidCcidOptions {
+                interface,
+                reason: "1 to 255 clock frequencies are required",
+            });
+        }
+        if self.data_rates.is_empty() || self.data_rates.len() > 255 {
+            return Err(BuilderError::InvalidCcidOptions {
+                interface,
+                reason: "1 to 255 data rates are required",
+            });
+        }
+        Ok(())
+    }
+
+    /// Returns the size of the longest interrupt message: a slot change notification with 2 bits
+    /// per slot, or a 4 byte hardware error notification.
+    fn notification_size(&self) -> u16 {
+        let slot_change = 1 + u16::from(self.slot_count).div_ceil(4);
+        slot_change.max(4)
+    }
+
+    fn class_descriptor(&self) -> Vec<u8> {
+        let max_clock = *self.clock_frequencies.iter().max().unwrap();
+        let max_data_rate = *self.data_rates.iter().max().unwrap();
+
+        let mut descriptor = vec![
+            0x10,
+            0x01,                 // bcdCCID
+            self.slot_count - 1,  // bMaxSlotIndex
+            self.voltage_support, // bVoltageSupport
+        ];
+        descriptor.extend_from_slice(&self.protocols.to_le_bytes()); // dwProtocols
+        descriptor.extend_from_slice(&self.clock_frequencies[0].to_le_bytes()); // dwDefaultClock
+        descriptor.extend_from_slice(&max_clock.to_le_bytes()); // dwMaximumClock
+        descriptor.push(self.clock_frequencies.len() as u8); // bNumClockSupported
+        descriptor.extend_from_slice(&self.data_rates[0].to_le_bytes()); // dwDataRate
+        descriptor.extend_from_slice(&max_data_rate.to_le_bytes()); // dwMaxDataRate
+        descriptor.push(self.data_rates.len() as u8); // bNumDataRatesSupported
+        descriptor.extend_from_slice(&self.max_ifsd.to_le_bytes()); // dwMaxIFSD
+        descriptor.extend_from_slice(&0u32.to_le_bytes()); // dwSynchProtocols
+        descriptor.extend_from_slice(&0u32.to_le_bytes()); // dwMechanical
+        descriptor.extend_from_slice(&self.features.to_le_bytes()); // dwFeatures
+        descriptor.extend_from_slice(&self.max_message_length.to_le_bytes()); // dwMaxCCIDMessageLength
+        descriptor.extend_from_slice(&[
+            0xff, // bClassGetResponse: echo the APDU class
+            0xff, // bClassEnvelope: echo the APDU class
+            0, 0, // wLcdLayout: no LCD
+            0, // bPINSupport
+            1, // bMaxCCIDBusySlots
+        ]);
+        descriptor
+    }
+}
+
+/// Adds a CCID smart card reader interface with a bulk OUT, a bulk IN and an interrupt IN
+/// endpoint. The interrupt endpoint must fit a slot change notification for all slots.
+///
+/// The generated code answers GET_CLOCK_FREQUENCIES and GET_DATA_RATES from the values of
+/// `options`, and accepts ABORT, after which the application has to abort the command of the slot
+/// and sequence number in `wValue`.
+pub fn create_ccid_function(
+    device: &mut DeviceBuilder,
+    options: &CcidOptions,
+    read_ep: impl EndpointInfo,
+    write_ep: impl EndpointInfo,
+    interrupt_ep: impl EndpointInfo,
+) {
+    if let Err(e) = options.validate(device.next_interface_number()) {
+        device.defer_error(e);
+        return;
+    }
+    device.check_endpoint(&read_ep, EndpointType::Bulk, UsbDirection::Out);
+    device.check_endpoint(&write_ep, EndpointType::Bulk, UsbDirection::In);
+    device.check_endpoint(&interrupt_ep, EndpointType::Interrupt, UsbDirection::In);
+    if interrupt_ep.descriptor().max_packet_size < options.notification_size() {
+        device.defer_error(BuilderError::InvalidMaxPacketSize {
+            address: interrupt_ep.address().into(),
+            max_packet_size: interrupt_ep.descriptor().max_packet_size,
+        });
+        return;
+    }
+
+    let mut interface = device
+        .alloc_interface()
+        .interface_class(USB_CLASS_CCID)
+        .interface_sub_class(CCID_SUBCLASS)
+        .interface_protocol(CCID_PROTOCOL_BULK)
+        .descriptor(CCID_DESCRIPTOR_TYPE, &options.class_descriptor())
+        .endpoint(read_ep.descriptor().clone())
+        .endpoint(write_ep.descriptor().clone())
+        .endpoint(interrupt_ep.descriptor().clone());
+    if let Some(interface_string) = &options.interface_string {
+        interface = interface.interface_string(interface_string.as_str());
+    }
+    let interface_number = u16::from(interface.descriptor.interface_number);
+    interface.save(device);
+
+    let tables = [
+        (
+            CCID_REQUEST_GET_CLOCK_FREQUENCIES,
+            &options.clock_frequencies,
+        ),
+        (CCID_REQUEST_GET_DATA_RATES, &options.data_rates),
+    ];
+    for &(request, values) in &tables {
+        let data = values
+            .iter()
+            .flat_map(|v| v.to_le_bytes().to_vec())
+            .collect();
+        device.add_control_request(
+            ControlRequest::new(
+                RequestType::Class,
+                Recipient::Interface,
+                request,
+                ControlResponse::Data(data),
+            )
+            .value(0)
+            .index(interface_number),
+        );
+    }
+    // wValue of ABORT holds the slot and sequence number of the aborted command
+    device.add_control_request(
+        ControlRequest::new(
+            RequestType::Class,
+            Recipient::Interface,
+            CCID_REQUEST_ABORT,
+            ControlResponse::Accept,
+        )
+        .index(interface_number),
+    );
+}
+
+/// CCID function. The interrupt endpoint is sized for the slot change notification of all slots.
+pub struct CcidFunction {
+    pub options: CcidOptions,
+    pub max_packet_size: u16,
+    endpoints: FunctionEndpoints,
+}
+
+impl CcidFunction {
+    pub fn new(options: CcidOptions) -> Self {
+        Self {
+            options,
+            max_packet_size: 64,
+            endpoints: FunctionEndpoints::default(),
+        }
+    }
+
+    /// Sets the max packet size of the bulk endpoints.
+    ///
+    /// Default: 64
+    pub fn max_packet_size(mut self, max_packet_size: u16) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+}
+
+impl UsbFunction for CcidFunction {
+    fn allocate_endpoints(&mut self, allocator: &mut DeviceAllocator) -> Result<(), BuilderError> {
+        let mps = self.max_packet_size;
+        // Too many slots are reported when the function is added
+        let notification_size = self.options.notification_size().min(MAX_NOTIFICATION_SIZE);
+        self.endpoints.allocate(
+            allocator,
+            &[
+                (UsbDirection::Out, EndpointType::Bulk, mps, 0),
+                (UsbDirection::In, EndpointType::Bulk, mps, 0),
+                (
+                    UsbDirection::In,
+                    EndpointType::Interrupt,
+                    notification_size,
+                    16,
+                ),
+            ],
+        )
+    }
+
+    fn add_to_device(&self, device: &mut DeviceBuilder) -> Result<(), BuilderError> {
+        let eps = self.endpoints.get(device, 3)?;
+        create_ccid_function(device, &self.options, &eps[0], &eps[1], &eps[2]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::UsbVidPid;
+    use crate::test_util::{build_function, endpoint, response};
+
+    #[test]
+    fn smart_card_reader() {
+        let config = build_function(&mut CcidFunction::new(CcidOptions::new())).unwrap();
+
+        let expected: &[u8] = &[
+            0x09, 0x02, 0x5d, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, // configuration
+            0x09, 0x04, 0x00, 0x00, 0x03, 0x0b, 0x00, 0x00, 0x00, // interface
+            0x36, 0x21, 0x10, 0x01, 0x00, 0x07, // CCID descriptor, 1 slot
+            0x03, 0x00, 0x00, 0x00, // dwProtocols
+            0xa0, 0x0f, 0x00, 0x00, 0xa0, 0x0f, 0x00, 0x00, 0x01, // clock frequencies
+            0x00, 0x2a, 0x00, 0x00, 0x00, 0x2a, 0x00, 0x00, 0x01, // data rates
+            0xfe, 0x00, 0x00, 0x00, // dwMaxIFSD
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // dwSynchProtocols, dwMechanical
+            0x7a, 0x00, 0x02, 0x00, // dwFeatures
+            0x0f, 0x01, 0x00, 0x00, // dwMaxCCIDMessageLength
+            0xff, 0xff, 0x00, 0x00, 0x00, 0x01, // APDU classes, no LCD or PIN, busy slots
+            0x07, 0x05, 0x01, 0x02, 0x40, 0x00, 0x00, // bulk OUT endpoint
+            0x07, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00, // bulk IN endpoint
+            0x07, 0x05, 0x82, 0x03, 0x04, 0x00, 0x10, // interrupt IN endpoint
+        ];
+        assert_eq!(config.configuration_descriptors[0], expected);
+
+        assert_eq!(
+            response(&config, |r| r.request == CCID_REQUEST_GET_CLOCK_FREQUENCIES),
+            [0xa0, 0x0f, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn notification_size() {
+        for &(slot_count, max_packet_size) in &[(1, 4u16), (16, 5), (252, 64)] {
+            let options = CcidOptions::new().slot_count(slot_count);
+            let config = build_function(&mut CcidFunction::new(options)).unwrap();
+            let descriptor = &config.configuration_descriptors[0];
+            assert_eq!(descriptor[18 + 4], slot_count - 1);
+            let interrupt_ep = &descriptor[descriptor.len() - 7..];
+            assert_eq!(interrupt_ep[4..6], max_packet_size.to_le_bytes());
+        }
+
+        assert_eq!(
+            build_function(&mut CcidFunction::new(CcidOptions::new().slot_count(253))).unwrap_err(),
+            BuilderError::InvalidCcidOptions {
+                interface: 0,
+                reason: "slot change notifications don't fit in 64 bytes",
+            }
+        );
+    }
+
+    #[test]
+    fn interrupt_endpoint_too_small() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        create_ccid_function(
+            &mut device,
+            &CcidOptions::new().slot_count(16),
+            endpoint(1, UsbDirection::Out, EndpointType::Bulk, 64, 0),
+            endpoint(1, UsbDirection::In, EndpointType::Bulk, 64, 0),
+            endpoint(2, UsbDirection::In, EndpointType::Interrupt, 4, 16),
+        );
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::InvalidMaxPacketSize {
+                address: 0x82,
+                max_packet_size: 4,
+            }
+        );
+    }
+}
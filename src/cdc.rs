@@ -1,37 +1,720 @@
 use crate::builder::DeviceBuilder;
+use crate::endpoint::DeviceAllocator;
+use crate::error::BuilderError;
+use crate::function::{FunctionEndpoints, UsbFunction};
+use crate::usb::{UsbCustomDescriptor, UsbString};
 use crate::EndpointInfo;
+use bit_field::BitField;
+use usb_device::endpoint::EndpointType;
+use usb_device::UsbDirection;
 
 pub const USB_CLASS_CDC: u8 = 0x02;
 const USB_CLASS_DATA: u8 = 0x0a;
 const CDC_SUBCLASS_ACM: u8 = 0x02;
-const CDC_PROTOCOL_AT: u8 = 0x01;
+const CDC_SUBCLASS_ECM: u8 = 0x06;
+const CDC_SUBCLASS_NCM: u8 = 0x0d;
+const CDC_PROTOCOL_NONE: u8 = 0x00;
+const DATA_PROTOCOL_NONE: u8 = 0x00;
+const DATA_PROTOCOL_NCM: u8 = 0x01;
+const USB_CLASS_WIRELESS_CONTROLLER: u8 = 0xef;
+const WIRELESS_SUBCLASS_RNDIS: u8 = 0x04;
+const WIRELESS_PROTOCOL_RNDIS_ETHERNET: u8 = 0x01;
+
+const RNDIS_COMPATIBLE_ID: &str = "RNDIS";
+const RNDIS_SUB_COMPATIBLE_ID: &str = "5162001";
 
 const CS_INTERFACE: u8 = 0x24;
 const CDC_TYPE_HEADER: u8 = 0x00;
 const CDC_TYPE_CALL_MANAGEMENT: u8 = 0x01;
 const CDC_TYPE_ACM: u8 = 0x02;
 const CDC_TYPE_UNION: u8 = 0x06;
+const CDC_TYPE_ETHERNET: u8 = 0x0f;
+const CDC_TYPE_NCM: u8 = 0x1a;
+
+/// Settings of a CDC-ECM or CDC-NCM network function.
+#[derive(Clone, Debug)]
+pub struct EthernetOptions {
+    pub mac_address: UsbString,
+    pub max_segment_size: u16,
+    pub ncm_network_capabilities: u8,
+}
+
+impl EthernetOptions {
+    /// Creates options for a function with the given MAC address of the device.
+    pub fn new(mac_address: [u8; 6]) -> Self {
+        let mac_address = mac_address.iter().map(|b| format!("{:02X}", b)).collect();
+        Self {
+            mac_address: UsbString::Const(mac_address),
+            max_segment_size: 1514,
+            ncm_network_capabilities: 0,
+        }
+    }
+
+    /// Uses a custom string descriptor for the MAC address, e.g. to derive it from a unique ID
+    /// at runtime. The string must consist of 12 hexadecimal digits.
+    pub fn custom_mac_address(mut self, id: usize) -> Self {
+        self.mac_address = UsbString::Custom(id);
+        self
+    }
+
+    /// Sets the maximum segment size of the Ethernet device in bytes.
+    ///
+    /// Default: 1514
+    pub fn max_segment_size(mut self, max_segment_size: u16) -> Self {
+        self.max_segment_size = max_segment_size;
+        self
+    }
+
+    /// Sets `bmNetworkCapabilities` of the NCM functional descriptor. Ignored by ECM functions.
+    ///
+    /// Default: `0x00`
+    pub fn ncm_network_capabilities(mut self, ncm_network_capabilities: u8) -> Self {
+        self.ncm_network_capabilities = ncm_network_capabilities;
+        self
+    }
+}
+
+/// `bInterfaceProtocol` of an ACM communication interface.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AcmProtocol {
+    None = 0x00,
+    /// AT commands as defined by ITU-T V.250
+    At = 0x01,
+    Vendor = 0xff,
+}
+
+/// Settings of a CDC-ACM function.
+#[derive(Clone, Debug)]
+pub struct AcmOptions {
+    pub cdc_release: u16,
+    pub protocol: AcmProtocol,
+    pub call_management_capabilities: u8,
+    pub acm_capabilities: u8,
+    pub function_string: Option<String>,
+    pub comm_interface_string: Option<String>,
+    pub data_interface_string: Option<String>,
+}
+
+impl Default for AcmOptions {
+    fn default() -> Self {
+        Self {
+            cdc_release: 0x0110,
+            protocol: AcmProtocol::At,
+            call_management_capabilities: 0x00,
+            acm_capabilities: 0x00,
+            function_string: None,
+            comm_interface_string: None,
+            data_interface_string: None,
+        }
+    }
+}
+
+impl AcmOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
 
+    /// Sets the CDC specification release number in BCD.
+    ///
+    /// Default: `0x0110`
+    pub fn cdc_release(mut self, cdc_release: u16) -> Self {
+        self.cdc_release = cdc_release;
+        self
+    }
+
+    /// Default: `AcmProtocol::At`
+    pub fn protocol(mut self, protocol: AcmProtocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Sets whether the device handles call management itself, and whether it is done over the
+    /// data interface rather than the communication interface.
+    ///
+    /// Default: `false`, `false`
+    pub fn call_management(mut self, handles_call_management: bool, over_data_interface: bool) -> Self {
+        self.call_management_capabilities.set_bit(0, handles_call_management);
+        self.call_management_capabilities.set_bit(1, over_data_interface);
+        self
+    }
+
+    /// Sets whether the device supports Set_Comm_Feature, Clear_Comm_Feature and
+    /// Get_Comm_Feature.
+    ///
+    /// Default: `false`
+    pub fn comm_features(mut self, supported: bool) -> Self {
+        self.acm_capabilities.set_bit(0, supported);
+        self
+    }
+
+    /// Sets whether the device supports Set_Line_Coding, Set_Control_Line_State,
+    /// Get_Line_Coding and the Serial_State notification.
+    ///
+    /// Default: `false`
+    pub fn line_coding(mut self, supported: bool) -> Self {
+        self.acm_capabilities.set_bit(1, supported);
+        self
+    }
+
+    /// Sets whether the device supports Send_Break.
+    ///
+    /// Default: `false`
+    pub fn send_break(mut self, supported: bool) -> Self {
+        self.acm_capabilities.set_bit(2, supported);
+        self
+    }
+
+    /// Sets whether the device supports the Network_Connection notification.
+    ///
+    /// Default: `false`
+    pub fn network_connection(mut self, supported: bool) -> Self {
+        self.acm_capabilities.set_bit(3, supported);
+        self
+    }
+
+    /// Sets the string descriptor of the interface association.
+    ///
+    /// Default: (none)
+    pub fn function_string(mut self, function_string: impl Into<String>) -> Self {
+        self.function_string = Some(function_string.into());
+        self
+    }
+
+    /// Sets the string descriptor of the communication interface.
+    ///
+    /// Default: (none)
+    pub fn comm_interface_string(mut self, interface_string: impl Into<String>) -> Self {
+        self.comm_interface_string = Some(interface_string.into());
+        self
+    }
+
+    /// Sets the string descriptor of the data interface.
+    ///
+    /// Default: (none)
+    pub fn data_interface_string(mut self, interface_string: impl Into<String>) -> Self {
+        self.data_interface_string = Some(interface_string.into());
+        self
+    }
+}
+
+/// Adds a CDC-ACM function with the default `AcmOptions`.
 pub fn create_cdc_function(device: &mut DeviceBuilder, comm_ep: impl EndpointInfo, read_ep: impl EndpointInfo, write_ep: impl EndpointInfo) {
+    create_acm_function(device, &AcmOptions::default(), comm_ep, read_ep, write_ep);
+}
+
+/// Adds a CDC-ACM function. `comm_ep` must be an interrupt IN endpoint, `read_ep` a bulk OUT
+/// endpoint and `write_ep` a bulk IN endpoint.
+pub fn create_acm_function(device: &mut DeviceBuilder, options: &AcmOptions, comm_ep: impl EndpointInfo, read_ep: impl EndpointInfo, write_ep: impl EndpointInfo) {
+    device.check_endpoint(&comm_ep, EndpointType::Interrupt, UsbDirection::In);
+    device.check_endpoint(&read_ep, EndpointType::Bulk, UsbDirection::Out);
+    device.check_endpoint(&write_ep, EndpointType::Bulk, UsbDirection::In);
+
+    let function = device.alloc_function();
     let comm_if = device.alloc_interface();
     let data_if = device.alloc_interface();
     let comm_if_id = comm_if.descriptor.interface_number;
     let data_if_id = data_if.descriptor.interface_number;
+    let protocol = options.protocol as u8;
 
-    comm_if
+    let mut comm_if = comm_if
         .interface_class(USB_CLASS_CDC)
         .interface_sub_class(CDC_SUBCLASS_ACM)
-        .interface_protocol(CDC_PROTOCOL_AT)
+        .interface_protocol(protocol)
+        .descriptor(CS_INTERFACE, &[CDC_TYPE_HEADER, options.cdc_release as u8, (options.cdc_release >> 8) as u8])
+        .descriptor(CS_INTERFACE, &[CDC_TYPE_CALL_MANAGEMENT, options.call_management_capabilities, data_if_id])
+        .descriptor(CS_INTERFACE, &[CDC_TYPE_ACM, options.acm_capabilities])
+        .descriptor(CS_INTERFACE, &[CDC_TYPE_UNION, comm_if_id, data_if_id])
+        .endpoint(comm_ep.descriptor().clone());
+    if let Some(interface_string) = &options.comm_interface_string {
+        comm_if = comm_if.interface_string(interface_string.as_str());
+    }
+    comm_if.save(device);
+
+    let mut data_if = data_if
+        .interface_class(USB_CLASS_DATA)
+        .endpoint(write_ep.descriptor().clone())
+        .endpoint(read_ep.descriptor().clone());
+    if let Some(interface_string) = &options.data_interface_string {
+        data_if = data_if.interface_string(interface_string.as_str());
+    }
+    data_if.save(device);
+
+    let mut function = function
+        .function_class(USB_CLASS_CDC)
+        .function_sub_class(CDC_SUBCLASS_ACM)
+        .function_protocol(protocol);
+    if let Some(function_string) = &options.function_string {
+        function = function.function_string(function_string.as_str());
+    }
+    function.save(device);
+}
+
+/// CDC-ACM function with a notification endpoint and a pair of bulk data endpoints.
+pub struct AcmFunction {
+    pub options: AcmOptions,
+    pub max_packet_size: u16,
+    endpoints: FunctionEndpoints,
+}
+
+impl AcmFunction {
+    pub fn new(options: AcmOptions) -> Self {
+        Self { options, max_packet_size: 64, endpoints: FunctionEndpoints::default() }
+    }
+
+    /// Sets the max packet size of the bulk data endpoints.
+    ///
+    /// Default: 64
+    pub fn max_packet_size(mut self, max_packet_size: u16) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+}
+
+impl UsbFunction for AcmFunction {
+    fn allocate_endpoints(&mut self, allocator: &mut DeviceAllocator) -> Result<(), BuilderError> {
+        self.endpoints.allocate(allocator, &[
+            (UsbDirection::In, EndpointType::Interrupt, 8, 255),
+            (UsbDirection::Out, EndpointType::Bulk, self.max_packet_size, 0),
+            (UsbDirection::In, EndpointType::Bulk, self.max_packet_size, 0),
+        ])
+    }
+
+    fn add_to_device(&self, device: &mut DeviceBuilder) -> Result<(), BuilderError> {
+        let eps = self.endpoints.get(device, 3)?;
+        create_acm_function(device, &self.options, &eps[0], &eps[1], &eps[2]);
+        Ok(())
+    }
+}
+
+/// Adds a CDC-ECM (Ethernet Control Model) function.
+pub fn create_ecm_function(device: &mut DeviceBuilder, options: &EthernetOptions, comm_ep: impl EndpointInfo, read_ep: impl EndpointInfo, write_ep: impl EndpointInfo) {
+    create_network_function(device, options, false, &comm_ep, &read_ep, &write_ep);
+}
+
+/// Adds a CDC-NCM (Network Control Model) function.
+pub fn create_ncm_function(device: &mut DeviceBuilder, options: &EthernetOptions, comm_ep: impl EndpointInfo, read_ep: impl EndpointInfo, write_ep: impl EndpointInfo) {
+    create_network_function(device, options, true, &comm_ep, &read_ep, &write_ep);
+}
+
+fn create_network_function(device: &mut DeviceBuilder, options: &EthernetOptions, ncm: bool, comm_ep: &dyn EndpointInfo, read_ep: &dyn EndpointInfo, write_ep: &dyn EndpointInfo) {
+    device.check_endpoint(comm_ep, EndpointType::Interrupt, UsbDirection::In);
+    device.check_endpoint(read_ep, EndpointType::Bulk, UsbDirection::Out);
+    device.check_endpoint(write_ep, EndpointType::Bulk, UsbDirection::In);
+
+    let (sub_class, data_protocol) = if ncm {
+        (CDC_SUBCLASS_NCM, DATA_PROTOCOL_NCM)
+    } else {
+        (CDC_SUBCLASS_ECM, DATA_PROTOCOL_NONE)
+    };
+
+    let function = device.alloc_function();
+    let comm_if = device.alloc_interface();
+    let data_if = device.alloc_interface();
+    let data_if_alt = device.alloc_alternate_setting(&data_if);
+    let comm_if_id = comm_if.descriptor.interface_number;
+    let data_if_id = data_if.descriptor.interface_number;
+
+    let mss = options.max_segment_size;
+    let ethernet = UsbCustomDescriptor::new(CS_INTERFACE, &[
+        CDC_TYPE_ETHERNET,
+        0, // iMACAddress
+        0, 0, 0, 0, // bmEthernetStatistics
+        mss as u8, (mss >> 8) as u8, // wMaxSegmentSize
+        0, 0, // wNumberMCFilters
+        0, // bNumberPowerFilters
+    ]).string(1, options.mac_address.clone());
+
+    let mut comm_if = comm_if
+        .interface_class(USB_CLASS_CDC)
+        .interface_sub_class(sub_class)
+        .interface_protocol(CDC_PROTOCOL_NONE)
+        .descriptor(CS_INTERFACE, &[CDC_TYPE_HEADER, 0x10, 0x01])
+        .descriptor(CS_INTERFACE, &[CDC_TYPE_UNION, comm_if_id, data_if_id])
+        .custom_descriptor(ethernet);
+    if ncm {
+        comm_if = comm_if.descriptor(CS_INTERFACE, &[CDC_TYPE_NCM, 0x00, 0x01, options.ncm_network_capabilities]);
+    }
+    comm_if
+        .endpoint(comm_ep.descriptor().clone())
+        .save(device);
+
+    // Alternate setting 0 has no endpoints, the host selects alternate setting 1 to start the
+    // network traffic
+    data_if
+        .interface_class(USB_CLASS_DATA)
+        .interface_protocol(data_protocol)
+        .save(device);
+
+    data_if_alt
+        .interface_class(USB_CLASS_DATA)
+        .interface_protocol(data_protocol)
+        .endpoint(write_ep.descriptor().clone())
+        .endpoint(read_ep.descriptor().clone())
+        .save(device);
+
+    function
+        .function_class(USB_CLASS_CDC)
+        .function_sub_class(sub_class)
+        .function_protocol(CDC_PROTOCOL_NONE)
+        .save(device);
+}
+
+/// Settings of an RNDIS (Remote NDIS) function.
+#[derive(Clone, Debug, Default)]
+pub struct RndisOptions {
+    pub cdc_class: bool,
+    pub function_string: Option<String>,
+}
+
+impl RndisOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Uses class `0x02/0x02/0xff` (CDC-ACM with a vendor protocol) for the communication
+    /// interface instead of `0xef/0x04/0x01` (RNDIS over Ethernet), for hosts that only know
+    /// the former.
+    ///
+    /// Default: `false`
+    pub fn cdc_class(mut self, cdc_class: bool) -> Self {
+        self.cdc_class = cdc_class;
+        self
+    }
+
+    /// Sets the string descriptor of the interface association.
+    ///
+    /// Default: (none)
+    pub fn function_string(mut self, function_string: impl Into<String>) -> Self {
+        self.function_string = Some(function_string.into());
+        self
+    }
+}
+
+/// Adds an RNDIS function. `comm_ep` must be an interrupt IN endpoint.
+///
+/// The communication interface has the `RNDIS` / `5162001` MS OS compatible ID, so that Windows
+/// loads its built-in RNDIS driver. MS OS 1.0 or 2.0 descriptors have to be enabled on `device`.
+/// The RNDIS messages exchanged with SEND_ENCAPSULATED_COMMAND and GET_ENCAPSULATED_RESPONSE
+/// have to be handled by the application.
+pub fn create_rndis_function(device: &mut DeviceBuilder, options: &RndisOptions, comm_ep: impl EndpointInfo, read_ep: impl EndpointInfo, write_ep: impl EndpointInfo) {
+    device.check_endpoint(&comm_ep, EndpointType::Interrupt, UsbDirection::In);
+    device.check_endpoint(&read_ep, EndpointType::Bulk, UsbDirection::Out);
+    device.check_endpoint(&write_ep, EndpointType::Bulk, UsbDirection::In);
+
+    let (class, sub_class, protocol) = if options.cdc_class {
+        (USB_CLASS_CDC, CDC_SUBCLASS_ACM, AcmProtocol::Vendor as u8)
+    } else {
+        (USB_CLASS_WIRELESS_CONTROLLER, WIRELESS_SUBCLASS_RNDIS, WIRELESS_PROTOCOL_RNDIS_ETHERNET)
+    };
+
+    let function = device.alloc_function();
+    let comm_if = device.alloc_interface();
+    let data_if = device.alloc_interface();
+    let comm_if_id = comm_if.descriptor.interface_number;
+    let data_if_id = data_if.descriptor.interface_number;
+
+    comm_if
+        .interface_class(class)
+        .interface_sub_class(sub_class)
+        .interface_protocol(protocol)
         .descriptor(CS_INTERFACE, &[CDC_TYPE_HEADER, 0x10, 0x01])
         .descriptor(CS_INTERFACE, &[CDC_TYPE_CALL_MANAGEMENT, 0x00, data_if_id])
         .descriptor(CS_INTERFACE, &[CDC_TYPE_ACM, 0x00])
         .descriptor(CS_INTERFACE, &[CDC_TYPE_UNION, comm_if_id, data_if_id])
         .endpoint(comm_ep.descriptor().clone())
+        .ms_os_compatible_id(RNDIS_COMPATIBLE_ID, RNDIS_SUB_COMPATIBLE_ID)
         .save(device);
 
     data_if
         .interface_class(USB_CLASS_DATA)
+        .interface_protocol(DATA_PROTOCOL_NONE)
         .endpoint(write_ep.descriptor().clone())
         .endpoint(read_ep.descriptor().clone())
         .save(device);
+
+    let mut function = function
+        .function_class(class)
+        .function_sub_class(sub_class)
+        .function_protocol(protocol);
+    if let Some(function_string) = &options.function_string {
+        function = function.function_string(function_string.as_str());
+    }
+    function.save(device);
+}
+
+/// RNDIS function with an 8 byte interrupt endpoint for RESPONSE_AVAILABLE notifications and a
+/// pair of bulk data endpoints.
+pub struct RndisFunction {
+    pub options: RndisOptions,
+    pub max_packet_size: u16,
+    endpoints: FunctionEndpoints,
+}
+
+impl RndisFunction {
+    pub fn new(options: RndisOptions) -> Self {
+        Self { options, max_packet_size: 64, endpoints: FunctionEndpoints::default() }
+    }
+
+    /// Sets the max packet size of the bulk data endpoints.
+    ///
+    /// Default: 64
+    pub fn max_packet_size(mut self, max_packet_size: u16) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+}
+
+impl UsbFunction for RndisFunction {
+    fn allocate_endpoints(&mut self, allocator: &mut DeviceAllocator) -> Result<(), BuilderError> {
+        // RESPONSE_AVAILABLE notifications are 8 bytes long
+        self.endpoints.allocate(allocator, &[
+            (UsbDirection::In, EndpointType::Interrupt, 8, 1),
+            (UsbDirection::Out, EndpointType::Bulk, self.max_packet_size, 0),
+            (UsbDirection::In, EndpointType::Bulk, self.max_packet_size, 0),
+        ])
+    }
+
+    fn add_to_device(&self, device: &mut DeviceBuilder) -> Result<(), BuilderError> {
+        let eps = self.endpoints.get(device, 3)?;
+        create_rndis_function(device, &self.options, &eps[0], &eps[1], &eps[2]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::UsbVidPid;
+    use crate::test_util::{build_device_function, endpoint, response};
+    use crate::usb::{UsbEndpointDescriptor, MS_OS_STRING_INDEX};
+
+    fn acm_endpoints(first: u8) -> [UsbEndpointDescriptor; 3] {
+        [
+            endpoint(first, UsbDirection::In, EndpointType::Interrupt, 8, 255),
+            endpoint(first + 1, UsbDirection::Out, EndpointType::Bulk, 64, 0),
+            endpoint(first + 1, UsbDirection::In, EndpointType::Bulk, 64, 0),
+        ]
+    }
+
+    #[test]
+    fn two_acm_ports_with_interface_associations() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let [comm, read, write] = acm_endpoints(1);
+        create_cdc_function(&mut device, comm, read, write);
+        let [comm, read, write] = acm_endpoints(3);
+        create_cdc_function(&mut device, comm, read, write);
+        let config = device.build();
+
+        // Miscellaneous device class with Interface Association Descriptors
+        assert_eq!(config.device_descriptor[4..7], [0xef, 0x02, 0x01]);
+        assert_eq!(config.configuration_descriptors[0], [
+            0x09, 0x02, 0x8d, 0x00, 0x04, 0x01, 0x00, 0x80, 0x32, // configuration
+            0x08, 0x0b, 0x00, 0x02, 0x02, 0x02, 0x01, 0x00, // interface association, interfaces 0-1
+            0x09, 0x04, 0x00, 0x00, 0x01, 0x02, 0x02, 0x01, 0x00, // communication interface 0
+            0x05, 0x24, 0x00, 0x10, 0x01, // header
+            0x05, 0x24, 0x01, 0x00, 0x01, // call management
+            0x04, 0x24, 0x02, 0x00, // abstract control management
+            0x05, 0x24, 0x06, 0x00, 0x01, // union
+            0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0xff, // notification endpoint
+            0x09, 0x04, 0x01, 0x00, 0x02, 0x0a, 0x00, 0x00, 0x00, // data interface 1
+            0x07, 0x05, 0x82, 0x02, 0x40, 0x00, 0x00, // bulk IN endpoint
+            0x07, 0x05, 0x02, 0x02, 0x40, 0x00, 0x00, // bulk OUT endpoint
+            0x08, 0x0b, 0x02, 0x02, 0x02, 0x02, 0x01, 0x00, // interface association, interfaces 2-3
+            0x09, 0x04, 0x02, 0x00, 0x01, 0x02, 0x02, 0x01, 0x00, // communication interface 2
+            0x05, 0x24, 0x00, 0x10, 0x01, // header
+            0x05, 0x24, 0x01, 0x00, 0x03, // call management
+            0x04, 0x24, 0x02, 0x00, // abstract control management
+            0x05, 0x24, 0x06, 0x02, 0x03, // union
+            0x07, 0x05, 0x83, 0x03, 0x08, 0x00, 0xff, // notification endpoint
+            0x09, 0x04, 0x03, 0x00, 0x02, 0x0a, 0x00, 0x00, 0x00, // data interface 3
+            0x07, 0x05, 0x84, 0x02, 0x40, 0x00, 0x00, // bulk IN endpoint
+            0x07, 0x05, 0x04, 0x02, 0x40, 0x00, 0x00, // bulk OUT endpoint
+        ][..]);
+    }
+
+    #[test]
+    fn ecm_function() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let [comm, read, write] = acm_endpoints(1);
+        create_ecm_function(&mut device, &EthernetOptions::new([0x02, 0x00, 0x00, 0xab, 0xcd, 0xef]), comm, read, write);
+        let config = device.build();
+
+        assert_eq!(config.configuration_descriptors[0], [
+            0x09, 0x02, 0x58, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32, // configuration
+            0x08, 0x0b, 0x00, 0x02, 0x02, 0x06, 0x00, 0x00, // interface association, interfaces 0-1
+            0x09, 0x04, 0x00, 0x00, 0x01, 0x02, 0x06, 0x00, 0x00, // communication interface 0
+            0x05, 0x24, 0x00, 0x10, 0x01, // header
+            0x05, 0x24, 0x06, 0x00, 0x01, // union
+            0x0d, 0x24, 0x0f, 0x01, 0x00, 0x00, 0x00, 0x00, 0xea, 0x05, 0x00, 0x00, 0x00, // ethernet networking
+            0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0xff, // notification endpoint
+            0x09, 0x04, 0x01, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, // data interface 1, alternate setting 0
+            0x09, 0x04, 0x01, 0x01, 0x02, 0x0a, 0x00, 0x00, 0x00, // data interface 1, alternate setting 1
+            0x07, 0x05, 0x82, 0x02, 0x40, 0x00, 0x00, // bulk IN endpoint
+            0x07, 0x05, 0x02, 0x02, 0x40, 0x00, 0x00, // bulk OUT endpoint
+        ][..]);
+        assert_eq!(config.string_descriptors[&1], b"\x1a\x030\x002\x000\x000\x000\x000\x00A\x00B\x00C\x00D\x00E\x00F\x00");
+    }
+
+    #[test]
+    fn ncm_function() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let options = EthernetOptions::new([0x02, 0x00, 0x00, 0xab, 0xcd, 0xef])
+            .custom_mac_address(0)
+            .max_segment_size(1518)
+            .ncm_network_capabilities(0x20);
+        let [comm, read, write] = acm_endpoints(1);
+        create_ncm_function(&mut device, &options, comm, read, write);
+        let config = device.build();
+
+        assert_eq!(config.configuration_descriptors[0], [
+            0x09, 0x02, 0x5e, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32, // configuration
+            0x08, 0x0b, 0x00, 0x02, 0x02, 0x0d, 0x00, 0x00, // interface association, interfaces 0-1
+            0x09, 0x04, 0x00, 0x00, 0x01, 0x02, 0x0d, 0x00, 0x00, // communication interface 0
+            0x05, 0x24, 0x00, 0x10, 0x01, // header
+            0x05, 0x24, 0x06, 0x00, 0x01, // union
+            0x0d, 0x24, 0x0f, 0x01, 0x00, 0x00, 0x00, 0x00, 0xee, 0x05, 0x00, 0x00, 0x00, // ethernet networking
+            0x06, 0x24, 0x1a, 0x00, 0x01, 0x20, // NCM
+            0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0xff, // notification endpoint
+            0x09, 0x04, 0x01, 0x00, 0x00, 0x0a, 0x00, 0x01, 0x00, // data interface 1, alternate setting 0
+            0x09, 0x04, 0x01, 0x01, 0x02, 0x0a, 0x00, 0x01, 0x00, // data interface 1, alternate setting 1
+            0x07, 0x05, 0x82, 0x02, 0x40, 0x00, 0x00, // bulk IN endpoint
+            0x07, 0x05, 0x02, 0x02, 0x40, 0x00, 0x00, // bulk OUT endpoint
+        ][..]);
+        assert_eq!(config.custom_strings[&1], 0);
+    }
+
+    #[test]
+    fn network_function_endpoint_errors() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let [comm, read, _] = acm_endpoints(1);
+        let write = endpoint(2, UsbDirection::In, EndpointType::Interrupt, 64, 1);
+        create_ecm_function(&mut device, &EthernetOptions::new([0; 6]), comm, read, write);
+        assert_eq!(device.try_build().unwrap_err(), BuilderError::UnexpectedEndpointType {
+            address: 0x82,
+            ep_type: EndpointType::Bulk,
+            direction: UsbDirection::In,
+        });
+    }
+
+    #[test]
+    fn acm_options() {
+        let options = AcmOptions::new()
+            .cdc_release(0x0120)
+            .protocol(AcmProtocol::None)
+            .call_management(true, true)
+            .line_coding(true)
+            .send_break(true)
+            .function_string("Modem")
+            .comm_interface_string("Control")
+            .data_interface_string("Data");
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let [comm, read, write] = acm_endpoints(1);
+        create_acm_function(&mut device, &options, comm, read, write);
+        let config = device.build();
+
+        assert_eq!(config.configuration_descriptors[0], [
+            0x09, 0x02, 0x4b, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32, // configuration
+            0x08, 0x0b, 0x00, 0x02, 0x02, 0x02, 0x00, 0x01, // interface association, interfaces 0-1
+            0x09, 0x04, 0x00, 0x00, 0x01, 0x02, 0x02, 0x00, 0x02, // communication interface 0
+            0x05, 0x24, 0x00, 0x20, 0x01, // header
+            0x05, 0x24, 0x01, 0x03, 0x01, // call management
+            0x04, 0x24, 0x02, 0x06, // abstract control management
+            0x05, 0x24, 0x06, 0x00, 0x01, // union
+            0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0xff, // notification endpoint
+            0x09, 0x04, 0x01, 0x00, 0x02, 0x0a, 0x00, 0x00, 0x03, // data interface 1
+            0x07, 0x05, 0x82, 0x02, 0x40, 0x00, 0x00, // bulk IN endpoint
+            0x07, 0x05, 0x02, 0x02, 0x40, 0x00, 0x00, // bulk OUT endpoint
+        ][..]);
+        assert_eq!(config.string_descriptors[&1], b"\x0c\x03M\0o\0d\0e\0m\0");
+        assert_eq!(config.string_descriptors[&2], b"\x10\x03C\0o\0n\0t\0r\0o\0l\0");
+        assert_eq!(config.string_descriptors[&3], b"\x0a\x03D\0a\0t\0a\0");
+    }
+
+    #[test]
+    fn acm_notification_endpoint_must_be_interrupt_in() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let [_, read, write] = acm_endpoints(1);
+        let comm = endpoint(1, UsbDirection::Out, EndpointType::Interrupt, 8, 255);
+        create_acm_function(&mut device, &AcmOptions::new(), comm, read, write);
+        assert_eq!(device.try_build().unwrap_err(), BuilderError::UnexpectedEndpointType {
+            address: 0x01,
+            ep_type: EndpointType::Interrupt,
+            direction: UsbDirection::In,
+        });
+    }
+
+    #[test]
+    fn acm_data_endpoints_must_be_bulk() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let [comm, read, write] = acm_endpoints(1);
+        // Read and write endpoints swapped
+        create_acm_function(&mut device, &AcmOptions::new(), comm, write, read);
+        assert_eq!(device.try_build().unwrap_err(), BuilderError::UnexpectedEndpointType {
+            address: 0x82,
+            ep_type: EndpointType::Bulk,
+            direction: UsbDirection::Out,
+        });
+
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let [comm, read, _] = acm_endpoints(1);
+        let write = endpoint(2, UsbDirection::In, EndpointType::Interrupt, 64, 1);
+        create_acm_function(&mut device, &AcmOptions::new(), comm, read, write);
+        assert_eq!(device.try_build().unwrap_err(), BuilderError::UnexpectedEndpointType {
+            address: 0x82,
+            ep_type: EndpointType::Bulk,
+            direction: UsbDirection::In,
+        });
+    }
+
+    #[test]
+    fn rndis_function_with_ms_os_10_descriptors() {
+        let device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001)).ms_os_10_descriptors(0x20);
+        let mut rndis = RndisFunction::new(RndisOptions::new());
+        let config = build_device_function(device, &mut rndis).unwrap();
+
+        assert_eq!(config.configuration_descriptors[0], [
+            0x09, 0x02, 0x4b, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32, // configuration
+            0x08, 0x0b, 0x00, 0x02, 0xef, 0x04, 0x01, 0x00, // interface association, interfaces 0-1
+            0x09, 0x04, 0x00, 0x00, 0x01, 0xef, 0x04, 0x01, 0x00, // communication interface 0
+            0x05, 0x24, 0x00, 0x10, 0x01, // header
+            0x05, 0x24, 0x01, 0x00, 0x01, // call management
+            0x04, 0x24, 0x02, 0x00, // abstract control management
+            0x05, 0x24, 0x06, 0x00, 0x01, // union
+            0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0x01, // notification endpoint
+            0x09, 0x04, 0x01, 0x00, 0x02, 0x0a, 0x00, 0x00, 0x00, // data interface 1
+            0x07, 0x05, 0x82, 0x02, 0x40, 0x00, 0x00, // bulk IN endpoint
+            0x07, 0x05, 0x02, 0x02, 0x40, 0x00, 0x00, // bulk OUT endpoint
+        ][..]);
+        assert_eq!(response(&config, |r| r.index == Some(0x04)), &[
+            0x28, 0x00, 0x00, 0x00, 0x00, 0x01, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // header
+            0x00, 0x01, b'R', b'N', b'D', b'I', b'S', 0x00, 0x00, 0x00, // interface 0, compatible ID
+            b'5', b'1', b'6', b'2', b'0', b'0', b'1', 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // sub-compatible ID
+        ][..]);
+    }
+
+    #[test]
+    fn rndis_function_with_ms_os_20_descriptors() {
+        let device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001)).ms_os_20_descriptors(0x21);
+        let mut rndis = RndisFunction::new(RndisOptions::new());
+        let config = build_device_function(device, &mut rndis).unwrap();
+
+        let compatible_id = [
+            0x14, 0x00, 0x03, 0x00, b'R', b'N', b'D', b'I', b'S', 0x00, 0x00, 0x00,
+            b'5', b'1', b'6', b'2', b'0', b'0', b'1', 0x00,
+        ];
+        assert!(response(&config, |r| r.index == Some(0x07)).windows(compatible_id.len()).any(|w| w == compatible_id));
+        assert!(!config.string_descriptors.contains_key(&MS_OS_STRING_INDEX));
+    }
+
+    #[test]
+    fn rndis_function_requires_ms_os_descriptors() {
+        let device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let mut rndis = RndisFunction::new(RndisOptions::new());
+        assert_eq!(build_device_function(device, &mut rndis).unwrap_err(), BuilderError::MsOsDescriptorsDisabled { interface: 0 });
+
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let [comm, read, write] = acm_endpoints(1);
+        create_rndis_function(&mut device, &RndisOptions::new().cdc_class(true), comm, read, write);
+        assert_eq!(device.try_build().unwrap_err(), BuilderError::MsOsDescriptorsDisabled { interface: 0 });
+    }
 }
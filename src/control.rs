@@ -0,0 +1,74 @@
+use usb_device::control::Request;
+pub use usb_device::control::{Recipient, RequestType};
+
+/// A control request answered by the generated code.
+///
+/// Requests are matched on type, recipient and request code, and optionally on `wValue` and
+/// `wIndex`. The generated `GeneratedRequests` class answers matching requests before any other
+/// class sees them.
+#[derive(Clone, Debug)]
+pub struct ControlRequest {
+    pub request_type: RequestType,
+    pub recipient: Recipient,
+    pub request: u8,
+    pub value: Option<u16>,
+    pub index: Option<u16>,
+    pub response: ControlResponse,
+}
+
+#[derive(Clone, Debug)]
+pub enum ControlResponse {
+    /// Static data returned in the data stage of a control IN request.
+    Data(Vec<u8>),
+    /// Accepts a control OUT request. The request is kept by `GeneratedRequests` until the
+    /// application takes it with `take_request`, so that it can act on it. Standard requests with
+    /// a data stage to the host, like GET_STATUS, cannot be accepted.
+    Accept,
+}
+
+impl ControlRequest {
+    pub fn new(
+        request_type: RequestType,
+        recipient: Recipient,
+        request: u8,
+        response: ControlResponse,
+    ) -> Self {
+        Self {
+            request_type,
+            recipient,
+            request,
+            value: None,
+            index: None,
+            response,
+        }
+    }
+
+    /// Matches only requests with the given `wValue`.
+    ///
+    /// Default: (any)
+    pub fn value(mut self, value: u16) -> Self {
+        self.value = Some(value);
+        self
+    }
+
+    /// Matches only requests with the given `wIndex`.
+    ///
+    /// Default: (any)
+    pub fn index(mut self, index: u16) -> Self {
+        self.index = Some(index);
+        self
+    }
+
+    /// Returns `true` for standard requests whose data stage goes to the host.
+    pub(crate) fn is_standard_in_request(&self) -> bool {
+        self.request_type == RequestType::Standard
+            && [
+                Request::GET_STATUS,
+                Request::GET_DESCRIPTOR,
+                Request::GET_CONFIGURATION,
+                Request::GET_INTERFACE,
+                Request::SYNCH_FRAME,
+            ]
+            .contains(&self.request)
+    }
+}
@@ -0,0 +1,383 @@
+use crate::builder::DeviceBuilder;
+use crate::control::{ControlRequest, ControlResponse, Recipient, RequestType};
+use crate::endpoint::DeviceAllocator;
+use crate::error::BuilderError;
+use crate::function::UsbFunction;
+use crate::usb::USB_CLASS_APPLICATION_SPECIFIC;
+use bit_field::BitField;
+use std::fmt::Write;
+
+pub const DFU_SUBCLASS: u8 = 0x01;
+pub const DFU_PROTOCOL_RUNTIME: u8 = 0x01;
+pub const DFU_PROTOCOL_DFU_MODE: u8 = 0x02;
+
+const DFU_FUNCTIONAL_DESCRIPTOR: u8 = 0x21;
+
+pub const DFU_REQUEST_DETACH: u8 = 0x00;
+pub const DFU_REQUEST_GETSTATUS: u8 = 0x03;
+pub const DFU_REQUEST_GETSTATE: u8 = 0x05;
+
+const DFU_STATE_APP_IDLE: u8 = 0x00;
+const DFU_STATUS_OK: u8 = 0x00;
+
+/// DFU version used by ST DfuSe devices
+pub const DFUSE_VERSION: u16 = 0x011a;
+
+/// Settings of the DFU functional descriptor.
+#[derive(Clone, Debug)]
+pub struct DfuOptions {
+    pub attributes: u8,
+    pub detach_timeout_ms: u16,
+    pub transfer_size: u16,
+    pub dfu_version: u16,
+    pub interface_string: Option<String>,
+}
+
+impl Default for DfuOptions {
+    fn default() -> Self {
+        Self {
+            attributes: 0b0000_0111,
+            detach_timeout_ms: 1000,
+            transfer_size: 1024,
+            dfu_version: 0x0110,
+            interface_string: None,
+        }
+    }
+}
+
+impl DfuOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whether the device supports downloading firmware from the host.
+    ///
+    /// Default: `true`
+    pub fn can_download(mut self, can_download: bool) -> Self {
+        self.attributes.set_bit(0, can_download);
+        self
+    }
+
+    /// Sets whether the device supports uploading firmware to the host.
+    ///
+    /// Default: `true`
+    pub fn can_upload(mut self, can_upload: bool) -> Self {
+        self.attributes.set_bit(1, can_upload);
+        self
+    }
+
+    /// Sets whether the device still responds after the manifestation phase.
+    ///
+    /// Default: `true`
+    pub fn manifestation_tolerant(mut self, manifestation_tolerant: bool) -> Self {
+        self.attributes.set_bit(2, manifestation_tolerant);
+        self
+    }
+
+    /// Sets whether the device detaches and reattaches itself after DFU_DETACH instead of
+    /// waiting for a USB reset.
+    ///
+    /// Default: `false`
+    pub fn will_detach(mut self, will_detach: bool) -> Self {
+        self.attributes.set_bit(3, will_detach);
+        self
+    }
+
+    /// Sets the time in milliseconds the device waits for a USB reset after DFU_DETACH.
+    ///
+    /// Default: 1000
+    pub fn detach_timeout_ms(mut self, detach_timeout_ms: u16) -> Self {
+        self.detach_timeout_ms = detach_timeout_ms;
+        self
+    }
+
+    /// Sets the maximum number of bytes transferred per DFU_DNLOAD or DFU_UPLOAD request.
+    ///
+    /// Default: 1024
+    pub fn transfer_size(mut self, transfer_size: u16) -> Self {
+        self.transfer_size = transfer_size;
+        self
+    }
+
+    /// Sets the DFU specification release number in BCD, e.g. `DFUSE_VERSION`.
+    ///
+    /// Default: `0x0110`
+    pub fn dfu_version(mut self, dfu_version: u16) -> Self {
+        self.dfu_version = dfu_version;
+        self
+    }
+
+    /// Sets the string descriptor of the DFU runtime interface.
+    ///
+    /// Default: (none)
+    pub fn interface_string(mut self, interface_string: impl Into<String>) -> Self {
+        self.interface_string = Some(interface_string.into());
+        self
+    }
+
+    fn functional_descriptor(&self) -> [u8; 7] {
+        [
+            self.attributes, // bmAttributes
+            self.detach_timeout_ms as u8,
+            (self.detach_timeout_ms >> 8) as u8, // wDetachTimeOut
+            self.transfer_size as u8,
+            (self.transfer_size >> 8) as u8, // wTransferSize
+            self.dfu_version as u8,
+            (self.dfu_version >> 8) as u8, // bcdDFUVersion
+        ]
+    }
+}
+
+/// Access rights of a DfuSe memory segment.
+pub mod segment {
+    pub const READABLE: u8 = 0b001;
+    pub const ERASABLE: u8 = 0b010;
+    pub const WRITABLE: u8 = 0b100;
+}
+
+/// Builds a DfuSe memory layout string, like `@Internal Flash  /0x08000000/16*001Ka,112*001Kg`,
+/// used as the name of a DFU-mode alternate setting.
+#[derive(Clone, Debug)]
+pub struct DfuseMemoryLayout {
+    name: String,
+    address: u32,
+    segments: Vec<(u16, u32, u8)>,
+}
+
+impl DfuseMemoryLayout {
+    pub fn new(name: impl Into<String>, address: u32) -> Self {
+        Self {
+            name: name.into(),
+            address,
+            segments: Vec::new(),
+        }
+    }
+
+    /// Adds `count` consecutive sectors of `size` bytes with the given `segment` access rights.
+    pub fn segment(mut self, count: u16, size: u32, access: u8) -> Self {
+        self.segments.push((count, size, access));
+        self
+    }
+
+    pub fn build(&self) -> String {
+        let mut layout = format!("@{}/0x{:08X}/", self.name, self.address);
+        for (i, &(count, size, access)) in self.segments.iter().enumerate() {
+            if i != 0 {
+                layout.push(',');
+            }
+            let (size, unit) = if size % (1024 * 1024) == 0 {
+                (size / (1024 * 1024), 'M')
+            } else if size % 1024 == 0 {
+                (size / 1024, 'K')
+            } else {
+                (size, ' ')
+            };
+            let access = char::from(b'`' + (access & 0b111));
+            write!(layout, "{}*{:03}{}{}", count, size, unit, access).unwrap();
+        }
+        layout
+    }
+}
+
+/// Adds a DFU runtime interface, which lets the host switch the device to DFU mode.
+///
+/// The generated code answers DFU_GETSTATUS and DFU_GETSTATE with the appIDLE state, and accepts
+/// DFU_DETACH, after which the application has to detach and restart in DFU mode.
+pub fn create_dfu_runtime_function(device: &mut DeviceBuilder, options: &DfuOptions) {
+    let mut interface = device
+        .alloc_interface()
+        .interface_class(USB_CLASS_APPLICATION_SPECIFIC)
+        .interface_sub_class(DFU_SUBCLASS)
+        .interface_protocol(DFU_PROTOCOL_RUNTIME)
+        .descriptor(DFU_FUNCTIONAL_DESCRIPTOR, &options.functional_descriptor());
+    if let Some(interface_string) = &options.interface_string {
+        interface = interface.interface_string(interface_string.as_str());
+    }
+    let interface_number = u16::from(interface.descriptor.interface_number);
+    interface.save(device);
+
+    let requests = [
+        (DFU_REQUEST_DETACH, ControlResponse::Accept),
+        (
+            DFU_REQUEST_GETSTATUS,
+            ControlResponse::Data(vec![
+                DFU_STATUS_OK, // bStatus
+                0,
+                0,
+                0,                  // bwPollTimeout
+                DFU_STATE_APP_IDLE, // bState
+                0,                  // iString
+            ]),
+        ),
+        (
+            DFU_REQUEST_GETSTATE,
+            ControlResponse::Data(vec![DFU_STATE_APP_IDLE]),
+        ),
+    ];
+    for (request, response) in requests.iter().cloned() {
+        device.add_control_request(
+            ControlRequest::new(RequestType::Class, Recipient::Interface, request, response)
+                .index(interface_number),
+        );
+    }
+}
+
+/// Adds the DFU-mode interface of a device running a DFU bootloader, with one alternate setting
+/// named by each of `alternate_settings`, e.g. a memory region described by
+/// `DfuseMemoryLayout`.
+///
+/// A DFU-mode device should contain only this interface. The DFU class requests have to be
+/// handled by the application.
+pub fn create_dfu_mode_function(
+    device: &mut DeviceBuilder,
+    options: &DfuOptions,
+    alternate_settings: &[&str],
+) {
+    if alternate_settings.is_empty() {
+        device.defer_error(BuilderError::DfuWithoutAlternateSettings {
+            interface: device.next_interface_number(),
+        });
+        return;
+    }
+
+    let first = device.alloc_interface();
+    let mut interfaces = vec![first.clone()];
+    for _ in 1..alternate_settings.len() {
+        interfaces.push(device.alloc_alternate_setting(&first));
+    }
+
+    let last = interfaces.len() - 1;
+    for (i, (interface, name)) in interfaces.into_iter().zip(alternate_settings).enumerate() {
+        let mut interface = interface
+            .interface_class(USB_CLASS_APPLICATION_SPECIFIC)
+            .interface_sub_class(DFU_SUBCLASS)
+            .interface_protocol(DFU_PROTOCOL_DFU_MODE)
+            .interface_string(*name);
+        // The functional descriptor follows the last alternate setting
+        if i == last {
+            interface =
+                interface.descriptor(DFU_FUNCTIONAL_DESCRIPTOR, &options.functional_descriptor());
+        }
+        interface.save(device);
+    }
+}
+
+/// DFU runtime function. It has no endpoints, DFU uses the default control pipe only.
+pub struct DfuFunction {
+    pub options: DfuOptions,
+}
+
+impl DfuFunction {
+    pub fn new(options: DfuOptions) -> Self {
+        Self { options }
+    }
+}
+
+impl UsbFunction for DfuFunction {
+    fn allocate_endpoints(&mut self, _allocator: &mut DeviceAllocator) -> Result<(), BuilderError> {
+        Ok(())
+    }
+
+    fn add_to_device(&self, device: &mut DeviceBuilder) -> Result<(), BuilderError> {
+        create_dfu_runtime_function(device, &self.options);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::UsbVidPid;
+    use crate::test_util::build_function;
+
+    #[test]
+    fn runtime_function() {
+        let config = build_function(&mut DfuFunction::new(DfuOptions::new())).unwrap();
+
+        assert_eq!(
+            config.configuration_descriptors[0],
+            [
+                0x09, 0x02, 0x1b, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, // configuration
+                0x09, 0x04, 0x00, 0x00, 0x00, 0xfe, 0x01, 0x01, 0x00, // DFU runtime interface
+                0x09, 0x21, 0x07, 0xe8, 0x03, 0x00, 0x04, 0x10, 0x01, // DFU functional
+            ][..]
+        );
+
+        let requests: Vec<_> = config
+            .control_requests
+            .iter()
+            .map(|r| (r.request, r.index, &r.response))
+            .collect();
+        assert_eq!(requests.len(), 3);
+        assert!(matches!(
+            requests[0],
+            (DFU_REQUEST_DETACH, Some(0), ControlResponse::Accept)
+        ));
+        match requests[1] {
+            (DFU_REQUEST_GETSTATUS, Some(0), ControlResponse::Data(data)) => {
+                assert_eq!(data, &[0, 0, 0, 0, 0, 0])
+            }
+            _ => panic!("unexpected request"),
+        }
+        match requests[2] {
+            (DFU_REQUEST_GETSTATE, Some(0), ControlResponse::Data(data)) => {
+                assert_eq!(data, &[DFU_STATE_APP_IDLE])
+            }
+            _ => panic!("unexpected request"),
+        }
+    }
+
+    #[test]
+    fn dfuse_memory_layout() {
+        let layout = DfuseMemoryLayout::new("Internal Flash  ", 0x0800_0000)
+            .segment(16, 1024, segment::READABLE)
+            .segment(
+                112,
+                1024,
+                segment::READABLE | segment::ERASABLE | segment::WRITABLE,
+            );
+        assert_eq!(
+            layout.build(),
+            "@Internal Flash  /0x08000000/16*001Ka,112*001Kg"
+        );
+
+        let layout = DfuseMemoryLayout::new("Option Bytes", 0x1fff_c000)
+            .segment(1, 16, segment::READABLE | segment::WRITABLE)
+            .segment(2, 1024 * 1024, segment::ERASABLE);
+        assert_eq!(layout.build(), "@Option Bytes/0x1FFFC000/1*016 e,2*001Mb");
+    }
+
+    #[test]
+    fn dfu_mode_function() {
+        let options = DfuOptions::new()
+            .will_detach(true)
+            .detach_timeout_ms(255)
+            .transfer_size(2048)
+            .dfu_version(DFUSE_VERSION);
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        create_dfu_mode_function(&mut device, &options, &["@Flash/0x08000000/4*16Kg", "@RAM"]);
+        let config = device.build();
+
+        assert_eq!(
+            config.configuration_descriptors[0],
+            [
+                0x09, 0x02, 0x24, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, // configuration
+                0x09, 0x04, 0x00, 0x00, 0x00, 0xfe, 0x01, 0x02, 0x01, // alternate setting 0
+                0x09, 0x04, 0x00, 0x01, 0x00, 0xfe, 0x01, 0x02, 0x02, // alternate setting 1
+                0x09, 0x21, 0x0f, 0xff, 0x00, 0x00, 0x08, 0x1a, 0x01, // DFU functional
+            ][..]
+        );
+        assert_eq!(config.string_descriptors[&2], b"\x0a\x03@\0R\0A\0M\0");
+        assert!(config.control_requests.is_empty());
+    }
+
+    #[test]
+    fn dfu_mode_without_alternate_settings() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        create_dfu_mode_function(&mut device, &DfuOptions::new(), &[]);
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::DfuWithoutAlternateSettings { interface: 0 }
+        );
+    }
+}
@@ -1,27 +1,32 @@
 use usb_device::UsbDirection;
-use failure::{Error, bail, err_msg};
-use usb_device::endpoint::EndpointType;
+use usb_device::endpoint::{EndpointAddress, EndpointType};
 use crate::builder::{EndpointBuilder, DeviceBuilder};
+use crate::error::BuilderError;
 use crate::usb::{USB_MAX_ENDPOINTS, UsbEndpointDescriptor};
 use crate::EndpointInfo;
 
-pub fn calculate_count_rx(mut size: u16) -> Result<(u16, u16), Error> {
+/// Returns the rounded up buffer size and the `COUNTn_RX` register value for a receive buffer of
+/// endpoint `address`, or an error if the size is too big.
+pub fn calculate_count_rx(address: EndpointAddress, mut size: u16) -> Result<(u16, u16), BuilderError> {
     if size <= 62 {
         // Buffer size is in units of 2 bytes, 0 = 0 bytes
         size = (size + 1) & !0x01;
 
         let size_bits = size >> 1;
 
-        Ok((size, (size_bits << 10) as u16))
+        Ok((size, size_bits << 10))
     } else if size <= 1024 {
         // Buffer size is in units of 32 bytes, 0 = 32 bytes
         size = (size + 31) & !0x1f;
 
         let size_bits = (size >> 5) - 1;
 
-        Ok((size, (0x8000 | (size_bits << 10)) as u16))
+        Ok((size, 0x8000 | (size_bits << 10)))
     } else {
-        bail!("Invalid size")
+        Err(BuilderError::InvalidMaxPacketSize {
+            address: address.into(),
+            max_packet_size: size,
+        })
     }
 }
 
@@ -50,13 +55,7 @@ impl EndpointAllocation {
     }
 
     fn has_space(&self, ep_type: EndpointType, direction: UsbDirection) -> bool {
-        if self.ep_type != ep_type {
-            false
-        } else if self.double_buffered {
-            false
-        } else {
-            !self.has_direction(direction)
-        }
+        self.ep_type == ep_type && !self.double_buffered && !self.has_direction(direction)
     }
 }
 
@@ -69,6 +68,12 @@ pub struct DeviceAllocator {
 const DEVICE_ENDPOINT_COUNT: usize = 8;
 const ENDPOINT_MEMORY_SIZE: u16 = 512;
 
+impl Default for DeviceAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl DeviceAllocator {
     pub fn new() -> DeviceAllocator {
         Self {
@@ -78,57 +83,59 @@ impl DeviceAllocator {
         }
     }
 
-    fn allocate_endpoint_buffer(&mut self, size: u16) -> Result<EndpointMemoryAllocation, Error> {
+    fn allocate_endpoint_buffer(&mut self, size: u16) -> Option<EndpointMemoryAllocation> {
         let size = (size + 1) & !0x01;
         if size <= (self.end_address - self.start_address) {
             self.end_address -= size;
             let address = self.end_address;
-            Ok(EndpointMemoryAllocation {
+            Some(EndpointMemoryAllocation {
                 address,
                 size,
             })
         } else {
-            bail!("Can't allocate endpoint buffer: not enough space");
+            None
         }
     }
 
-    fn allocate_buffer_descriptor(&mut self) -> Result<EndpointMemoryAllocation, Error> {
+    fn allocate_buffer_descriptor(&mut self) -> Option<EndpointMemoryAllocation> {
         assert_eq!(self.start_address % 8, 0);
         let size = 8;
         if size <= (self.end_address - self.start_address) {
             let address = self.start_address;
             self.start_address += size;
-            Ok(EndpointMemoryAllocation {
+            Some(EndpointMemoryAllocation {
                 address,
                 size,
             })
         } else {
-            bail!("Can't allocate buffer descriptor: not enough space")
+            None
         }
     }
 
-    fn allocate_rx_buffer(&mut self, max_packet_size: u16) -> Result<EndpointMemoryAllocation, Error> {
-        let (size, _) = calculate_count_rx(max_packet_size)?;
-        self.allocate_endpoint_buffer(size)
+    fn allocate_rx_buffer(&mut self, address: EndpointAddress, max_packet_size: u16) -> Result<EndpointMemoryAllocation, BuilderError> {
+        let (size, _) = calculate_count_rx(address, max_packet_size)?;
+        self.allocate_endpoint_buffer(size).ok_or(BuilderError::PmaExhausted { address: address.into() })
     }
 
-    fn allocate_tx_buffer(&mut self, max_packet_size: u16) -> Result<EndpointMemoryAllocation, Error> {
-        self.allocate_endpoint_buffer(max_packet_size)
+    fn allocate_tx_buffer(&mut self, address: EndpointAddress, max_packet_size: u16) -> Result<EndpointMemoryAllocation, BuilderError> {
+        self.allocate_endpoint_buffer(max_packet_size).ok_or(BuilderError::PmaExhausted { address: address.into() })
     }
 
-    fn get_free_address_index(&self) -> Result<u8, Error> {
+    fn get_free_address_index(&self) -> Result<u8, BuilderError> {
         for index in 1..USB_MAX_ENDPOINTS {
             if !self.endpoints.iter().any(|ep| ep.address_index == index as u8) {
                 return Ok(index as u8);
             }
         }
-        bail!("All endpoint addressees are already allocated")
+        Err(BuilderError::NoFreeEndpoint)
     }
 
-    fn allocate_empty_endpoint(&mut self, ep_type: EndpointType) -> Result<usize, Error> {
+    fn allocate_empty_endpoint(&mut self, ep_type: EndpointType, direction: UsbDirection) -> Result<usize, BuilderError> {
         if self.endpoints.len() < DEVICE_ENDPOINT_COUNT {
             let address_index = self.get_free_address_index()?;
-            let buffer_descriptor = self.allocate_buffer_descriptor()?;
+            let address = EndpointAddress::from_parts(address_index as usize, direction);
+            let buffer_descriptor = self.allocate_buffer_descriptor()
+                .ok_or(BuilderError::PmaExhausted { address: address.into() })?;
             let ep = EndpointAllocation {
                 address_index,
                 ep_type,
@@ -142,44 +149,47 @@ impl DeviceAllocator {
             self.endpoints.push(ep);
             Ok(i)
         } else {
-            bail!("Can't allocate endpoint");
+            Err(BuilderError::NoFreeEndpoint)
         }
     }
 
-    fn allocate_from_builder(&mut self, builder: EndpointBuilder, double_buffered: bool) -> Result<EndpointBuilder, Error> {
-        let ep_type = builder.ep_type.ok_or_else(|| err_msg("Endpoint type is not set"))?;
-        let direction = builder.direction.ok_or_else(|| err_msg("Endpoint direction is not set"))?;
-        let max_packet_size = builder.max_packet_size.ok_or_else(|| err_msg("Max packet size is not set"))?;
+    fn allocate_from_builder(&mut self, builder: EndpointBuilder, double_buffered: bool) -> Result<EndpointBuilder, BuilderError> {
+        let ep_type = builder.ep_type.ok_or_else(|| builder.missing_field("ep_type"))?;
+        let direction = builder.direction.ok_or_else(|| builder.missing_field("direction"))?;
+        let max_packet_size = builder.max_packet_size.ok_or_else(|| builder.missing_field("max_packet_size"))?;
 
         let ep_index;
         if let Some(address_index) = builder.number {
+            if address_index as usize >= USB_MAX_ENDPOINTS {
+                return Err(BuilderError::InvalidEndpointNumber(address_index));
+            }
             if let Some((i, ep)) = self.endpoints.iter().enumerate().find(|(_, ep)| ep.address_index == address_index) {
                 if double_buffered || ep.double_buffered || ep.has_direction(direction) {
-                    bail!("Endpoint with given address is already exists");
+                    let address = EndpointAddress::from_parts(address_index as usize, direction);
+                    return Err(BuilderError::DuplicateEndpointAddress { address: address.into() });
                 }
                 ep_index = i;
             } else {
-                let i = self.allocate_empty_endpoint(ep_type)?;
+                let i = self.allocate_empty_endpoint(ep_type, direction)?;
                 self.endpoints[i].address_index = address_index;
                 ep_index = i;
             }
+        } else if let Some((i, _)) = self.endpoints.iter().enumerate().find(|(_, ep)| ep.has_space(ep_type, direction)) {
+            ep_index = i;
         } else {
-            if let Some((i, _)) = self.endpoints.iter().enumerate().find(|(_, ep)| ep.has_space(ep_type, direction)) {
-                ep_index = i;
-            } else {
-                ep_index = self.allocate_empty_endpoint(ep_type)?;
-            }
+            ep_index = self.allocate_empty_endpoint(ep_type, direction)?;
         }
 
+        let address = EndpointAddress::from_parts(self.endpoints[ep_index].address_index as usize, direction);
         if double_buffered {
             let buf0;
             let buf1;
             if direction == UsbDirection::In {
-                buf0 = self.allocate_tx_buffer(max_packet_size)?;
-                buf1 = self.allocate_tx_buffer(max_packet_size)?;
+                buf0 = self.allocate_tx_buffer(address, max_packet_size)?;
+                buf1 = self.allocate_tx_buffer(address, max_packet_size)?;
             } else {
-                buf0 = self.allocate_rx_buffer(max_packet_size)?;
-                buf1 = self.allocate_rx_buffer(max_packet_size)?;
+                buf0 = self.allocate_rx_buffer(address, max_packet_size)?;
+                buf1 = self.allocate_rx_buffer(address, max_packet_size)?;
             }
 
             let ep = &mut self.endpoints[ep_index];
@@ -190,9 +200,9 @@ impl DeviceAllocator {
             ep.buffers[1] = Some(buf1);
         } else {
             let buffer = if direction == UsbDirection::In {
-                self.allocate_tx_buffer(max_packet_size)?
+                self.allocate_tx_buffer(address, max_packet_size)?
             } else {
-                self.allocate_rx_buffer(max_packet_size)?
+                self.allocate_rx_buffer(address, max_packet_size)?
             };
 
             let ep = &mut self.endpoints[ep_index];
@@ -212,15 +222,22 @@ impl DeviceAllocator {
         })
     }
 
-    fn allocate_ep0_from_builfer(&mut self, builder: DeviceBuilder) -> Result<DeviceBuilder, Error> {
+    fn allocate_ep0_from_builfer(&mut self, builder: DeviceBuilder) -> Result<DeviceBuilder, BuilderError> {
         let max_packet_size = builder.descriptor.max_packet_size_0 as u16;
+        match max_packet_size {
+            8 | 16 | 32 | 64 => {}
+            size => return Err(BuilderError::InvalidEp0Size(size as u8)),
+        }
 
+        let ep0_out = EndpointAddress::from_parts(0, UsbDirection::Out);
+        let ep0_in = EndpointAddress::from_parts(0, UsbDirection::In);
         if self.endpoints.iter().any(|ep| ep.address_index == 0) {
-            bail!("Endpoint 0 is already allocated!");
+            return Err(BuilderError::DuplicateEndpointAddress { address: ep0_out.into() });
         }
-        let buffer_descriptor = self.allocate_buffer_descriptor()?;
-        let buffer_tx = self.allocate_tx_buffer(max_packet_size)?;
-        let buffer_rx = self.allocate_rx_buffer(max_packet_size)?;
+        let buffer_descriptor = self.allocate_buffer_descriptor()
+            .ok_or(BuilderError::PmaExhausted { address: ep0_out.into() })?;
+        let buffer_tx = self.allocate_tx_buffer(ep0_in, max_packet_size)?;
+        let buffer_rx = self.allocate_rx_buffer(ep0_out, max_packet_size)?;
         let ep = EndpointAllocation {
             address_index: 0,
             ep_type: EndpointType::Control,
@@ -245,35 +262,47 @@ impl EndpointInfo for DeviceEndpoint {
     }
 }
 
-pub trait EndpointBuilderEx {
-    fn allocate(self, allocator: &mut DeviceAllocator) -> DeviceEndpoint;
+pub trait EndpointBuilderEx: Sized {
+    fn try_allocate(self, allocator: &mut DeviceAllocator) -> Result<DeviceEndpoint, BuilderError>;
+
+    fn try_allocate_double_buffered(self, allocator: &mut DeviceAllocator) -> Result<DeviceEndpoint, BuilderError>;
+
+    fn allocate(self, allocator: &mut DeviceAllocator) -> DeviceEndpoint {
+        self.try_allocate(allocator).unwrap_or_else(|e| panic!("{}", e))
+    }
 
-    fn allocate_double_buffered(self, allocator: &mut DeviceAllocator) -> DeviceEndpoint;
+    fn allocate_double_buffered(self, allocator: &mut DeviceAllocator) -> DeviceEndpoint {
+        self.try_allocate_double_buffered(allocator).unwrap_or_else(|e| panic!("{}", e))
+    }
 }
 
 impl EndpointBuilderEx for EndpointBuilder {
-    fn allocate(self, allocator: &mut DeviceAllocator) -> DeviceEndpoint {
-        let descriptor = allocator.allocate_from_builder(self, false).unwrap().build();
-        DeviceEndpoint {
+    fn try_allocate(self, allocator: &mut DeviceAllocator) -> Result<DeviceEndpoint, BuilderError> {
+        let descriptor = allocator.allocate_from_builder(self, false)?.try_build()?;
+        Ok(DeviceEndpoint {
             descriptor,
-        }
+        })
     }
 
-    fn allocate_double_buffered(self, allocator: &mut DeviceAllocator) -> DeviceEndpoint {
-        let descriptor = allocator.allocate_from_builder(self, true).unwrap().build();
-        DeviceEndpoint {
+    fn try_allocate_double_buffered(self, allocator: &mut DeviceAllocator) -> Result<DeviceEndpoint, BuilderError> {
+        let descriptor = allocator.allocate_from_builder(self, true)?.try_build()?;
+        Ok(DeviceEndpoint {
             descriptor,
-        }
+        })
     }
 }
 
-pub trait DeviceBuilderEx {
-    fn allocate(self, allocator: &mut DeviceAllocator) -> Self;
+pub trait DeviceBuilderEx: Sized {
+    fn try_allocate(self, allocator: &mut DeviceAllocator) -> Result<Self, BuilderError>;
+
+    fn allocate(self, allocator: &mut DeviceAllocator) -> Self {
+        self.try_allocate(allocator).unwrap_or_else(|e| panic!("{}", e))
+    }
 }
 
 impl DeviceBuilderEx for DeviceBuilder {
-    fn allocate(self, allocator: &mut DeviceAllocator) -> Self {
-        allocator.allocate_ep0_from_builfer(self).unwrap()
+    fn try_allocate(self, allocator: &mut DeviceAllocator) -> Result<Self, BuilderError> {
+        allocator.allocate_ep0_from_builfer(self)
     }
 }
 
@@ -291,7 +320,7 @@ pub struct TargetEndpointConfiguration {
     pub buffer1_size_words: u16,
 }
 
-fn create_buffer_descriptor(mem: Option<EndpointMemoryAllocation>, is_rx: bool) -> (u16, u16, u16, u16) {
+fn create_buffer_descriptor(mem: Option<EndpointMemoryAllocation>, is_rx: bool, address_index: u8) -> (u16, u16, u16, u16) {
     let offset_words;
     let size_words;
     let address;
@@ -302,7 +331,8 @@ fn create_buffer_descriptor(mem: Option<EndpointMemoryAllocation>, is_rx: bool)
         size_words = mem.size >> 1;
 
         if is_rx {
-            let (size, bits) = calculate_count_rx(mem.size).unwrap();
+            let ep_address = EndpointAddress::from_parts(address_index as usize, UsbDirection::Out);
+            let (size, bits) = calculate_count_rx(ep_address, mem.size).unwrap();
             assert_eq!(size, mem.size);
             count = bits;
         } else {
@@ -327,9 +357,9 @@ impl From<EndpointAllocation> for TargetEndpointConfiguration {
             EndpointType::Interrupt => 0b11,
         };*/
         let (buffer0_offset_words, buffer0_size_words, buffer0_addr, buffer0_count) =
-            create_buffer_descriptor(ep.buffers[0], ep.double_buffered && ep.rx_enabled);
+            create_buffer_descriptor(ep.buffers[0], ep.double_buffered && ep.rx_enabled, ep.address_index);
         let (buffer1_offset_words, buffer1_size_words, buffer1_addr, buffer1_count) =
-            create_buffer_descriptor(ep.buffers[1], ep.rx_enabled);
+            create_buffer_descriptor(ep.buffers[1], ep.rx_enabled, ep.address_index);
         TargetEndpointConfiguration {
             ep_address: ep.address_index,
             ep_type: ep.ep_type,
@@ -355,7 +385,74 @@ impl From<DeviceAllocator> for TargetDeviceConfiguration {
     fn from(dev: DeviceAllocator) -> Self {
         TargetDeviceConfiguration {
             buffer_table_address: 0,
-            endpoints: dev.endpoints.into_iter().map(|ep| TargetEndpointConfiguration::from(ep)).collect(),
+            endpoints: dev.endpoints.into_iter().map(TargetEndpointConfiguration::from).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::UsbVidPid;
+
+    fn allocator() -> DeviceAllocator {
+        let mut alloc = DeviceAllocator::new();
+        DeviceBuilder::new(UsbVidPid(0x1209, 0x0001)).try_allocate(&mut alloc).unwrap();
+        alloc
+    }
+
+    fn endpoint(direction: UsbDirection, ep_type: EndpointType, max_packet_size: u16) -> EndpointBuilder {
+        EndpointBuilder::new().direction(direction).ep_type(ep_type).max_packet_size(max_packet_size)
+    }
+
+    #[test]
+    fn count_rx() {
+        let address = EndpointAddress::from_parts(1, UsbDirection::Out);
+        assert_eq!(calculate_count_rx(address, 8), Ok((8, 0x1000)));
+        assert_eq!(calculate_count_rx(address, 63), Ok((64, 0x8400)));
+        assert_eq!(calculate_count_rx(address, 1024), Ok((1024, 0xfc00)));
+        assert_eq!(
+            calculate_count_rx(address, 1025),
+            Err(BuilderError::InvalidMaxPacketSize { address: 0x01, max_packet_size: 1025 })
+        );
+    }
+
+    #[test]
+    fn endpoints_of_the_same_type_share_a_number() {
+        let mut alloc = allocator();
+        let out_ep = endpoint(UsbDirection::Out, EndpointType::Bulk, 64).try_allocate(&mut alloc).unwrap();
+        let in_ep = endpoint(UsbDirection::In, EndpointType::Bulk, 64).try_allocate(&mut alloc).unwrap();
+        let interrupt_ep = endpoint(UsbDirection::In, EndpointType::Interrupt, 8).try_allocate(&mut alloc).unwrap();
+        assert_eq!(u8::from(out_ep.address()), 0x01);
+        assert_eq!(u8::from(in_ep.address()), 0x81);
+        assert_eq!(u8::from(interrupt_ep.address()), 0x82);
+    }
+
+    #[test]
+    fn allocation_errors() {
+        let mut alloc = DeviceAllocator::new();
+        let device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001)).max_packet_size_0(9);
+        assert_eq!(device.try_allocate(&mut alloc).err(), Some(BuilderError::InvalidEp0Size(9)));
+
+        let mut alloc = allocator();
+        let builder = endpoint(UsbDirection::In, EndpointType::Bulk, 64).number(1);
+        builder.try_allocate(&mut alloc).unwrap();
+        let builder = endpoint(UsbDirection::In, EndpointType::Bulk, 64).number(1);
+        assert_eq!(builder.try_allocate(&mut alloc).err(), Some(BuilderError::DuplicateEndpointAddress { address: 0x81 }));
+
+        let builder = endpoint(UsbDirection::Out, EndpointType::Bulk, 1100);
+        assert_eq!(builder.try_allocate(&mut alloc).err(), Some(BuilderError::InvalidMaxPacketSize { address: 0x01, max_packet_size: 1100 }));
+
+        let mut alloc = allocator();
+        let builder = endpoint(UsbDirection::In, EndpointType::Bulk, 512);
+        assert_eq!(builder.try_allocate(&mut alloc).err(), Some(BuilderError::PmaExhausted { address: 0x81 }));
+
+        // Endpoint 0 and 7 more hardware endpoints
+        let mut alloc = allocator();
+        for _ in 0..7 {
+            endpoint(UsbDirection::In, EndpointType::Interrupt, 8).try_allocate(&mut alloc).unwrap();
         }
+        let builder = endpoint(UsbDirection::In, EndpointType::Interrupt, 8);
+        assert_eq!(builder.try_allocate(&mut alloc).err(), Some(BuilderError::NoFreeEndpoint));
     }
 }
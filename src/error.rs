@@ -0,0 +1,331 @@
+use failure::Fail;
+use std::fmt;
+use usb_device::endpoint::EndpointType;
+use usb_device::UsbDirection;
+
+/// Errors reported by the device, interface and endpoint builders.
+///
+/// Endpoint addresses are reported as the raw `bEndpointAddress` value, i.e. with the direction
+/// bit set for IN endpoints.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BuilderError {
+    InvalidEp0Size(u8),
+    MaxPowerTooHigh {
+        configuration: u8,
+        max_power_ma: usize,
+    },
+    ConfigurationNotAllocated {
+        configuration: u8,
+    },
+    ConfigurationWithoutInterfaces {
+        configuration: u8,
+    },
+    InterfaceNotAllocated {
+        configuration: u8,
+        interface: u8,
+        alternate_setting: u8,
+    },
+    TooManyAlternateSettings {
+        configuration: u8,
+        interface: u8,
+    },
+    FunctionWithoutInterfaces {
+        configuration: u8,
+        first_interface: u8,
+    },
+    EndpointUsedTwice {
+        configuration: u8,
+        interface: u8,
+        address: u8,
+    },
+    MissingEndpointField {
+        field: &'static str,
+        number: Option<u8>,
+        direction: Option<UsbDirection>,
+    },
+    InvalidEndpointNumber(u8),
+    UnexpectedEndpointType {
+        address: u8,
+        ep_type: EndpointType,
+        direction: UsbDirection,
+    },
+    DuplicateEndpointAddress {
+        address: u8,
+    },
+    InvalidMaxPacketSize {
+        address: u8,
+        max_packet_size: u16,
+    },
+    PmaExhausted {
+        address: u8,
+    },
+    NoFreeEndpoint,
+    InvalidBesl(u8),
+    TranslationToPrimaryLanguage {
+        lang_id: u16,
+    },
+    InvalidCompatibleId {
+        interface: u8,
+        compatible_id: String,
+    },
+    InvalidWebUsbUrl {
+        url: String,
+        reason: &'static str,
+    },
+    InvalidWebUsbOrigin {
+        origin: String,
+        reason: &'static str,
+    },
+    InvalidReportDescriptor(&'static str),
+    InvalidLunCount(u8),
+    InvalidAudioTopology {
+        entity: u8,
+        reason: &'static str,
+    },
+    InvalidMidiCableCount {
+        cables_from_host: u8,
+        cables_to_host: u8,
+    },
+    DfuWithoutAlternateSettings {
+        interface: u8,
+    },
+    UnallocatedFunctionEndpoints {
+        first_interface: u8,
+    },
+    InvalidPrinterProtocols {
+        interface: u8,
+        reason: &'static str,
+    },
+    InvalidVideoFormat {
+        interface: u8,
+        reason: &'static str,
+    },
+    InvalidCcidOptions {
+        interface: u8,
+        reason: &'static str,
+    },
+    InvalidUsbtmcCapabilities {
+        interface: u8,
+        reason: &'static str,
+    },
+    MsOsDescriptorsDisabled {
+        interface: u8,
+    },
+    AcceptedInRequest {
+        request: u8,
+    },
+    DeviceClassWithFunctions {
+        device_class: u8,
+        device_sub_class: u8,
+        device_protocol: u8,
+    },
+    ConflictingEndpoint {
+        configuration: u8,
+        address: u8,
+    },
+    MissingMidiEndpoint {
+        direction: UsbDirection,
+    },
+}
+
+impl fmt::Display for BuilderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use BuilderError::*;
+
+        match self {
+            InvalidEp0Size(size) => write!(
+                f,
+                "invalid max_packet_size_0: {} (valid values are 8, 16, 32 and 64)",
+                size
+            ),
+            MaxPowerTooHigh {
+                configuration,
+                max_power_ma,
+            } => write!(
+                f,
+                "configuration {}: max_power of {} mA is too much (at most 500 mA)",
+                configuration, max_power_ma
+            ),
+            ConfigurationNotAllocated { configuration } => {
+                write!(f, "configuration {} is not allocated", configuration)
+            }
+            ConfigurationWithoutInterfaces { configuration } => {
+                write!(f, "configuration {} has no interfaces", configuration)
+            }
+            InterfaceNotAllocated {
+                configuration,
+                interface,
+                alternate_setting,
+            } => write!(
+                f,
+                "configuration {}: interface {} alternate setting {} is not allocated",
+                configuration, interface, alternate_setting
+            ),
+            TooManyAlternateSettings {
+                configuration,
+                interface,
+            } => write!(
+                f,
+                "configuration {}: interface {} has more than 256 alternate settings",
+                configuration, interface
+            ),
+            FunctionWithoutInterfaces {
+                configuration,
+                first_interface,
+            } => write!(
+                f,
+                "configuration {}: function starting at interface {} has no interfaces",
+                configuration, first_interface
+            ),
+            EndpointUsedTwice {
+                configuration,
+                interface,
+                address,
+            } => write!(
+                f,
+                "configuration {}: interface {} uses endpoint 0x{:02x} which is already in use",
+                configuration, interface, address
+            ),
+            MissingEndpointField {
+                field,
+                number,
+                direction,
+            } => {
+                write!(f, "endpoint")?;
+                if let Some(number) = number {
+                    write!(f, " {}", number)?;
+                }
+                if let Some(direction) = direction {
+                    write!(f, " {:?}", direction)?;
+                }
+                write!(f, ": {} is not set", field)
+            }
+            InvalidEndpointNumber(number) => write!(
+                f,
+                "invalid endpoint number: {} (must be less than 16)",
+                number
+            ),
+            UnexpectedEndpointType {
+                address,
+                ep_type,
+                direction,
+            } => write!(
+                f,
+                "endpoint 0x{:02x}: expected {:?} {:?} endpoint",
+                address, ep_type, direction
+            ),
+            DuplicateEndpointAddress { address } => {
+                write!(f, "endpoint 0x{:02x} is already allocated", address)
+            }
+            InvalidMaxPacketSize {
+                address,
+                max_packet_size,
+            } => write!(
+                f,
+                "endpoint 0x{:02x}: invalid max packet size {}",
+                address, max_packet_size
+            ),
+            PmaExhausted { address } => {
+                write!(f, "endpoint 0x{:02x}: not enough packet memory", address)
+            }
+            NoFreeEndpoint => write!(f, "all hardware endpoints are already allocated"),
+            InvalidBesl(besl) => write!(f, "invalid BESL value: {} (must be less than 16)", besl),
+            TranslationToPrimaryLanguage { lang_id } => write!(
+                f,
+                "translation to the primary language 0x{:04x} (its strings are given to the builders)",
+                lang_id
+            ),
+            InvalidCompatibleId {
+                interface,
+                compatible_id,
+            } => write!(
+                f,
+                "interface {}: invalid compatible ID {:?} (at most 8 ASCII characters)",
+                interface, compatible_id
+            ),
+            InvalidWebUsbUrl { url, reason } => {
+                write!(f, "invalid WebUSB URL {:?}: {}", url, reason)
+            }
+            InvalidWebUsbOrigin { origin, reason } => {
+                write!(f, "invalid WebUSB origin {:?}: {}", origin, reason)
+            }
+            InvalidReportDescriptor(reason) => {
+                write!(f, "invalid HID report descriptor: {}", reason)
+            }
+            InvalidLunCount(count) => write!(
+                f,
+                "invalid number of logical units: {} (must be from 1 to 16)",
+                count
+            ),
+            InvalidAudioTopology { entity, reason } => {
+                write!(f, "audio entity {}: {}", entity, reason)
+            }
+            InvalidMidiCableCount {
+                cables_from_host,
+                cables_to_host,
+            } => write!(
+                f,
+                "invalid number of MIDI cables: {} from host, {} to host (at most 16 each, at least one in total)",
+                cables_from_host, cables_to_host
+            ),
+            DfuWithoutAlternateSettings { interface } => write!(
+                f,
+                "interface {}: DFU-mode interface has no alternate settings",
+                interface
+            ),
+            UnallocatedFunctionEndpoints { first_interface } => write!(
+                f,
+                "function starting at interface {} added to the device before allocating its endpoints",
+                first_interface
+            ),
+            InvalidPrinterProtocols { interface, reason } => {
+                write!(f, "interface {}: invalid printer protocols: {}", interface, reason)
+            }
+            InvalidVideoFormat { interface, reason } => {
+                write!(f, "interface {}: invalid video format: {}", interface, reason)
+            }
+            InvalidCcidOptions { interface, reason } => {
+                write!(f, "interface {}: invalid CCID options: {}", interface, reason)
+            }
+            InvalidUsbtmcCapabilities { interface, reason } => write!(
+                f,
+                "interface {}: invalid USBTMC capabilities: {}",
+                interface, reason
+            ),
+            MsOsDescriptorsDisabled { interface } => write!(
+                f,
+                "interface {}: the compatible ID requires MS OS 1.0 or 2.0 descriptors",
+                interface
+            ),
+            AcceptedInRequest { request } => write!(
+                f,
+                "control request 0x{:02x}: IN requests have to be answered with data",
+                request
+            ),
+            DeviceClassWithFunctions {
+                device_class,
+                device_sub_class,
+                device_protocol,
+            } => write!(
+                f,
+                "device class 0x{:02x}/0x{:02x}/0x{:02x} cannot be combined with functions (use 0x00 or 0xef/0x02/0x01)",
+                device_class, device_sub_class, device_protocol
+            ),
+            ConflictingEndpoint {
+                configuration,
+                address,
+            } => write!(
+                f,
+                "configuration {}: endpoint 0x{:02x} differs from the same endpoint in another alternate setting or an earlier configuration",
+                configuration, address
+            ),
+            MissingMidiEndpoint { direction } => write!(
+                f,
+                "MIDI function has cables in the {:?} direction, but no endpoint for them",
+                direction
+            ),
+        }
+    }
+}
+
+impl Fail for BuilderError {}
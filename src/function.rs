@@ -0,0 +1,255 @@
+use crate::builder::{DeviceBuilder, EndpointBuilder};
+use crate::control::ControlRequest;
+use crate::endpoint::{DeviceAllocator, EndpointBuilderEx};
+use crate::error::BuilderError;
+use crate::usb::UsbEndpointDescriptor;
+use crate::EndpointInfo;
+use usb_device::endpoint::EndpointType;
+use usb_device::UsbDirection;
+
+/// A USB function that can be composed with other functions into a device by `add_functions`.
+///
+/// The `*Function` types of the class modules implement this trait by allocating their own
+/// endpoints first and then adding their interfaces, descriptors, strings and control requests to
+/// the device, so unlike the `create_*_function` helpers they take no endpoint arguments.
+/// Functions defined outside this crate can keep their endpoints in `FunctionEndpoints` and check
+/// the settings with `DeviceBuilder::check_endpoint` and `DeviceBuilder::defer_error`, like the
+/// built-in ones.
+pub trait UsbFunction {
+    /// Allocates the endpoints of the function. Called before `add_to_device`.
+    fn allocate_endpoints(&mut self, allocator: &mut DeviceAllocator) -> Result<(), BuilderError>;
+
+    /// Allocates the interfaces of the function and adds them to `device`.
+    ///
+    /// Errors in the function settings may also be deferred to `DeviceBuilder::try_build`.
+    fn add_to_device(&self, device: &mut DeviceBuilder) -> Result<(), BuilderError>;
+
+    /// Returns control requests answered by the generated code, in addition to those added by
+    /// `add_to_device`. Called after `add_to_device`.
+    ///
+    /// Default: no requests
+    fn control_requests(&self) -> Vec<ControlRequest> {
+        Vec::new()
+    }
+
+    /// Returns code added to the `generated` module, e.g. a class that handles the requests of
+    /// the function which need more than a static response. Called after `add_to_device`.
+    ///
+    /// Default: no code
+    fn generated_code(&self) -> Option<String> {
+        None
+    }
+}
+
+/// Allocates the endpoints of all `functions`, then adds them to `device` in order.
+pub fn try_add_functions(
+    device: &mut DeviceBuilder,
+    allocator: &mut DeviceAllocator,
+    functions: &mut [&mut dyn UsbFunction],
+) -> Result<(), BuilderError> {
+    for function in functions.iter_mut() {
+        function.allocate_endpoints(allocator)?;
+    }
+    for function in functions.iter() {
+        function.add_to_device(device)?;
+        for request in function.control_requests() {
+            device.add_control_request(request);
+        }
+        if let Some(code) = function.generated_code() {
+            device.add_generated_code(code);
+        }
+    }
+    Ok(())
+}
+
+/// Allocates the endpoints of all `functions`, then adds them to `device` in order.
+///
+/// # Panics
+///
+/// Panics if an endpoint cannot be allocated. See `try_add_functions` for a non-panicking version.
+pub fn add_functions(
+    device: &mut DeviceBuilder,
+    allocator: &mut DeviceAllocator,
+    functions: &mut [&mut dyn UsbFunction],
+) {
+    try_add_functions(device, allocator, functions).unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// Endpoint settings used by `FunctionEndpoints::allocate`: direction, type, max packet size and
+/// interval.
+pub type EndpointSpec = (UsbDirection, EndpointType, u16, u8);
+
+/// The endpoints of a function, allocated by `UsbFunction::allocate_endpoints` and used by
+/// `UsbFunction::add_to_device`.
+#[derive(Clone, Debug, Default)]
+pub struct FunctionEndpoints {
+    endpoints: Vec<UsbEndpointDescriptor>,
+}
+
+impl FunctionEndpoints {
+    /// Allocates an endpoint for each of `specs`, replacing the previously allocated endpoints.
+    pub fn allocate(
+        &mut self,
+        allocator: &mut DeviceAllocator,
+        specs: &[EndpointSpec],
+    ) -> Result<(), BuilderError> {
+        let mut endpoints = Vec::new();
+        for &(direction, ep_type, max_packet_size, interval) in specs {
+            endpoints.push(allocate_endpoint(
+                allocator,
+                direction,
+                ep_type,
+                max_packet_size,
+                interval,
+            )?);
+        }
+        self.endpoints = endpoints;
+        Ok(())
+    }
+
+    /// Returns the `count` allocated endpoints, or an error naming the first interface of the
+    /// function about to be added to `device` if they have not been allocated.
+    pub fn get(
+        &self,
+        device: &DeviceBuilder,
+        count: usize,
+    ) -> Result<&[UsbEndpointDescriptor], BuilderError> {
+        if self.endpoints.len() == count {
+            Ok(&self.endpoints)
+        } else {
+            Err(BuilderError::UnallocatedFunctionEndpoints {
+                first_interface: device.next_interface_number(),
+            })
+        }
+    }
+}
+
+/// Allocates an endpoint for a function.
+fn allocate_endpoint(
+    allocator: &mut DeviceAllocator,
+    direction: UsbDirection,
+    ep_type: EndpointType,
+    max_packet_size: u16,
+    interval: u8,
+) -> Result<UsbEndpointDescriptor, BuilderError> {
+    let endpoint = EndpointBuilder::new()
+        .direction(direction)
+        .ep_type(ep_type)
+        .max_packet_size(max_packet_size)
+        .interval(interval)
+        .try_allocate(allocator)?;
+    Ok(endpoint.descriptor().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::UsbVidPid;
+    use crate::cdc::{AcmFunction, AcmOptions};
+    use crate::control::{ControlResponse, Recipient, RequestType};
+    use crate::endpoint::DeviceBuilderEx;
+    use crate::msc::{MscFunction, MSC_SUBCLASS_SCSI};
+
+    fn device(alloc: &mut DeviceAllocator) -> DeviceBuilder {
+        DeviceBuilder::new(UsbVidPid(0x1209, 0x0001))
+            .try_allocate(alloc)
+            .unwrap()
+    }
+
+    #[test]
+    fn composite_device() {
+        let mut alloc = DeviceAllocator::new();
+        let mut device = device(&mut alloc);
+        let mut acm = AcmFunction::new(AcmOptions::new());
+        let mut msc = MscFunction::new(MSC_SUBCLASS_SCSI, 1);
+        add_functions(&mut device, &mut alloc, &mut [&mut acm, &mut msc]);
+        let config = device.build();
+
+        let addresses: Vec<u8> = config
+            .endpoints
+            .iter()
+            .map(|ep| u8::from(ep.address))
+            .collect();
+        assert_eq!(addresses, [0x00, 0x80, 0x81, 0x82, 0x02, 0x83, 0x03]);
+        // ACM communication and data interfaces, then the mass storage interface
+        assert_eq!(config.configuration_descriptors[0][4], 3);
+        assert_eq!(config.control_requests.len(), 2);
+    }
+
+    /// A vendor function as it could be defined outside this crate.
+    #[derive(Default)]
+    struct VendorFunction {
+        endpoints: FunctionEndpoints,
+    }
+
+    impl UsbFunction for VendorFunction {
+        fn allocate_endpoints(
+            &mut self,
+            allocator: &mut DeviceAllocator,
+        ) -> Result<(), BuilderError> {
+            self.endpoints
+                .allocate(allocator, &[(UsbDirection::In, EndpointType::Bulk, 64, 0)])
+        }
+
+        fn add_to_device(&self, device: &mut DeviceBuilder) -> Result<(), BuilderError> {
+            let endpoints = self.endpoints.get(device, 1)?;
+            device
+                .alloc_interface()
+                .interface_class(0xff)
+                .endpoint(endpoints[0].clone())
+                .save(device);
+            Ok(())
+        }
+
+        fn control_requests(&self) -> Vec<ControlRequest> {
+            vec![ControlRequest::new(
+                RequestType::Vendor,
+                Recipient::Device,
+                0x01,
+                ControlResponse::Data(vec![0x42]),
+            )]
+        }
+
+        fn generated_code(&self) -> Option<String> {
+            Some("pub struct VendorClass;".to_string())
+        }
+    }
+
+    #[test]
+    fn function_hooks() {
+        let mut alloc = DeviceAllocator::new();
+        let mut device = device(&mut alloc);
+        let mut vendor = VendorFunction::default();
+        add_functions(&mut device, &mut alloc, &mut [&mut vendor]);
+        let config = device.build();
+
+        assert_eq!(config.control_requests.len(), 1);
+        assert_eq!(config.control_requests[0].request, 0x01);
+        assert_eq!(config.generated_code, ["pub struct VendorClass;"]);
+    }
+
+    #[test]
+    fn unallocated_endpoints() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let acm = AcmFunction::new(AcmOptions::new());
+        assert_eq!(
+            acm.add_to_device(&mut device),
+            Err(BuilderError::UnallocatedFunctionEndpoints { first_interface: 0 })
+        );
+    }
+
+    #[test]
+    fn allocation_error() {
+        let mut alloc = DeviceAllocator::new();
+        let mut device = device(&mut alloc);
+        let mut acm = AcmFunction::new(AcmOptions::new());
+        let mut msc = MscFunction::new(MSC_SUBCLASS_SCSI, 1).max_packet_size(2048);
+        assert_eq!(
+            try_add_functions(&mut device, &mut alloc, &mut [&mut acm, &mut msc]),
+            Err(BuilderError::InvalidMaxPacketSize {
+                address: 0x03,
+                max_packet_size: 2048,
+            })
+        );
+    }
+}
@@ -1,4 +1,32 @@
+//! Generates the `generated` module of a firmware crate from a `DeviceConfig`.
+//!
+//! The generated code is not written against the `usb-device` release used by this crate, but
+//! against the `usb-device` fork used together with `stm32f103xx-usb`, where the descriptors are
+//! provided by the application instead of being built by `UsbDeviceBuilder`. The firmware crate
+//! has to depend on a revision of the fork whose `usb_device::device` module has these traits:
+//!
+//! ```ignore
+//! pub trait DescriptorProvider<B: UsbBus> {
+//!     fn get_device_descriptor(buffer: &mut [u8]) -> Result<usize>;
+//!     fn get_configuration_descriptor(index: u8, buffer: &mut [u8]) -> Result<usize>;
+//!     fn get_bos_descriptor(buffer: &mut [u8]) -> Result<usize>;
+//!     fn get_string_descriptor(lang_id: u16, index: u8, xfer: ControlIn<B>) -> Result<()>;
+//!     fn get_ep0_max_packet_size() -> u8;
+//! }
+//!
+//! // Implemented by the application for `UsbString::Custom` strings, empty otherwise
+//! pub trait CustomStringDescriptorProvider<B: UsbBus> {
+//!     fn get_custom_string_descriptor(id: usize, xfer: ControlIn<B>) -> Result<()> { .. }
+//! }
+//! ```
+//!
+//! The `index` argument of `get_configuration_descriptor` (the zero-based configuration index of
+//! GET_DESCRIPTOR) and `get_bos_descriptor` are required for devices with several configurations
+//! and for BOS descriptors; older revisions of the fork without them cannot build the generated
+//! code. Endpoints are configured through `stm32f103xx_usb::endpoint::EndpointConfiguration`.
+
 use crate::builder::DeviceConfig;
+use crate::control::{ControlRequest, ControlResponse};
 use std::{fmt, fs};
 use std::io::Write;
 use std::fmt::Display;
@@ -22,55 +50,70 @@ impl TargetDeviceConfig {
     }
 
     fn write_descriptor_information(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "{}", r#"
+        f.write_str(r#"
 pub struct GeneratedDevice;
 
 use ::usb_device::{Result, UsbError, bus::UsbBus, device::{DescriptorProvider, CustomStringDescriptorProvider}, class::ControlIn};
+
+fn copy_descriptor(buffer: &mut [u8], descriptor: &[u8]) -> Result<usize> {
+    let n = descriptor.len();
+    if buffer.len() < n {
+        Err(UsbError::BufferOverflow)
+    } else {
+        buffer[..n].copy_from_slice(descriptor);
+        Ok(n)
+    }
+}
+
 impl<B: UsbBus> DescriptorProvider<B> for GeneratedDevice {
     fn get_device_descriptor(buffer: &mut [u8]) -> Result<usize> {
-        let n = DEVICE_DESCRIPTOR.len();
-        if buffer.len() < n {
-            Err(UsbError::BufferOverflow)
-        } else {
-            buffer[..n].copy_from_slice(&DEVICE_DESCRIPTOR);
-            Ok(n)
+        copy_descriptor(buffer, &DEVICE_DESCRIPTOR)
+    }
+
+    fn get_configuration_descriptor(index: u8, buffer: &mut [u8]) -> Result<usize> {
+        match CONFIGURATION_DESCRIPTORS.get(index as usize) {
+            Some(descriptor) => copy_descriptor(buffer, descriptor),
+            None => Err(UsbError::InvalidState),
         }
     }
 
-    fn get_configuration_descriptor(buffer: &mut [u8]) -> Result<usize> {
-        let n = CONFIGURATION_DESCRIPTOR.len();
-        if buffer.len() < n {
-            Err(UsbError::BufferOverflow)
+    fn get_bos_descriptor(buffer: &mut [u8]) -> Result<usize> {
+"#)?;
+        if self.usb_config.bos_descriptor.is_some() {
+            writeln!(f, "copy_descriptor(buffer, &BOS_DESCRIPTOR)")?;
         } else {
-            buffer[..n].copy_from_slice(&CONFIGURATION_DESCRIPTOR);
-            Ok(n)
+            writeln!(f, "let _ = buffer; Err(UsbError::Unsupported)")?;
         }
-    }
+        f.write_str(r#"    }
 
-    fn get_string_descriptor(_lang_id: u16, index: u8, xfer: ControlIn<B>) -> Result<()> {
-        match index {"#
-        )?;
-        for (id, _descriptor) in &self.usb_config.string_descriptors {
+    fn get_string_descriptor(lang_id: u16, index: u8, xfer: ControlIn<B>) -> Result<()> {
+        match (lang_id, index) {
+"#)?;
+        for (lang_id, id) in self.usb_config.translated_string_descriptors.keys() {
+            let name = format!("STRING_DESCRIPTOR_{}_{:04x}", id, lang_id);
+            writeln!(f, "(0x{:04x}, {}) => xfer.accept_with(&{}),", lang_id, id, name)?;
+        }
+        for id in self.usb_config.string_descriptors.keys() {
             let name = format!("STRING_DESCRIPTOR_{}", id);
-            writeln!(f, "{} => xfer.accept_with(&{}),", id, name)?;
+            writeln!(f, "(_, {}) => xfer.accept_with(&{}),", id, name)?;
         }
         for (id, index) in &self.usb_config.custom_strings {
-            writeln!(f, "{} => <Self as CustomStringDescriptorProvider<B>>::get_custom_string_descriptor({}, xfer),", id, index)?;
+            writeln!(f, "(_, {}) => <Self as CustomStringDescriptorProvider<B>>::get_custom_string_descriptor({}, xfer),", id, index)?;
         }
 
-        writeln!(f, "{}", r#"
+        f.write_str(r#"
             _ => xfer.reject(),
         }
     }
 
     fn get_ep0_max_packet_size() -> u8 {
-"#
-        )?;
+
+"#)?;
         writeln!(f, "{}", self.usb_config.ep0_max_packet_size)?;
-        writeln!(f, "{}", r#"
+        f.write_str(r#"
     }
-}"#
-        )?;
+}
+"#)?;
 
         if self.usb_config.custom_strings.is_empty() {
             writeln!(f, "impl<B: UsbBus> CustomStringDescriptorProvider<B> for GeneratedDevice {{}}")?;
@@ -78,13 +121,108 @@ impl<B: UsbBus> DescriptorProvider<B> for GeneratedDevice {
         Ok(())
     }
 
+    fn write_control_requests(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let requests = &self.usb_config.control_requests;
+        let has_data = requests.iter().any(|r| matches!(r.response, ControlResponse::Data(_)));
+        let accepted = requests.iter().filter(|r| matches!(r.response, ControlResponse::Accept)).count();
+
+        writeln!(f)?;
+        if accepted > 0 {
+            writeln!(f, "use ::usb_device::class::{{ControlOut, UsbClass}};")?;
+        } else {
+            writeln!(f, "use ::usb_device::class::UsbClass;")?;
+        }
+        if requests.is_empty() {
+            writeln!(f, "use ::usb_device::control::Request;")?;
+        } else {
+            writeln!(f, "use ::usb_device::control::{{Recipient, Request, RequestType}};")?;
+        }
+
+        // Each accepted request has its own slot, so that requests of different kinds don't
+        // overwrite each other before the application takes them
+        f.write_str(r#"
+/// Answers the control requests described by the device builder. Put it first in the list of
+/// classes passed to `UsbDevice::poll`.
+pub struct GeneratedRequests {
+"#)?;
+        writeln!(f, "    requests: [Option<Request>; {}],", accepted)?;
+        f.write_str(r#"}
+
+impl Default for GeneratedRequests {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GeneratedRequests {
+    pub fn new() -> Self {
+"#)?;
+        writeln!(f, "        Self {{ requests: [None; {}] }}", accepted)?;
+        f.write_str(r#"    }
+
+    /// Returns an accepted control OUT request that the application has to act on, e.g. a
+    /// class-specific reset. Each accepted request is kept in its own slot until it is taken, so
+    /// it is only overwritten by a later request matching the same pattern.
+    pub fn take_request(&mut self) -> Option<Request> {
+        self.requests.iter_mut().find_map(Option::take)
+    }
+}
+
+impl<B: UsbBus> UsbClass<B> for GeneratedRequests {
+"#)?;
+        if has_data {
+            f.write_str(r#"    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+        match (req.request_type, req.recipient, req.request, req.value, req.index) {
+"#)?;
+            for (i, request) in requests.iter().enumerate() {
+                if let ControlResponse::Data(_) = request.response {
+                    self.write_request_pattern(f, request)?;
+                    writeln!(f, "{{ xfer.accept_with_static(&CONTROL_RESPONSE_{}).ok(); }}", i)?;
+                }
+            }
+            f.write_str(r#"
+            _ => {}
+        }
+    }
+"#)?;
+        }
+        if accepted > 0 {
+            f.write_str(r#"
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+        match (req.request_type, req.recipient, req.request, req.value, req.index) {
+"#)?;
+            let accepted_requests = requests.iter().filter(|r| matches!(r.response, ControlResponse::Accept));
+            for (slot, request) in accepted_requests.enumerate() {
+                self.write_request_pattern(f, request)?;
+                writeln!(f, "{{ self.requests[{}] = Some(req); xfer.accept().ok(); }}", slot)?;
+            }
+            f.write_str(r#"
+            _ => {}
+        }
+    }
+"#)?;
+        }
+        f.write_str("}
+")?;
+        Ok(())
+    }
+
+    fn write_request_pattern(&self, f: &mut fmt::Formatter, request: &ControlRequest) -> fmt::Result {
+        let value = request.value.map_or("_".to_string(), |v| format!("0x{:04x}", v));
+        let index = request.index.map_or("_".to_string(), |v| format!("0x{:04x}", v));
+        write!(f, "(RequestType::{:?}, Recipient::{:?}, 0x{:02x}, {}, {}) => ",
+               request.request_type, request.recipient, request.request, value, index)
+    }
+
     fn write_endpoint_configuration(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        writeln!(f, "{}", r#"
+        f.write_str(r#"
 use ::stm32f103xx_usb::endpoint::{Endpoint, EndpointConfiguration};
 use ::usb_device::endpoint::EndpointType;
 impl EndpointConfiguration for GeneratedDevice {
-    fn configure_endpoints(endpoints: &mut [Endpoint]) {"#
-        )?;
+    fn configure_endpoints(endpoints: &mut [Endpoint]) {
+"#)?;
 
         for (i, ep) in self.device_config.endpoints.iter().enumerate() {
             let prefix = format!("endpoints[{}]", i);
@@ -111,10 +249,10 @@ impl EndpointConfiguration for GeneratedDevice {
             writeln!(f)?;
         }
 
-        writeln!(f, "{}", r#"
+        f.write_str(r#"
     }
-}"#
-        )?;
+}
+"#)?;
         Ok(())
     }
 }
@@ -123,12 +261,37 @@ impl Display for TargetDeviceConfig {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         writeln!(f, "mod generated {{")?;
         self.write_blob(f, "DEVICE_DESCRIPTOR", &self.usb_config.device_descriptor)?;
-        self.write_blob(f, "CONFIGURATION_DESCRIPTOR", &self.usb_config.configuration_descriptor)?;
+        for (index, descriptor) in self.usb_config.configuration_descriptors.iter().enumerate() {
+            let name = format!("CONFIGURATION_DESCRIPTOR_{}", index);
+            self.write_blob(f, &name, descriptor)?;
+        }
+        write!(f, "const CONFIGURATION_DESCRIPTORS: [&[u8]; {}] = [", self.usb_config.configuration_descriptors.len())?;
+        for index in 0..self.usb_config.configuration_descriptors.len() {
+            write!(f, "&CONFIGURATION_DESCRIPTOR_{}, ", index)?;
+        }
+        writeln!(f, "];")?;
+        if let Some(descriptor) = &self.usb_config.bos_descriptor {
+            self.write_blob(f, "BOS_DESCRIPTOR", descriptor)?;
+        }
         for (id, descriptor) in &self.usb_config.string_descriptors {
             let name = format!("STRING_DESCRIPTOR_{}", id);
-            self.write_blob(f, &name, &descriptor)?;
+            self.write_blob(f, &name, descriptor)?;
+        }
+        for ((lang_id, id), descriptor) in &self.usb_config.translated_string_descriptors {
+            let name = format!("STRING_DESCRIPTOR_{}_{:04x}", id, lang_id);
+            self.write_blob(f, &name, descriptor)?;
+        }
+        for (i, request) in self.usb_config.control_requests.iter().enumerate() {
+            if let ControlResponse::Data(data) = &request.response {
+                self.write_blob(f, &format!("CONTROL_RESPONSE_{}", i), data)?;
+            }
         }
         self.write_descriptor_information(f)?;
+        self.write_control_requests(f)?;
+        for code in &self.usb_config.generated_code {
+            writeln!(f)?;
+            writeln!(f, "{}", code)?;
+        }
         self.write_endpoint_configuration(f)?;
         writeln!(f, "}}")?; // mod generated
         Ok(())
@@ -144,3 +307,77 @@ pub fn generate_file(filename: impl AsRef<Path>, usb_config: DeviceConfig, devic
     write!(file, "{}", config)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{DeviceBuilder, UsbVidPid};
+    use crate::control::{Recipient, RequestType};
+    use crate::endpoint::{DeviceAllocator, DeviceBuilderEx};
+
+    fn generate(requests: Vec<ControlRequest>) -> String {
+        let mut alloc = DeviceAllocator::new();
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001)).try_allocate(&mut alloc).unwrap();
+        device.alloc_interface().interface_class(0xff).save(&mut device);
+        for request in requests {
+            device.add_control_request(request);
+        }
+        let config = TargetDeviceConfig {
+            usb_config: device.build(),
+            device_config: alloc.into(),
+        };
+        config.to_string()
+    }
+
+    #[test]
+    fn control_requests() {
+        let generated = generate(vec![
+            ControlRequest::new(RequestType::Class, Recipient::Interface, 0x0a, ControlResponse::Accept).index(0),
+            ControlRequest::new(RequestType::Vendor, Recipient::Device, 0x20, ControlResponse::Data(vec![0x12, 0x34])).value(1),
+            ControlRequest::new(RequestType::Class, Recipient::Interface, 0xff, ControlResponse::Accept),
+        ]);
+
+        // Data responses are named after the request, accepted requests are numbered separately
+        assert!(generated.contains("const CONTROL_RESPONSE_1: [u8; 2] = [0x12, 0x34, ];"));
+        assert!(!generated.contains("CONTROL_RESPONSE_0"));
+        assert!(!generated.contains("CONTROL_RESPONSE_2"));
+        assert!(generated.contains("use ::usb_device::class::{ControlOut, UsbClass};"));
+        assert!(generated.contains("    requests: [Option<Request>; 2],"));
+        assert!(generated.contains("        Self { requests: [None; 2] }"));
+        assert!(generated.contains(
+            "(RequestType::Vendor, Recipient::Device, 0x20, 0x0001, _) => { xfer.accept_with_static(&CONTROL_RESPONSE_1).ok(); }"
+        ));
+        assert!(generated.contains(
+            "(RequestType::Class, Recipient::Interface, 0x0a, _, 0x0000) => { self.requests[0] = Some(req); xfer.accept().ok(); }"
+        ));
+        assert!(generated.contains(
+            "(RequestType::Class, Recipient::Interface, 0xff, _, _) => { self.requests[1] = Some(req); xfer.accept().ok(); }"
+        ));
+    }
+
+    #[test]
+    fn data_requests_only() {
+        let generated = generate(vec![
+            ControlRequest::new(RequestType::Vendor, Recipient::Device, 0x20, ControlResponse::Data(vec![0x12])),
+        ]);
+
+        assert!(generated.contains("use ::usb_device::class::UsbClass;"));
+        assert!(generated.contains("    requests: [Option<Request>; 0],"));
+        assert!(generated.contains("fn control_in(&mut self, xfer: ControlIn<B>)"));
+        assert!(!generated.contains("fn control_out"));
+    }
+
+    #[test]
+    fn generated_code() {
+        let mut alloc = DeviceAllocator::new();
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001)).try_allocate(&mut alloc).unwrap();
+        device.alloc_interface().interface_class(0xff).save(&mut device);
+        device.add_generated_code("pub struct VendorClass;".to_string());
+        let config = TargetDeviceConfig {
+            usb_config: device.build(),
+            device_config: alloc.into(),
+        };
+
+        assert!(config.to_string().contains("\npub struct VendorClass;\n"));
+    }
+}
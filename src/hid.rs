@@ -0,0 +1,576 @@
+use crate::builder::DeviceBuilder;
+use crate::control::{ControlRequest, ControlResponse, Recipient, RequestType};
+use crate::endpoint::DeviceAllocator;
+use crate::error::BuilderError;
+use crate::function::{FunctionEndpoints, UsbFunction};
+use crate::EndpointInfo;
+use usb_device::endpoint::EndpointType;
+use usb_device::UsbDirection;
+
+pub const USB_CLASS_HID: u8 = 0x03;
+pub const HID_SUBCLASS_NONE: u8 = 0x00;
+pub const HID_SUBCLASS_BOOT: u8 = 0x01;
+pub const HID_PROTOCOL_NONE: u8 = 0x00;
+pub const HID_PROTOCOL_KEYBOARD: u8 = 0x01;
+pub const HID_PROTOCOL_MOUSE: u8 = 0x02;
+
+const HID_DESCRIPTOR_TYPE_HID: u8 = 0x21;
+const HID_DESCRIPTOR_TYPE_REPORT: u8 = 0x22;
+const REQUEST_GET_DESCRIPTOR: u8 = 0x06;
+
+/// Usage page identifiers from the HID Usage Tables.
+pub mod usage_page {
+    pub const GENERIC_DESKTOP: u16 = 0x01;
+    pub const SIMULATION: u16 = 0x02;
+    pub const KEYBOARD: u16 = 0x07;
+    pub const LED: u16 = 0x08;
+    pub const BUTTON: u16 = 0x09;
+    pub const CONSUMER: u16 = 0x0c;
+    pub const DIGITIZER: u16 = 0x0d;
+    pub const VENDOR_DEFINED: u16 = 0xff00;
+}
+
+/// Data flags of the Input, Output and Feature main items. Cleared bits mean Data, Array,
+/// Absolute, No Wrap, Linear, Preferred State, No Null Position, Non Volatile and Bit Field.
+pub mod main_item {
+    pub const CONSTANT: u32 = 1 << 0;
+    pub const VARIABLE: u32 = 1 << 1;
+    pub const RELATIVE: u32 = 1 << 2;
+    pub const WRAP: u32 = 1 << 3;
+    pub const NON_LINEAR: u32 = 1 << 4;
+    pub const NO_PREFERRED: u32 = 1 << 5;
+    pub const NULL_STATE: u32 = 1 << 6;
+    pub const VOLATILE: u32 = 1 << 7;
+    pub const BUFFERED_BYTES: u32 = 1 << 8;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Collection {
+    Physical = 0x00,
+    Application = 0x01,
+    Logical = 0x02,
+    Report = 0x03,
+    NamedArray = 0x04,
+    UsageSwitch = 0x05,
+    UsageModifier = 0x06,
+}
+
+#[derive(Clone, Copy)]
+enum ItemType {
+    Main = 0,
+    Global = 1,
+    Local = 2,
+}
+
+#[derive(Clone, Copy, Default)]
+struct GlobalState {
+    report_size: bool,
+    report_count: bool,
+}
+
+/// Builds a HID report descriptor item by item.
+///
+/// Values are encoded with the shortest item size that holds them. `build` checks that
+/// collections and Push/Pop items are balanced and that Report Size and Report Count are set
+/// before the first Input, Output or Feature item.
+#[derive(Clone, Default)]
+pub struct ReportDescriptorBuilder {
+    data: Vec<u8>,
+    depth: usize,
+    global: GlobalState,
+    global_stack: Vec<GlobalState>,
+    error: Option<&'static str>,
+}
+
+impl ReportDescriptorBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn item(mut self, item_type: ItemType, tag: u8, data: &[u8]) -> Self {
+        let size_code = match data.len() {
+            0 => 0,
+            1 => 1,
+            2 => 2,
+            _ => 3,
+        };
+        self.data
+            .push((tag << 4) | ((item_type as u8) << 2) | size_code);
+        self.data.extend_from_slice(data);
+        self
+    }
+
+    fn unsigned(self, item_type: ItemType, tag: u8, value: u32) -> Self {
+        let bytes = value.to_le_bytes();
+        let size = match value {
+            0..=0xff => 1,
+            0x100..=0xffff => 2,
+            _ => 4,
+        };
+        self.item(item_type, tag, &bytes[..size])
+    }
+
+    fn signed(self, item_type: ItemType, tag: u8, value: i32) -> Self {
+        let bytes = value.to_le_bytes();
+        let size = match value {
+            -0x80..=0x7f => 1,
+            -0x8000..=0x7fff => 2,
+            _ => 4,
+        };
+        self.item(item_type, tag, &bytes[..size])
+    }
+
+    fn fail(mut self, error: &'static str) -> Self {
+        self.error.get_or_insert(error);
+        self
+    }
+
+    fn main_data(self, tag: u8, flags: u32) -> Self {
+        if !self.global.report_size || !self.global.report_count {
+            return self.fail("Report Size and Report Count must be set before a main item");
+        }
+        self.unsigned(ItemType::Main, tag, flags)
+    }
+
+    /// Adds an Input item with the given `main_item` flags.
+    pub fn input(self, flags: u32) -> Self {
+        self.main_data(0x8, flags)
+    }
+
+    /// Adds an Output item with the given `main_item` flags.
+    pub fn output(self, flags: u32) -> Self {
+        self.main_data(0x9, flags)
+    }
+
+    /// Adds a Feature item with the given `main_item` flags.
+    pub fn feature(self, flags: u32) -> Self {
+        self.main_data(0xb, flags)
+    }
+
+    /// Opens a collection. Each collection must be closed with `end_collection`.
+    pub fn collection(mut self, collection: Collection) -> Self {
+        self.depth += 1;
+        self.unsigned(ItemType::Main, 0xa, collection as u32)
+    }
+
+    pub fn end_collection(mut self) -> Self {
+        if self.depth == 0 {
+            return self.fail("End Collection without a matching Collection");
+        }
+        self.depth -= 1;
+        self.item(ItemType::Main, 0xc, &[])
+    }
+
+    pub fn usage_page(self, usage_page: u16) -> Self {
+        self.unsigned(ItemType::Global, 0x0, usage_page.into())
+    }
+
+    pub fn logical_minimum(self, value: i32) -> Self {
+        self.signed(ItemType::Global, 0x1, value)
+    }
+
+    pub fn logical_maximum(self, value: i32) -> Self {
+        self.signed(ItemType::Global, 0x2, value)
+    }
+
+    pub fn physical_minimum(self, value: i32) -> Self {
+        self.signed(ItemType::Global, 0x3, value)
+    }
+
+    pub fn physical_maximum(self, value: i32) -> Self {
+        self.signed(ItemType::Global, 0x4, value)
+    }
+
+    pub fn unit_exponent(self, exponent: i8) -> Self {
+        self.signed(ItemType::Global, 0x5, exponent.into())
+    }
+
+    pub fn unit(self, unit: u32) -> Self {
+        self.unsigned(ItemType::Global, 0x6, unit)
+    }
+
+    /// Sets the size of a report field in bits.
+    pub fn report_size(mut self, bits: u32) -> Self {
+        self.global.report_size = true;
+        self.unsigned(ItemType::Global, 0x7, bits)
+    }
+
+    pub fn report_id(self, report_id: u8) -> Self {
+        if report_id == 0 {
+            return self.fail("Report ID 0 is reserved");
+        }
+        self.unsigned(ItemType::Global, 0x8, report_id.into())
+    }
+
+    /// Sets the number of report fields.
+    pub fn report_count(mut self, count: u32) -> Self {
+        self.global.report_count = true;
+        self.unsigned(ItemType::Global, 0x9, count)
+    }
+
+    /// Saves the global item state, to be restored with `pop`.
+    pub fn push(mut self) -> Self {
+        self.global_stack.push(self.global);
+        self.item(ItemType::Global, 0xa, &[])
+    }
+
+    pub fn pop(mut self) -> Self {
+        match self.global_stack.pop() {
+            Some(global) => self.global = global,
+            None => return self.fail("Pop without a matching Push"),
+        }
+        self.item(ItemType::Global, 0xb, &[])
+    }
+
+    /// Adds a usage. Values above `0xffff` are extended usages that include the usage page.
+    pub fn usage(self, usage: u32) -> Self {
+        self.unsigned(ItemType::Local, 0x0, usage)
+    }
+
+    pub fn usage_minimum(self, usage: u32) -> Self {
+        self.unsigned(ItemType::Local, 0x1, usage)
+    }
+
+    pub fn usage_maximum(self, usage: u32) -> Self {
+        self.unsigned(ItemType::Local, 0x2, usage)
+    }
+
+    /// Returns the encoded report descriptor.
+    pub fn build(self) -> Result<Vec<u8>, BuilderError> {
+        if let Some(error) = self.error {
+            return Err(BuilderError::InvalidReportDescriptor(error));
+        }
+        if self.depth != 0 {
+            return Err(BuilderError::InvalidReportDescriptor(
+                "Collection without a matching End Collection",
+            ));
+        }
+        if !self.global_stack.is_empty() {
+            return Err(BuilderError::InvalidReportDescriptor(
+                "Push without a matching Pop",
+            ));
+        }
+        Ok(self.data)
+    }
+}
+
+/// Settings of a HID interface.
+#[derive(Clone, Debug)]
+pub struct HidOptions {
+    pub sub_class: u8,
+    pub protocol: u8,
+    pub hid_release: u16,
+    pub country_code: u8,
+    pub interface_string: Option<String>,
+    pub report_descriptor: Vec<u8>,
+}
+
+impl HidOptions {
+    pub fn new(report_descriptor: Vec<u8>) -> Self {
+        Self {
+            sub_class: HID_SUBCLASS_NONE,
+            protocol: HID_PROTOCOL_NONE,
+            hid_release: 0x0111,
+            country_code: 0,
+            interface_string: None,
+            report_descriptor,
+        }
+    }
+
+    /// Marks the interface as a boot interface with the given protocol, e.g.
+    /// `HID_PROTOCOL_KEYBOARD`.
+    ///
+    /// Default: (not a boot interface)
+    pub fn boot_protocol(mut self, protocol: u8) -> Self {
+        self.sub_class = HID_SUBCLASS_BOOT;
+        self.protocol = protocol;
+        self
+    }
+
+    /// Sets the HID specification release number in BCD.
+    ///
+    /// Default: `0x0111`
+    pub fn hid_release(mut self, hid_release: u16) -> Self {
+        self.hid_release = hid_release;
+        self
+    }
+
+    /// Sets the country code of localized hardware, e.g. keyboard layouts.
+    ///
+    /// Default: `0` (not localized)
+    pub fn country_code(mut self, country_code: u8) -> Self {
+        self.country_code = country_code;
+        self
+    }
+
+    /// Sets the interface string descriptor.
+    ///
+    /// Default: (none)
+    pub fn interface_string(mut self, interface_string: impl Into<String>) -> Self {
+        self.interface_string = Some(interface_string.into());
+        self
+    }
+}
+
+/// Adds a HID interface with an interrupt IN endpoint and an optional interrupt OUT endpoint.
+///
+/// The generated code answers GET_DESCRIPTOR requests for the HID and report descriptors of the
+/// interface.
+pub fn create_hid_function(
+    device: &mut DeviceBuilder,
+    options: &HidOptions,
+    in_ep: impl EndpointInfo,
+    out_ep: Option<&dyn EndpointInfo>,
+) {
+    device.check_endpoint(&in_ep, EndpointType::Interrupt, UsbDirection::In);
+    if let Some(out_ep) = out_ep {
+        device.check_endpoint(out_ep, EndpointType::Interrupt, UsbDirection::Out);
+    }
+
+    let report_length = options.report_descriptor.len();
+    let hid_descriptor = [
+        options.hid_release as u8,
+        (options.hid_release >> 8) as u8,
+        options.country_code,
+        1, // bNumDescriptors
+        HID_DESCRIPTOR_TYPE_REPORT,
+        report_length as u8,
+        (report_length >> 8) as u8,
+    ];
+
+    let mut interface = device
+        .alloc_interface()
+        .interface_class(USB_CLASS_HID)
+        .interface_sub_class(options.sub_class)
+        .interface_protocol(options.protocol)
+        .descriptor(HID_DESCRIPTOR_TYPE_HID, &hid_descriptor)
+        .endpoint(in_ep.descriptor().clone());
+    if let Some(out_ep) = out_ep {
+        interface = interface.endpoint(out_ep.descriptor().clone());
+    }
+    if let Some(interface_string) = &options.interface_string {
+        interface = interface.interface_string(interface_string.as_str());
+    }
+    let interface_number = interface.descriptor.interface_number;
+    interface.save(device);
+
+    let mut descriptor = vec![hid_descriptor.len() as u8 + 2, HID_DESCRIPTOR_TYPE_HID];
+    descriptor.extend_from_slice(&hid_descriptor);
+    let descriptors = vec![
+        (HID_DESCRIPTOR_TYPE_HID, descriptor),
+        (
+            HID_DESCRIPTOR_TYPE_REPORT,
+            options.report_descriptor.clone(),
+        ),
+    ];
+    for (descriptor_type, data) in descriptors {
+        device.add_control_request(
+            ControlRequest::new(
+                RequestType::Standard,
+                Recipient::Interface,
+                REQUEST_GET_DESCRIPTOR,
+                ControlResponse::Data(data),
+            )
+            .value(u16::from(descriptor_type) << 8)
+            .index(interface_number.into()),
+        );
+    }
+}
+
+/// HID function. The interrupt OUT endpoint is only allocated if `out_endpoint` is set.
+pub struct HidFunction {
+    pub options: HidOptions,
+    pub max_packet_size: u16,
+    pub interval: u8,
+    pub out_endpoint: bool,
+    endpoints: FunctionEndpoints,
+}
+
+impl HidFunction {
+    pub fn new(options: HidOptions) -> Self {
+        Self {
+            options,
+            max_packet_size: 8,
+            interval: 10,
+            out_endpoint: false,
+            endpoints: FunctionEndpoints::default(),
+        }
+    }
+
+    /// Sets the max packet size of the interrupt endpoints.
+    ///
+    /// Default: 8
+    pub fn max_packet_size(mut self, max_packet_size: u16) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+
+    /// Sets the polling interval of the interrupt endpoints in milliseconds.
+    ///
+    /// Default: 10
+    pub fn interval(mut self, interval: u8) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets whether the interface has an interrupt OUT endpoint.
+    ///
+    /// Default: `false`
+    pub fn out_endpoint(mut self, out_endpoint: bool) -> Self {
+        self.out_endpoint = out_endpoint;
+        self
+    }
+}
+
+impl UsbFunction for HidFunction {
+    fn allocate_endpoints(&mut self, allocator: &mut DeviceAllocator) -> Result<(), BuilderError> {
+        let mut specs = vec![(
+            UsbDirection::In,
+            EndpointType::Interrupt,
+            self.max_packet_size,
+            self.interval,
+        )];
+        if self.out_endpoint {
+            specs.push((
+                UsbDirection::Out,
+                EndpointType::Interrupt,
+                self.max_packet_size,
+                self.interval,
+            ));
+        }
+        self.endpoints.allocate(allocator, &specs)
+    }
+
+    fn add_to_device(&self, device: &mut DeviceBuilder) -> Result<(), BuilderError> {
+        let count = if self.out_endpoint { 2 } else { 1 };
+        let eps = self.endpoints.get(device, count)?;
+        let out_ep = eps.get(1).map(|ep| ep as &dyn EndpointInfo);
+        create_hid_function(device, &self.options, &eps[0], out_ep);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::UsbVidPid;
+    use crate::test_util::{endpoint, response};
+
+    /// The boot mouse report descriptor of the HID specification, appendix E.10
+    const BOOT_MOUSE_REPORT: [u8; 50] = [
+        0x05, 0x01, 0x09, 0x02, 0xa1, 0x01, 0x09, 0x01, 0xa1, 0x00, 0x05, 0x09, 0x19, 0x01, 0x29,
+        0x03, 0x15, 0x00, 0x25, 0x01, 0x95, 0x03, 0x75, 0x01, 0x81, 0x02, 0x95, 0x01, 0x75, 0x05,
+        0x81, 0x01, 0x05, 0x01, 0x09, 0x30, 0x09, 0x31, 0x15, 0x81, 0x25, 0x7f, 0x75, 0x08, 0x95,
+        0x02, 0x81, 0x06, 0xc0, 0xc0,
+    ];
+
+    fn boot_mouse_report() -> ReportDescriptorBuilder {
+        ReportDescriptorBuilder::new()
+            .usage_page(usage_page::GENERIC_DESKTOP)
+            .usage(0x02) // Mouse
+            .collection(Collection::Application)
+            .usage(0x01) // Pointer
+            .collection(Collection::Physical)
+            .usage_page(usage_page::BUTTON)
+            .usage_minimum(1)
+            .usage_maximum(3)
+            .logical_minimum(0)
+            .logical_maximum(1)
+            .report_count(3)
+            .report_size(1)
+            .input(main_item::VARIABLE)
+            .report_count(1)
+            .report_size(5)
+            .input(main_item::CONSTANT)
+            .usage_page(usage_page::GENERIC_DESKTOP)
+            .usage(0x30) // X
+            .usage(0x31) // Y
+            .logical_minimum(-127)
+            .logical_maximum(127)
+            .report_size(8)
+            .report_count(2)
+            .input(main_item::VARIABLE | main_item::RELATIVE)
+            .end_collection()
+            .end_collection()
+    }
+
+    #[test]
+    fn report_descriptor() {
+        assert_eq!(boot_mouse_report().build().unwrap(), &BOOT_MOUSE_REPORT[..]);
+        assert_eq!(
+            ReportDescriptorBuilder::new()
+                .usage_page(usage_page::VENDOR_DEFINED)
+                .logical_maximum(0x1_0000)
+                .unit_exponent(-3)
+                .build()
+                .unwrap(),
+            [0x06, 0x00, 0xff, 0x27, 0x00, 0x00, 0x01, 0x00, 0x55, 0xfd]
+        );
+    }
+
+    #[test]
+    fn invalid_report_descriptors() {
+        let error = |builder: ReportDescriptorBuilder| match builder.build() {
+            Err(BuilderError::InvalidReportDescriptor(reason)) => reason,
+            result => panic!("unexpected result: {:?}", result),
+        };
+        assert_eq!(
+            error(ReportDescriptorBuilder::new().collection(Collection::Application)),
+            "Collection without a matching End Collection"
+        );
+        assert_eq!(
+            error(ReportDescriptorBuilder::new().end_collection()),
+            "End Collection without a matching Collection"
+        );
+        assert_eq!(
+            error(ReportDescriptorBuilder::new().push()),
+            "Push without a matching Pop"
+        );
+        assert_eq!(
+            error(ReportDescriptorBuilder::new().pop()),
+            "Pop without a matching Push"
+        );
+        assert_eq!(
+            error(ReportDescriptorBuilder::new().report_id(0)),
+            "Report ID 0 is reserved"
+        );
+        assert_eq!(
+            error(ReportDescriptorBuilder::new().report_size(8).input(0)),
+            "Report Size and Report Count must be set before a main item"
+        );
+    }
+
+    #[test]
+    fn boot_mouse_interface() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let in_ep = endpoint(1, UsbDirection::In, EndpointType::Interrupt, 4, 10);
+        let options =
+            HidOptions::new(boot_mouse_report().build().unwrap()).boot_protocol(HID_PROTOCOL_MOUSE);
+        create_hid_function(&mut device, &options, in_ep, None);
+        let config = device.build();
+
+        assert_eq!(
+            config.configuration_descriptors[0],
+            [
+                0x09, 0x02, 0x22, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, // configuration
+                0x09, 0x04, 0x00, 0x00, 0x01, 0x03, 0x01, 0x02, 0x00, // interface
+                0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 0x32, 0x00, // HID
+                0x07, 0x05, 0x81, 0x03, 0x04, 0x00, 0x0a, // endpoint
+            ][..]
+        );
+
+        let get_descriptor = |value: u16| {
+            move |r: &ControlRequest| {
+                r.request == 0x06 && r.value == Some(value) && r.index == Some(0)
+            }
+        };
+        assert_eq!(
+            response(&config, get_descriptor(0x2100)),
+            [0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 0x32, 0x00]
+        );
+        assert_eq!(
+            response(&config, get_descriptor(0x2200)),
+            &BOOT_MOUSE_REPORT[..]
+        );
+    }
+}
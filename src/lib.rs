@@ -1,21 +1,38 @@
 pub use usb_device::UsbDirection;
 pub use usb_device::endpoint::{EndpointType, EndpointAddress};
+pub mod audio;
+pub mod audio2;
 pub mod builder;
+pub mod ccid;
 pub mod cdc;
+pub mod control;
+pub mod dfu;
 pub mod endpoint;
+pub mod error;
+pub mod function;
 pub mod generator;
+pub mod hid;
+pub mod midi;
+pub mod msc;
+pub mod msos;
+pub mod printer;
 pub mod usb;
+pub mod usbtmc;
+pub mod uvc;
+pub mod webusb;
+#[cfg(test)]
+mod test_util;
 
 
 pub trait EndpointInfo {
     fn descriptor(&self) -> &usb::UsbEndpointDescriptor;
 
     fn address(&self) -> EndpointAddress {
-        self.descriptor().address.into()
+        self.descriptor().address
     }
 
     fn ep_type(&self) -> EndpointType {
-        match self.descriptor().attributes {
+        match self.descriptor().attributes & 0b11 {
             0b00 => EndpointType::Control,
             0b01 => EndpointType::Isochronous,
             0b10 => EndpointType::Bulk,
@@ -34,3 +51,9 @@ impl EndpointInfo for usb::UsbEndpointDescriptor {
         self
     }
 }
+
+impl<T: EndpointInfo + ?Sized> EndpointInfo for &T {
+    fn descriptor(&self) -> &usb::UsbEndpointDescriptor {
+        (**self).descriptor()
+    }
+}
@@ -0,0 +1,392 @@
+use crate::audio::{
+    AUDIO_SUBCLASS_CONTROL, AUDIO_SUBCLASS_MIDISTREAMING, CS_ENDPOINT, CS_INTERFACE,
+    USB_CLASS_AUDIO,
+};
+use crate::builder::DeviceBuilder;
+use crate::endpoint::DeviceAllocator;
+use crate::error::BuilderError;
+use crate::function::{FunctionEndpoints, UsbFunction};
+use crate::usb::UsbEndpointDescriptor;
+use crate::EndpointInfo;
+use usb_device::endpoint::EndpointType;
+use usb_device::UsbDirection;
+
+const AC_HEADER: u8 = 0x01;
+const MS_HEADER: u8 = 0x01;
+const MS_MIDI_IN_JACK: u8 = 0x02;
+const MS_MIDI_OUT_JACK: u8 = 0x03;
+const MS_GENERAL: u8 = 0x01;
+const JACK_TYPE_EMBEDDED: u8 = 0x01;
+const JACK_TYPE_EXTERNAL: u8 = 0x02;
+
+/// Maximum number of virtual cables per endpoint, limited by the 4 bit cable number of
+/// USB-MIDI event packets
+const MAX_CABLES: u8 = 16;
+
+/// Settings of a USB MIDI function.
+///
+/// Each cable from the host is an embedded MIDI IN jack connected to the OUT endpoint, and each
+/// cable to the host is an embedded MIDI OUT jack connected to the IN endpoint. With external
+/// jacks, every embedded jack is wired to an external jack of the opposite kind, representing a
+/// physical MIDI connector.
+#[derive(Clone, Debug)]
+pub struct MidiOptions {
+    pub cables_from_host: u8,
+    pub cables_to_host: u8,
+    pub external_jacks: bool,
+}
+
+impl Default for MidiOptions {
+    fn default() -> Self {
+        Self {
+            cables_from_host: 1,
+            cables_to_host: 1,
+            external_jacks: true,
+        }
+    }
+}
+
+impl MidiOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the number of virtual cables carrying MIDI data from the host to the device.
+    ///
+    /// Default: 1
+    pub fn cables_from_host(mut self, cables: u8) -> Self {
+        self.cables_from_host = cables;
+        self
+    }
+
+    /// Sets the number of virtual cables carrying MIDI data from the device to the host.
+    ///
+    /// Default: 1
+    pub fn cables_to_host(mut self, cables: u8) -> Self {
+        self.cables_to_host = cables;
+        self
+    }
+
+    /// Sets whether the embedded jacks are wired to external jacks.
+    ///
+    /// Default: `true`
+    pub fn external_jacks(mut self, external_jacks: bool) -> Self {
+        self.external_jacks = external_jacks;
+        self
+    }
+}
+
+/// Adds a USB MIDI function: an audio control interface and a MIDI streaming interface with
+/// jack IDs numbered from 1. `read_ep` is required if there are cables from the host, and
+/// `write_ep` if there are cables to the host; otherwise they are ignored.
+pub fn create_midi_function(
+    device: &mut DeviceBuilder,
+    options: &MidiOptions,
+    read_ep: Option<&dyn EndpointInfo>,
+    write_ep: Option<&dyn EndpointInfo>,
+) {
+    let from_host = options.cables_from_host;
+    let to_host = options.cables_to_host;
+    if from_host > MAX_CABLES || to_host > MAX_CABLES || from_host + to_host == 0 {
+        device.defer_error(BuilderError::InvalidMidiCableCount {
+            cables_from_host: from_host,
+            cables_to_host: to_host,
+        });
+        return;
+    }
+
+    let mut jacks = Vec::new();
+    let mut next_id = 1;
+    let mut alloc_id = || {
+        let id = next_id;
+        next_id += 1;
+        id
+    };
+
+    // Jacks of the cables from the host: embedded IN jack -> external OUT jack
+    let mut read_jacks = Vec::new();
+    for _ in 0..from_host {
+        let embedded = alloc_id();
+        jacks.push(vec![MS_MIDI_IN_JACK, JACK_TYPE_EMBEDDED, embedded, 0]);
+        if options.external_jacks {
+            jacks.push(vec![
+                MS_MIDI_OUT_JACK,
+                JACK_TYPE_EXTERNAL,
+                alloc_id(),
+                1,
+                embedded,
+                1,
+                0,
+            ]);
+        }
+        read_jacks.push(embedded);
+    }
+
+    // Jacks of the cables to the host: external IN jack -> embedded OUT jack
+    let mut write_jacks = Vec::new();
+    for _ in 0..to_host {
+        let embedded = if options.external_jacks {
+            let external = alloc_id();
+            jacks.push(vec![MS_MIDI_IN_JACK, JACK_TYPE_EXTERNAL, external, 0]);
+            let embedded = alloc_id();
+            jacks.push(vec![
+                MS_MIDI_OUT_JACK,
+                JACK_TYPE_EMBEDDED,
+                embedded,
+                1,
+                external,
+                1,
+                0,
+            ]);
+            embedded
+        } else {
+            let embedded = alloc_id();
+            jacks.push(vec![MS_MIDI_OUT_JACK, JACK_TYPE_EMBEDDED, embedded, 0, 0]);
+            embedded
+        };
+        write_jacks.push(embedded);
+    }
+
+    let mut endpoints = Vec::new();
+    for &(cables, ep, jacks, direction) in &[
+        (from_host, read_ep, &read_jacks, UsbDirection::Out),
+        (to_host, write_ep, &write_jacks, UsbDirection::In),
+    ] {
+        if cables == 0 {
+            continue;
+        }
+        match ep {
+            Some(ep) => {
+                device.check_endpoint(ep, EndpointType::Bulk, direction);
+                endpoints.push(midi_endpoint(ep.descriptor(), jacks));
+            }
+            None => {
+                device.defer_error(BuilderError::MissingMidiEndpoint { direction });
+                return;
+            }
+        }
+    }
+
+    let control_if = device.alloc_interface();
+    let streaming_if = device.alloc_interface();
+    let streaming_if_id = streaming_if.descriptor.interface_number;
+
+    control_if
+        .interface_class(USB_CLASS_AUDIO)
+        .interface_sub_class(AUDIO_SUBCLASS_CONTROL)
+        .descriptor(
+            CS_INTERFACE,
+            &[
+                AC_HEADER,
+                0x00,
+                0x01, // bcdADC
+                0x09,
+                0x00,            // wTotalLength
+                1,               // bInCollection
+                streaming_if_id, // baInterfaceNr
+            ],
+        )
+        .save(device);
+
+    // The total length covers the class-specific interface descriptors and the endpoint
+    // descriptors that follow them, each descriptor being 2 bytes longer than its data
+    let jacks_length: usize = jacks.iter().map(|j| j.len() + 2).sum();
+    let endpoints_length: usize = endpoints
+        .iter()
+        .flat_map(|ep| ep.custom_descriptors.iter())
+        .map(|d| d.data.len() + 2)
+        .sum::<usize>()
+        + 9 * endpoints.len();
+    let total_length = 7 + jacks_length + endpoints_length;
+
+    let mut streaming_if = streaming_if
+        .interface_class(USB_CLASS_AUDIO)
+        .interface_sub_class(AUDIO_SUBCLASS_MIDISTREAMING)
+        .descriptor(
+            CS_INTERFACE,
+            &[
+                MS_HEADER,
+                0x00,
+                0x01, // bcdMSC
+                total_length as u8,
+                (total_length >> 8) as u8, // wTotalLength
+            ],
+        );
+    for jack in &jacks {
+        streaming_if = streaming_if.descriptor(CS_INTERFACE, jack);
+    }
+    for endpoint in endpoints {
+        streaming_if = streaming_if.endpoint(endpoint);
+    }
+    streaming_if.save(device);
+}
+
+/// Returns the 9 byte bulk endpoint descriptor followed by the class-specific descriptor
+/// listing the embedded jacks of the endpoint.
+fn midi_endpoint(endpoint: &UsbEndpointDescriptor, jacks: &[u8]) -> UsbEndpointDescriptor {
+    let mut data = vec![MS_GENERAL, jacks.len() as u8]; // bNumEmbMIDIJack
+    data.extend_from_slice(jacks); // baAssocJackID
+
+    endpoint
+        .clone()
+        .extra_fields(&[
+            0, // bRefresh
+            0, // bSynchAddress
+        ])
+        .descriptor(CS_ENDPOINT, &data)
+}
+
+/// USB MIDI function. Only the bulk endpoints of the directions that have cables are allocated.
+pub struct MidiFunction {
+    pub options: MidiOptions,
+    pub max_packet_size: u16,
+    endpoints: FunctionEndpoints,
+}
+
+impl MidiFunction {
+    pub fn new(options: MidiOptions) -> Self {
+        Self {
+            options,
+            max_packet_size: 64,
+            endpoints: FunctionEndpoints::default(),
+        }
+    }
+
+    /// Sets the max packet size of the bulk endpoints.
+    ///
+    /// Default: 64
+    pub fn max_packet_size(mut self, max_packet_size: u16) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+}
+
+impl UsbFunction for MidiFunction {
+    fn allocate_endpoints(&mut self, allocator: &mut DeviceAllocator) -> Result<(), BuilderError> {
+        let mut specs = Vec::new();
+        if self.options.cables_from_host > 0 {
+            specs.push((
+                UsbDirection::Out,
+                EndpointType::Bulk,
+                self.max_packet_size,
+                0,
+            ));
+        }
+        if self.options.cables_to_host > 0 {
+            specs.push((
+                UsbDirection::In,
+                EndpointType::Bulk,
+                self.max_packet_size,
+                0,
+            ));
+        }
+        self.endpoints.allocate(allocator, &specs)
+    }
+
+    fn add_to_device(&self, device: &mut DeviceBuilder) -> Result<(), BuilderError> {
+        let from_host = self.options.cables_from_host > 0;
+        let to_host = self.options.cables_to_host > 0;
+        let count = usize::from(from_host) + usize::from(to_host);
+        let mut eps = self.endpoints.get(device, count)?.iter();
+        let read_ep = if from_host { eps.next() } else { None };
+        let write_ep = if to_host { eps.next() } else { None };
+        create_midi_function(
+            device,
+            &self.options,
+            read_ep.map(|ep| ep as &dyn EndpointInfo),
+            write_ep.map(|ep| ep as &dyn EndpointInfo),
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::UsbVidPid;
+    use crate::test_util::{build_function, endpoint};
+
+    #[test]
+    fn external_jacks() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        create_midi_function(
+            &mut device,
+            &MidiOptions::new(),
+            Some(&endpoint(1, UsbDirection::Out, EndpointType::Bulk, 64, 0)),
+            Some(&endpoint(1, UsbDirection::In, EndpointType::Bulk, 64, 0)),
+        );
+        let config = device.build();
+
+        let expected: &[u8] = &[
+            0x09, 0x02, 0x65, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32, // configuration
+            0x09, 0x04, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00, // audio control interface
+            0x09, 0x24, 0x01, 0x00, 0x01, 0x09, 0x00, 0x01, 0x01, // header
+            0x09, 0x04, 0x01, 0x00, 0x02, 0x01, 0x03, 0x00, 0x00, // MIDI streaming interface
+            0x07, 0x24, 0x01, 0x00, 0x01, 0x41, 0x00, // header
+            0x06, 0x24, 0x02, 0x01, 0x01, 0x00, // embedded IN jack 1
+            0x09, 0x24, 0x03, 0x02, 0x02, 0x01, 0x01, 0x01, 0x00, // external OUT jack 2
+            0x06, 0x24, 0x02, 0x02, 0x03, 0x00, // external IN jack 3
+            0x09, 0x24, 0x03, 0x01, 0x04, 0x01, 0x03, 0x01, 0x00, // embedded OUT jack 4
+            0x09, 0x05, 0x01, 0x02, 0x40, 0x00, 0x00, 0x00, 0x00, // bulk OUT endpoint
+            0x05, 0x25, 0x01, 0x01, 0x01, // class-specific endpoint, jack 1
+            0x09, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00, 0x00, 0x00, // bulk IN endpoint
+            0x05, 0x25, 0x01, 0x01, 0x04, // class-specific endpoint, jack 4
+        ];
+        assert_eq!(config.configuration_descriptors[0], expected);
+    }
+
+    #[test]
+    fn function_allocates_only_used_endpoints() {
+        let options = MidiOptions::new()
+            .cables_from_host(0)
+            .cables_to_host(2)
+            .external_jacks(false);
+        let config = build_function(&mut MidiFunction::new(options)).unwrap();
+
+        assert_eq!(config.endpoints.len(), 3);
+        let expected: &[u8] = &[
+            0x09, 0x02, 0x48, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32, // configuration
+            0x09, 0x04, 0x00, 0x00, 0x00, 0x01, 0x01, 0x00, 0x00, // audio control interface
+            0x09, 0x24, 0x01, 0x00, 0x01, 0x09, 0x00, 0x01, 0x01, // header
+            0x09, 0x04, 0x01, 0x00, 0x01, 0x01, 0x03, 0x00, 0x00, // MIDI streaming interface
+            0x07, 0x24, 0x01, 0x00, 0x01, 0x24, 0x00, // header
+            0x07, 0x24, 0x03, 0x01, 0x01, 0x00, 0x00, // embedded OUT jack 1
+            0x07, 0x24, 0x03, 0x01, 0x02, 0x00, 0x00, // embedded OUT jack 2
+            0x09, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00, 0x00, 0x00, // bulk IN endpoint
+            0x06, 0x25, 0x01, 0x02, 0x01, 0x02, // class-specific endpoint, jacks 1-2
+        ];
+        assert_eq!(config.configuration_descriptors[0], expected);
+    }
+
+    #[test]
+    fn invalid_cable_count() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let options = MidiOptions::new().cables_from_host(17);
+        create_midi_function(
+            &mut device,
+            &options,
+            Some(&endpoint(1, UsbDirection::Out, EndpointType::Bulk, 64, 0)),
+            Some(&endpoint(1, UsbDirection::In, EndpointType::Bulk, 64, 0)),
+        );
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::InvalidMidiCableCount {
+                cables_from_host: 17,
+                cables_to_host: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn missing_endpoint() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let options = MidiOptions::new().cables_from_host(0);
+        create_midi_function(&mut device, &options, None, None);
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::MissingMidiEndpoint {
+                direction: UsbDirection::In,
+            }
+        );
+    }
+}
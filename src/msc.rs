@@ -0,0 +1,192 @@
+use crate::builder::DeviceBuilder;
+use crate::control::{ControlRequest, ControlResponse, Recipient, RequestType};
+use crate::endpoint::DeviceAllocator;
+use crate::error::BuilderError;
+use crate::function::{FunctionEndpoints, UsbFunction};
+use crate::EndpointInfo;
+use usb_device::endpoint::EndpointType;
+use usb_device::UsbDirection;
+
+pub const USB_CLASS_MSC: u8 = 0x08;
+pub const MSC_SUBCLASS_RBC: u8 = 0x01;
+pub const MSC_SUBCLASS_MMC5: u8 = 0x02;
+pub const MSC_SUBCLASS_UFI: u8 = 0x04;
+pub const MSC_SUBCLASS_SCSI: u8 = 0x06;
+pub const MSC_SUBCLASS_LSD_FS: u8 = 0x07;
+pub const MSC_SUBCLASS_IEEE_1667: u8 = 0x08;
+const MSC_PROTOCOL_BULK_ONLY: u8 = 0x50;
+
+/// Bulk-Only Mass Storage Reset request code
+pub const MSC_REQUEST_RESET: u8 = 0xff;
+/// Get Max LUN request code
+pub const MSC_REQUEST_GET_MAX_LUN: u8 = 0xfe;
+
+/// Maximum number of logical units of a Bulk-Only Transport device
+const MAX_LUN_COUNT: u8 = 16;
+
+/// Adds a Mass Storage interface using the Bulk-Only Transport with the given subclass, e.g.
+/// `MSC_SUBCLASS_SCSI`, and number of logical units.
+///
+/// The generated code answers Get Max LUN, and accepts Bulk-Only Mass Storage Reset, after which
+/// the application has to get ready for the next Command Block Wrapper.
+pub fn create_msc_function(
+    device: &mut DeviceBuilder,
+    sub_class: u8,
+    lun_count: u8,
+    read_ep: impl EndpointInfo,
+    write_ep: impl EndpointInfo,
+) {
+    device.check_endpoint(&read_ep, EndpointType::Bulk, UsbDirection::Out);
+    device.check_endpoint(&write_ep, EndpointType::Bulk, UsbDirection::In);
+    if lun_count == 0 || lun_count > MAX_LUN_COUNT {
+        device.defer_error(BuilderError::InvalidLunCount(lun_count));
+    }
+
+    let interface = device.alloc_interface();
+    let interface_number = u16::from(interface.descriptor.interface_number);
+
+    interface
+        .interface_class(USB_CLASS_MSC)
+        .interface_sub_class(sub_class)
+        .interface_protocol(MSC_PROTOCOL_BULK_ONLY)
+        .endpoint(write_ep.descriptor().clone())
+        .endpoint(read_ep.descriptor().clone())
+        .save(device);
+
+    device.add_control_request(
+        ControlRequest::new(
+            RequestType::Class,
+            Recipient::Interface,
+            MSC_REQUEST_GET_MAX_LUN,
+            ControlResponse::Data(vec![lun_count.wrapping_sub(1)]),
+        )
+        .value(0)
+        .index(interface_number),
+    );
+    device.add_control_request(
+        ControlRequest::new(
+            RequestType::Class,
+            Recipient::Interface,
+            MSC_REQUEST_RESET,
+            ControlResponse::Accept,
+        )
+        .value(0)
+        .index(interface_number),
+    );
+}
+
+/// Mass Storage function using the Bulk-Only Transport.
+pub struct MscFunction {
+    pub sub_class: u8,
+    pub lun_count: u8,
+    pub max_packet_size: u16,
+    endpoints: FunctionEndpoints,
+}
+
+impl MscFunction {
+    pub fn new(sub_class: u8, lun_count: u8) -> Self {
+        Self {
+            sub_class,
+            lun_count,
+            max_packet_size: 64,
+            endpoints: FunctionEndpoints::default(),
+        }
+    }
+
+    /// Sets the max packet size of the bulk endpoints.
+    ///
+    /// Default: 64
+    pub fn max_packet_size(mut self, max_packet_size: u16) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+}
+
+impl UsbFunction for MscFunction {
+    fn allocate_endpoints(&mut self, allocator: &mut DeviceAllocator) -> Result<(), BuilderError> {
+        let mps = self.max_packet_size;
+        self.endpoints.allocate(
+            allocator,
+            &[
+                (UsbDirection::Out, EndpointType::Bulk, mps, 0),
+                (UsbDirection::In, EndpointType::Bulk, mps, 0),
+            ],
+        )
+    }
+
+    fn add_to_device(&self, device: &mut DeviceBuilder) -> Result<(), BuilderError> {
+        let eps = self.endpoints.get(device, 2)?;
+        create_msc_function(device, self.sub_class, self.lun_count, &eps[0], &eps[1]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::UsbVidPid;
+    use crate::test_util::{endpoint, response};
+
+    #[test]
+    fn bulk_only_interface() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let read_ep = endpoint(1, UsbDirection::Out, EndpointType::Bulk, 64, 0);
+        let write_ep = endpoint(1, UsbDirection::In, EndpointType::Bulk, 64, 0);
+        create_msc_function(&mut device, MSC_SUBCLASS_SCSI, 2, read_ep, write_ep);
+        let config = device.build();
+
+        assert_eq!(
+            config.configuration_descriptors[0],
+            [
+                0x09, 0x02, 0x20, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, // configuration
+                0x09, 0x04, 0x00, 0x00, 0x02, 0x08, 0x06, 0x50, 0x00, // interface
+                0x07, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00, // bulk IN endpoint
+                0x07, 0x05, 0x01, 0x02, 0x40, 0x00, 0x00, // bulk OUT endpoint
+            ][..]
+        );
+
+        let get_max_lun = &config.control_requests[0];
+        assert_eq!(
+            (get_max_lun.request, get_max_lun.value, get_max_lun.index),
+            (MSC_REQUEST_GET_MAX_LUN, Some(0), Some(0))
+        );
+        assert_eq!(
+            response(&config, |r| r.request == MSC_REQUEST_GET_MAX_LUN),
+            [1]
+        );
+        let reset = &config.control_requests[1];
+        assert_eq!(
+            (reset.request, reset.value, reset.index),
+            (MSC_REQUEST_RESET, Some(0), Some(0))
+        );
+        assert!(matches!(reset.response, ControlResponse::Accept));
+    }
+
+    #[test]
+    fn invalid_lun_count() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let read_ep = endpoint(1, UsbDirection::Out, EndpointType::Bulk, 64, 0);
+        let write_ep = endpoint(1, UsbDirection::In, EndpointType::Bulk, 64, 0);
+        create_msc_function(&mut device, MSC_SUBCLASS_SCSI, 17, read_ep, write_ep);
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::InvalidLunCount(17)
+        );
+    }
+
+    #[test]
+    fn unexpected_endpoint_type() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let read_ep = endpoint(1, UsbDirection::Out, EndpointType::Interrupt, 64, 0);
+        let write_ep = endpoint(1, UsbDirection::In, EndpointType::Bulk, 64, 0);
+        create_msc_function(&mut device, MSC_SUBCLASS_SCSI, 1, read_ep, write_ep);
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::UnexpectedEndpointType {
+                address: 0x01,
+                ep_type: EndpointType::Bulk,
+                direction: UsbDirection::Out,
+            }
+        );
+    }
+}
@@ -0,0 +1,550 @@
+use crate::builder::ConfigurationBuilder;
+use crate::control::{ControlRequest, ControlResponse, Recipient, RequestType};
+use crate::error::BuilderError;
+use crate::usb::{UsbDescriptorType, UsbDescriptorWriter, UsbDeviceCapability};
+
+/// `wIndex` of the vendor request that returns the MS OS 2.0 descriptor set
+pub const MS_OS_20_DESCRIPTOR_INDEX: u16 = 0x07;
+
+/// `wIndex` of the vendor request that returns the MS OS 1.0 Extended Compat ID descriptor
+pub const MS_OS_10_EXTENDED_COMPAT_ID_INDEX: u16 = 0x04;
+
+/// `wIndex` of the vendor request that returns the MS OS 1.0 Extended Properties descriptor
+pub const MS_OS_10_EXTENDED_PROPERTIES_INDEX: u16 = 0x05;
+
+/// Windows 8.1, the first version that supports MS OS 2.0 descriptors
+pub const WINDOWS_VERSION_8_1: u32 = 0x0603_0000;
+
+/// MS OS 2.0 platform capability UUID {D8DD60DF-4589-4CC7-9CD2-659D9E648A9F} in wire byte order
+const MS_OS_20_PLATFORM_UUID: [u8; 16] = [
+    0xdf, 0x60, 0xdd, 0xd8, 0x89, 0x45, 0xc7, 0x4c, 0x9c, 0xd2, 0x65, 0x9d, 0x9e, 0x64, 0x8a, 0x9f,
+];
+
+const MS_OS_20_SET_HEADER_DESCRIPTOR: u16 = 0x00;
+const MS_OS_20_SUBSET_HEADER_CONFIGURATION: u16 = 0x01;
+const MS_OS_20_SUBSET_HEADER_FUNCTION: u16 = 0x02;
+const MS_OS_20_FEATURE_COMPATIBLE_ID: u16 = 0x03;
+const MS_OS_20_FEATURE_REG_PROPERTY: u16 = 0x04;
+
+/// A value of a registry property set for a device interface.
+#[derive(Clone, Debug)]
+pub enum RegistryValue {
+    String(String),
+    ExpandString(String),
+    Binary(Vec<u8>),
+    DwordLittleEndian(u32),
+    DwordBigEndian(u32),
+    Link(String),
+    MultiString(Vec<String>),
+}
+
+impl RegistryValue {
+    /// Returns the registry data type (`REG_SZ`, `REG_BINARY` and so on).
+    pub fn data_type(&self) -> u16 {
+        match self {
+            RegistryValue::String(_) => 1,
+            RegistryValue::ExpandString(_) => 2,
+            RegistryValue::Binary(_) => 3,
+            RegistryValue::DwordLittleEndian(_) => 4,
+            RegistryValue::DwordBigEndian(_) => 5,
+            RegistryValue::Link(_) => 6,
+            RegistryValue::MultiString(_) => 7,
+        }
+    }
+
+    /// Returns the value as stored in the descriptor.
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            RegistryValue::String(s) | RegistryValue::ExpandString(s) | RegistryValue::Link(s) => {
+                encode_utf16z(s)
+            }
+            RegistryValue::Binary(data) => data.clone(),
+            RegistryValue::DwordLittleEndian(value) => value.to_le_bytes().to_vec(),
+            RegistryValue::DwordBigEndian(value) => value.to_be_bytes().to_vec(),
+            RegistryValue::MultiString(strings) => {
+                let mut buf = Vec::new();
+                for s in strings {
+                    buf.extend(encode_utf16z(s));
+                }
+                buf.extend_from_slice(&[0, 0]);
+                buf
+            }
+        }
+    }
+}
+
+/// A Microsoft OS feature of an interface, see `InterfaceBuilder::ms_os_compatible_id` and
+/// `InterfaceBuilder::ms_os_registry_property`.
+#[derive(Clone, Debug)]
+pub enum MsOsFeature {
+    CompatibleId {
+        compatible_id: String,
+        sub_compatible_id: String,
+    },
+    RegistryProperty {
+        name: String,
+        value: RegistryValue,
+    },
+}
+
+/// Options of the MS OS 2.0 descriptor set.
+#[derive(Clone, Debug)]
+pub struct MsOs20Options {
+    pub vendor_code: u8,
+    pub windows_version: u32,
+    pub alt_enum_code: u8,
+}
+
+/// Encodes a string as UTF-16LE with a terminating NUL character.
+pub(crate) fn encode_utf16z(s: &str) -> Vec<u8> {
+    let mut buf = Vec::new();
+    s.encode_utf16()
+        .chain(Some(0))
+        .for_each(|c| buf.extend_from_slice(&c.to_le_bytes()));
+    buf
+}
+
+/// Encodes a compatible or sub-compatible ID as a NUL-padded 8-byte ASCII field.
+pub(crate) fn encode_compatible_id(
+    interface: u8,
+    compatible_id: &str,
+) -> Result<[u8; 8], BuilderError> {
+    if compatible_id.len() > 8 || !compatible_id.is_ascii() {
+        return Err(BuilderError::InvalidCompatibleId {
+            interface,
+            compatible_id: compatible_id.to_string(),
+        });
+    }
+    let mut buf = [0; 8];
+    buf[..compatible_id.len()].copy_from_slice(compatible_id.as_bytes());
+    Ok(buf)
+}
+
+/// Returns the interface numbers that have MS OS features together with the features.
+pub(crate) fn interface_features(
+    configuration: &ConfigurationBuilder,
+) -> Vec<(u8, Vec<&MsOsFeature>)> {
+    let mut result: Vec<(u8, Vec<&MsOsFeature>)> = Vec::new();
+    for interface in &configuration.interfaces {
+        let number = interface.descriptor.interface_number;
+        for feature in &interface.ms_os_features {
+            match result.iter_mut().find(|(n, _)| *n == number) {
+                Some((_, features)) => features.push(feature),
+                None => result.push((number, vec![feature])),
+            }
+        }
+    }
+    result
+}
+
+fn write_descriptor(buf: &mut Vec<u8>, descriptor_type: u16, data: &[u8]) {
+    let length = data.len() as u16 + 4;
+    buf.extend_from_slice(&length.to_le_bytes()); // wLength
+    buf.extend_from_slice(&descriptor_type.to_le_bytes()); // wDescriptorType
+    buf.extend_from_slice(data);
+}
+
+fn write_subset(buf: &mut Vec<u8>, descriptor_type: u16, first: u8, subset: &[u8]) {
+    let length = subset.len() as u16 + 8;
+    write_descriptor(
+        buf,
+        descriptor_type,
+        &[
+            first, // bConfigurationValue / bFirstInterface
+            0,     // bReserved
+            length as u8,
+            (length >> 8) as u8, // wTotalLength / wSubsetLength
+        ],
+    );
+    buf.extend_from_slice(subset);
+}
+
+fn write_features(
+    buf: &mut Vec<u8>,
+    interface: u8,
+    features: &[&MsOsFeature],
+) -> Result<(), BuilderError> {
+    for feature in features {
+        match feature {
+            MsOsFeature::CompatibleId {
+                compatible_id,
+                sub_compatible_id,
+            } => {
+                let mut data = Vec::new();
+                data.extend_from_slice(&encode_compatible_id(interface, compatible_id)?);
+                data.extend_from_slice(&encode_compatible_id(interface, sub_compatible_id)?);
+                write_descriptor(buf, MS_OS_20_FEATURE_COMPATIBLE_ID, &data);
+            }
+            MsOsFeature::RegistryProperty { name, value } => {
+                let name = encode_utf16z(name);
+                let value_data = value.encode();
+                let mut data = Vec::new();
+                data.extend_from_slice(&value.data_type().to_le_bytes()); // wPropertyDataType
+                data.extend_from_slice(&(name.len() as u16).to_le_bytes()); // wPropertyNameLength
+                data.extend_from_slice(&name); // PropertyName
+                data.extend_from_slice(&(value_data.len() as u16).to_le_bytes()); // wPropertyDataLength
+                data.extend_from_slice(&value_data); // PropertyData
+                write_descriptor(buf, MS_OS_20_FEATURE_REG_PROPERTY, &data);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Generates the MS OS 2.0 descriptor set for the interface features of all configurations.
+///
+/// Configuration and function subsets are only used when needed: a device with a single
+/// configuration and a single interface gets its features directly after the set header.
+pub(crate) fn descriptor_set(
+    options: &MsOs20Options,
+    configurations: &[ConfigurationBuilder],
+) -> Result<Vec<u8>, BuilderError> {
+    let mut body = Vec::new();
+    let single_configuration = configurations.len() == 1;
+    for (index, configuration) in configurations.iter().enumerate() {
+        let features = interface_features(configuration);
+        if features.is_empty() {
+            continue;
+        }
+
+        let composite = configuration.interfaces.iter().any(|i| {
+            i.descriptor.interface_number != configuration.interfaces[0].descriptor.interface_number
+        });
+
+        let mut subset = Vec::new();
+        for (interface, features) in &features {
+            if composite {
+                let mut function = Vec::new();
+                write_features(&mut function, *interface, features)?;
+                write_subset(
+                    &mut subset,
+                    MS_OS_20_SUBSET_HEADER_FUNCTION,
+                    *interface,
+                    &function,
+                );
+            } else {
+                write_features(&mut subset, *interface, features)?;
+            }
+        }
+
+        if single_configuration && !composite {
+            body.extend(subset);
+        } else {
+            // bConfigurationValue of the subset is in fact the configuration index
+            write_subset(
+                &mut body,
+                MS_OS_20_SUBSET_HEADER_CONFIGURATION,
+                index as u8,
+                &subset,
+            );
+        }
+    }
+
+    let mut buf = Vec::new();
+    let total_length = body.len() as u16 + 10;
+    let mut header = options.windows_version.to_le_bytes().to_vec(); // dwWindowsVersion
+    header.extend_from_slice(&total_length.to_le_bytes()); // wTotalLength
+    write_descriptor(&mut buf, MS_OS_20_SET_HEADER_DESCRIPTOR, &header);
+    buf.extend(body);
+    Ok(buf)
+}
+
+/// Returns the BOS platform capability that points the host to the descriptor set.
+pub(crate) fn platform_capability(
+    options: &MsOs20Options,
+    set_length: usize,
+) -> UsbDeviceCapability {
+    let mut data = options.windows_version.to_le_bytes().to_vec(); // dwWindowsVersion
+    data.extend_from_slice(&(set_length as u16).to_le_bytes()); // wMSOSDescriptorSetTotalLength
+    data.push(options.vendor_code); // bMS_VendorCode
+    data.push(options.alt_enum_code); // bAltEnumCode
+    UsbDeviceCapability::Platform {
+        uuid: MS_OS_20_PLATFORM_UUID,
+        data,
+    }
+}
+
+/// Returns the `MSFT100` string descriptor that announces the MS OS 1.0 vendor code.
+pub(crate) fn os_string_descriptor(vendor_code: u8) -> Vec<u8> {
+    let mut data = Vec::new();
+    "MSFT100"
+        .encode_utf16()
+        .for_each(|c| data.extend_from_slice(&c.to_le_bytes())); // qwSignature
+    data.push(vendor_code); // bMS_VendorCode
+    data.push(0); // bPad
+
+    let mut w = UsbDescriptorWriter::new();
+    w.write(UsbDescriptorType::String as u8, &data);
+    w.finish()
+}
+
+fn ms_os_10_header(length: usize, index: u16, count: u16) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&(length as u32).to_le_bytes()); // dwLength
+    buf.extend_from_slice(&0x0100u16.to_le_bytes()); // bcdVersion
+    buf.extend_from_slice(&index.to_le_bytes()); // wIndex
+    buf.extend_from_slice(&count.to_le_bytes()); // wCount / bCount and reserved
+    buf
+}
+
+fn extended_compat_id(features: &[(u8, Vec<&MsOsFeature>)]) -> Result<Vec<u8>, BuilderError> {
+    let mut sections = Vec::new();
+    let mut count = 0;
+    for (interface, features) in features {
+        for feature in features {
+            if let MsOsFeature::CompatibleId {
+                compatible_id,
+                sub_compatible_id,
+            } = feature
+            {
+                sections.push(*interface); // bFirstInterfaceNumber
+                sections.push(0x01); // reserved
+                sections.extend_from_slice(&encode_compatible_id(*interface, compatible_id)?);
+                sections.extend_from_slice(&encode_compatible_id(*interface, sub_compatible_id)?);
+                sections.extend_from_slice(&[0; 6]); // reserved
+                count += 1;
+            }
+        }
+    }
+
+    let mut buf = ms_os_10_header(
+        16 + sections.len(),
+        MS_OS_10_EXTENDED_COMPAT_ID_INDEX,
+        count,
+    );
+    buf.extend_from_slice(&[0; 6]); // reserved
+    buf.extend(sections);
+    Ok(buf)
+}
+
+fn extended_properties(features: &[&MsOsFeature]) -> Vec<u8> {
+    let mut sections = Vec::new();
+    let mut count = 0;
+    for feature in features {
+        if let MsOsFeature::RegistryProperty { name, value } = feature {
+            let name = encode_utf16z(name);
+            let data = value.encode();
+            let size = 14 + name.len() + data.len();
+            sections.extend_from_slice(&(size as u32).to_le_bytes()); // dwSize
+            sections.extend_from_slice(&u32::from(value.data_type()).to_le_bytes()); // dwPropertyDataType
+            sections.extend_from_slice(&(name.len() as u16).to_le_bytes()); // wPropertyNameLength
+            sections.extend_from_slice(&name); // bPropertyName
+            sections.extend_from_slice(&(data.len() as u32).to_le_bytes()); // dwPropertyDataLength
+            sections.extend_from_slice(&data); // bPropertyData
+            count += 1;
+        }
+    }
+
+    let mut buf = ms_os_10_header(
+        10 + sections.len(),
+        MS_OS_10_EXTENDED_PROPERTIES_INDEX,
+        count,
+    );
+    buf.extend(sections);
+    buf
+}
+
+/// Returns the vendor requests that return the MS OS 1.0 feature descriptors of a configuration.
+///
+/// The Extended Properties descriptor of an interface is requested with the interface number in
+/// `wValue`. Windows is known to send this request both to the interface and to the device, so
+/// both recipients are answered.
+pub(crate) fn ms_os_10_requests(
+    vendor_code: u8,
+    configuration: &ConfigurationBuilder,
+) -> Result<Vec<ControlRequest>, BuilderError> {
+    let features = interface_features(configuration);
+    let mut requests = Vec::new();
+
+    let compat_id = extended_compat_id(&features)?;
+    requests.push(
+        ControlRequest::new(
+            RequestType::Vendor,
+            Recipient::Device,
+            vendor_code,
+            ControlResponse::Data(compat_id),
+        )
+        .index(MS_OS_10_EXTENDED_COMPAT_ID_INDEX),
+    );
+
+    for (interface, features) in &features {
+        if !features
+            .iter()
+            .any(|f| matches!(f, MsOsFeature::RegistryProperty { .. }))
+        {
+            continue;
+        }
+        let properties = extended_properties(features);
+        for &recipient in &[Recipient::Interface, Recipient::Device] {
+            requests.push(
+                ControlRequest::new(
+                    RequestType::Vendor,
+                    recipient,
+                    vendor_code,
+                    ControlResponse::Data(properties.clone()),
+                )
+                .value(u16::from(*interface))
+                .index(MS_OS_10_EXTENDED_PROPERTIES_INDEX),
+            );
+        }
+    }
+
+    Ok(requests)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{DeviceBuilder, DeviceConfig, UsbVidPid};
+    use crate::test_util::{endpoint, response};
+    use crate::usb::MS_OS_STRING_INDEX;
+    use usb_device::endpoint::EndpointType;
+    use usb_device::UsbDirection;
+
+    const GUID: &str = "{88bae032-5a81-49f0-bc3d-a4ff138216d6}";
+
+    fn utf16z(s: &str) -> Vec<u8> {
+        s.encode_utf16()
+            .chain(Some(0))
+            .flat_map(|c| c.to_le_bytes().to_vec())
+            .collect()
+    }
+
+    fn build_device(device: DeviceBuilder, interfaces: u8) -> DeviceConfig {
+        let mut device = device;
+        for number in 0..interfaces {
+            let ep = endpoint(number + 1, UsbDirection::In, EndpointType::Bulk, 64, 0);
+            let mut interface = device.alloc_interface().interface_class(0xff).endpoint(ep);
+            if number == interfaces - 1 {
+                interface = interface
+                    .ms_os_compatible_id("WINUSB", "")
+                    .ms_os_registry_property(
+                        "DeviceInterfaceGUIDs",
+                        RegistryValue::MultiString(vec![GUID.to_string()]),
+                    );
+            }
+            interface.save(&mut device);
+        }
+        device.build()
+    }
+
+    /// Matches the vendor requests for the descriptor with the given `wIndex`.
+    fn descriptor_request(
+        recipient: Recipient,
+        value: Option<u16>,
+        index: u16,
+    ) -> impl Fn(&ControlRequest) -> bool {
+        move |r| r.recipient == recipient && r.value == value && r.index == Some(index)
+    }
+
+    fn registry_property_20() -> Vec<u8> {
+        let mut property = vec![
+            0x84, 0x00, 0x04, 0x00, // wLength, wDescriptorType
+            0x07, 0x00, // wPropertyDataType: REG_MULTI_SZ
+            0x2a, 0x00, // wPropertyNameLength
+        ];
+        property.extend(utf16z("DeviceInterfaceGUIDs"));
+        property.extend_from_slice(&[0x50, 0x00]); // wPropertyDataLength
+        property.extend(utf16z(GUID));
+        property.extend_from_slice(&[0x00, 0x00]);
+        property
+    }
+
+    #[test]
+    fn ms_os_20_single_interface() {
+        let device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001)).ms_os_20_descriptors(0x21);
+        let config = build_device(device, 1);
+
+        let mut expected = vec![
+            0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x06, 0xa2, 0x00, // set header
+            0x14, 0x00, 0x03, 0x00, b'W', b'I', b'N', b'U', b'S', b'B', 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // compatible ID
+        ];
+        expected.extend(registry_property_20());
+        assert_eq!(
+            response(&config, descriptor_request(Recipient::Device, None, 0x07)),
+            &expected[..]
+        );
+
+        let mut capability = vec![
+            0x05, 0x0f, 0x21, 0x00, 0x01, // BOS
+            0x1c, 0x10, 0x05, 0x00, // platform capability
+        ];
+        capability.extend_from_slice(&MS_OS_20_PLATFORM_UUID);
+        capability.extend_from_slice(&[0x00, 0x00, 0x03, 0x06, 0xa2, 0x00, 0x21, 0x00]);
+        assert_eq!(config.bos_descriptor, Some(capability));
+    }
+
+    #[test]
+    fn ms_os_20_options() {
+        let device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001))
+            .ms_os_20_windows_version(0x0a00_0000)
+            .ms_os_20_alt_enum_code(1)
+            .ms_os_20_descriptors(0x21);
+        let config = build_device(device, 1);
+
+        let set = response(&config, descriptor_request(Recipient::Device, None, 0x07));
+        assert_eq!(set[4..8], [0x00, 0x00, 0x00, 0x0a]); // dwWindowsVersion
+        let capability = config.bos_descriptor.unwrap();
+        assert_eq!(
+            capability[capability.len() - 8..],
+            [0x00, 0x00, 0x00, 0x0a, 0xa2, 0x00, 0x21, 0x01]
+        );
+    }
+
+    #[test]
+    fn ms_os_20_composite_device() {
+        let device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001)).ms_os_20_descriptors(0x21);
+        let config = build_device(device, 2);
+
+        let mut expected = vec![
+            0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x06, 0xb2, 0x00, // set header
+            0x08, 0x00, 0x01, 0x00, 0x00, 0x00, 0xa8, 0x00, // configuration subset, index 0
+            0x08, 0x00, 0x02, 0x00, 0x01, 0x00, 0xa0, 0x00, // function subset, interface 1
+            0x14, 0x00, 0x03, 0x00, b'W', b'I', b'N', b'U', b'S', b'B', 0x00, 0x00, 0x00, 0x00,
+            0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // compatible ID
+        ];
+        expected.extend(registry_property_20());
+        assert_eq!(
+            response(&config, descriptor_request(Recipient::Device, None, 0x07)),
+            &expected[..]
+        );
+    }
+
+    #[test]
+    fn ms_os_10_descriptors() {
+        let device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001)).ms_os_10_descriptors(0x20);
+        let config = build_device(device, 2);
+
+        assert_eq!(
+            config.string_descriptors[&MS_OS_STRING_INDEX],
+            b"\x12\x03M\0S\0F\0T\x001\x000\x000\0\x20\0"
+        );
+        assert_eq!(
+            response(&config, descriptor_request(Recipient::Device, None, 0x04)),
+            &[
+                0x28, 0x00, 0x00, 0x00, 0x00, 0x01, 0x04, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, // header
+                0x01, 0x01, b'W', b'I', b'N', b'U', b'S', b'B', 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // interface 1
+            ][..]
+        );
+
+        let mut properties = vec![
+            0x92, 0x00, 0x00, 0x00, 0x00, 0x01, 0x05, 0x00, 0x01, 0x00, // header
+            0x88, 0x00, 0x00, 0x00, // dwSize
+            0x07, 0x00, 0x00, 0x00, // dwPropertyDataType: REG_MULTI_SZ
+            0x2a, 0x00, // wPropertyNameLength
+        ];
+        properties.extend(utf16z("DeviceInterfaceGUIDs"));
+        properties.extend_from_slice(&[0x50, 0x00, 0x00, 0x00]); // dwPropertyDataLength
+        properties.extend(utf16z(GUID));
+        properties.extend_from_slice(&[0x00, 0x00]);
+        for &recipient in &[Recipient::Interface, Recipient::Device] {
+            assert_eq!(
+                response(&config, descriptor_request(recipient, Some(1), 0x05)),
+                &properties[..]
+            );
+        }
+    }
+}
@@ -0,0 +1,325 @@
+use crate::builder::DeviceBuilder;
+use crate::control::{ControlRequest, ControlResponse, Recipient, RequestType};
+use crate::endpoint::DeviceAllocator;
+use crate::error::BuilderError;
+use crate::function::{FunctionEndpoints, UsbFunction};
+use crate::EndpointInfo;
+use usb_device::endpoint::EndpointType;
+use usb_device::UsbDirection;
+
+pub const USB_CLASS_PRINTER: u8 = 0x07;
+const PRINTER_SUBCLASS: u8 = 0x01;
+
+pub const PRINTER_REQUEST_GET_DEVICE_ID: u8 = 0x00;
+pub const PRINTER_REQUEST_GET_PORT_STATUS: u8 = 0x01;
+pub const PRINTER_REQUEST_SOFT_RESET: u8 = 0x02;
+
+/// Bits of the GET_PORT_STATUS response.
+pub mod port_status {
+    pub const PAPER_EMPTY: u8 = 1 << 5;
+    pub const SELECTED: u8 = 1 << 4;
+    pub const NOT_ERROR: u8 = 1 << 3;
+}
+
+/// Interface protocol of a printer alternate setting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrinterProtocol {
+    /// Bulk OUT endpoint only
+    Unidirectional = 0x01,
+    /// Bulk OUT and bulk IN endpoints
+    Bidirectional = 0x02,
+    /// Bulk OUT and bulk IN endpoints carrying IEEE 1284.4 packets
+    Ieee1284_4 = 0x03,
+}
+
+/// Settings of a printer interface.
+#[derive(Clone, Debug)]
+pub struct PrinterOptions {
+    pub device_id: String,
+    pub protocols: Vec<PrinterProtocol>,
+    pub port_status: u8,
+    pub interface_string: Option<String>,
+}
+
+impl PrinterOptions {
+    /// Creates printer settings with an IEEE 1284 device ID string, e.g.
+    /// `MFG:Acme;MDL:LaserJet 9000;CMD:PCL,PJL;CLS:PRINTER;`.
+    pub fn new(device_id: impl Into<String>) -> Self {
+        Self {
+            device_id: device_id.into(),
+            protocols: vec![PrinterProtocol::Bidirectional],
+            port_status: port_status::SELECTED | port_status::NOT_ERROR,
+            interface_string: None,
+        }
+    }
+
+    /// Sets the protocols of the alternate settings of the interface, in order.
+    ///
+    /// Default: `[PrinterProtocol::Bidirectional]`
+    pub fn protocols(mut self, protocols: &[PrinterProtocol]) -> Self {
+        self.protocols = protocols.to_vec();
+        self
+    }
+
+    /// Sets the `port_status` bits returned by GET_PORT_STATUS.
+    ///
+    /// Default: `SELECTED | NOT_ERROR`
+    pub fn port_status(mut self, port_status: u8) -> Self {
+        self.port_status = port_status;
+        self
+    }
+
+    /// Sets the interface string descriptor.
+    ///
+    /// Default: (none)
+    pub fn interface_string(mut self, interface_string: impl Into<String>) -> Self {
+        self.interface_string = Some(interface_string.into());
+        self
+    }
+}
+
+/// Adds a printer interface with one alternate setting per protocol. `write_ep` is required if
+/// any protocol is bidirectional.
+///
+/// The generated code answers GET_DEVICE_ID for every alternate setting and GET_PORT_STATUS, and
+/// accepts SOFT_RESET, after which the application has to flush its buffers and reset the bulk
+/// endpoints.
+pub fn create_printer_function(
+    device: &mut DeviceBuilder,
+    options: &PrinterOptions,
+    read_ep: impl EndpointInfo,
+    write_ep: Option<&dyn EndpointInfo>,
+) {
+    let interface = device.next_interface_number();
+    if options.protocols.is_empty() {
+        device.defer_error(BuilderError::InvalidPrinterProtocols {
+            interface,
+            reason: "at least one protocol is required",
+        });
+        return;
+    }
+    let bidirectional = options
+        .protocols
+        .iter()
+        .any(|&p| p != PrinterProtocol::Unidirectional);
+    if bidirectional && write_ep.is_none() {
+        device.defer_error(BuilderError::InvalidPrinterProtocols {
+            interface,
+            reason: "bidirectional protocols require a bulk IN endpoint",
+        });
+        return;
+    }
+    device.check_endpoint(&read_ep, EndpointType::Bulk, UsbDirection::Out);
+    if let Some(write_ep) = write_ep {
+        device.check_endpoint(write_ep, EndpointType::Bulk, UsbDirection::In);
+    }
+
+    let first = device.alloc_interface();
+    let interface_number = first.descriptor.interface_number;
+    let mut interfaces = vec![first.clone()];
+    for _ in 1..options.protocols.len() {
+        interfaces.push(device.alloc_alternate_setting(&first));
+    }
+
+    // GET_DEVICE_ID starts with the big-endian length of the whole response
+    let device_id_length = options.device_id.len() + 2;
+    let mut device_id = vec![(device_id_length >> 8) as u8, device_id_length as u8];
+    device_id.extend_from_slice(options.device_id.as_bytes());
+
+    for (interface, &protocol) in interfaces.into_iter().zip(&options.protocols) {
+        let alternate_setting = interface.descriptor.alternate_setting;
+        let mut interface = interface
+            .interface_class(USB_CLASS_PRINTER)
+            .interface_sub_class(PRINTER_SUBCLASS)
+            .interface_protocol(protocol as u8)
+            .endpoint(read_ep.descriptor().clone());
+        if protocol != PrinterProtocol::Unidirectional {
+            if let Some(write_ep) = write_ep {
+                interface = interface.endpoint(write_ep.descriptor().clone());
+            }
+        }
+        if let Some(interface_string) = &options.interface_string {
+            interface = interface.interface_string(interface_string.as_str());
+        }
+        interface.save(device);
+
+        device.add_control_request(
+            ControlRequest::new(
+                RequestType::Class,
+                Recipient::Interface,
+                PRINTER_REQUEST_GET_DEVICE_ID,
+                ControlResponse::Data(device_id.clone()),
+            )
+            .index(u16::from(interface_number) << 8 | u16::from(alternate_setting)),
+        );
+    }
+
+    device.add_control_request(
+        ControlRequest::new(
+            RequestType::Class,
+            Recipient::Interface,
+            PRINTER_REQUEST_GET_PORT_STATUS,
+            ControlResponse::Data(vec![options.port_status]),
+        )
+        .value(0)
+        .index(interface_number.into()),
+    );
+    // The specification addresses SOFT_RESET to "other", but some hosts send it to the interface
+    for &recipient in &[Recipient::Other, Recipient::Interface] {
+        device.add_control_request(
+            ControlRequest::new(
+                RequestType::Class,
+                recipient,
+                PRINTER_REQUEST_SOFT_RESET,
+                ControlResponse::Accept,
+            )
+            .value(0)
+            .index(interface_number.into()),
+        );
+    }
+}
+
+/// Printer function. The bulk IN endpoint is only allocated if a protocol is bidirectional.
+pub struct PrinterFunction {
+    pub options: PrinterOptions,
+    pub max_packet_size: u16,
+    endpoints: FunctionEndpoints,
+}
+
+impl PrinterFunction {
+    pub fn new(options: PrinterOptions) -> Self {
+        Self {
+            options,
+            max_packet_size: 64,
+            endpoints: FunctionEndpoints::default(),
+        }
+    }
+
+    /// Sets the max packet size of the bulk endpoints.
+    ///
+    /// Default: 64
+    pub fn max_packet_size(mut self, max_packet_size: u16) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+
+    fn bidirectional(&self) -> bool {
+        self.options
+            .protocols
+            .iter()
+            .any(|&p| p != PrinterProtocol::Unidirectional)
+    }
+}
+
+impl UsbFunction for PrinterFunction {
+    fn allocate_endpoints(&mut self, allocator: &mut DeviceAllocator) -> Result<(), BuilderError> {
+        let mps = self.max_packet_size;
+        let mut specs = vec![(UsbDirection::Out, EndpointType::Bulk, mps, 0)];
+        if self.bidirectional() {
+            specs.push((UsbDirection::In, EndpointType::Bulk, mps, 0));
+        }
+        self.endpoints.allocate(allocator, &specs)
+    }
+
+    fn add_to_device(&self, device: &mut DeviceBuilder) -> Result<(), BuilderError> {
+        let count = if self.bidirectional() { 2 } else { 1 };
+        let eps = self.endpoints.get(device, count)?;
+        let write_ep = eps.get(1).map(|ep| ep as &dyn EndpointInfo);
+        create_printer_function(device, &self.options, &eps[0], write_ep);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{DeviceConfig, UsbVidPid};
+    use crate::test_util::{build_function, endpoint};
+
+    const DEVICE_ID: &str = "MFG:Acme;MDL:Printer;CMD:PCL;";
+
+    fn requests(
+        config: &DeviceConfig,
+        request: u8,
+    ) -> Vec<(Recipient, Option<u16>, &ControlResponse)> {
+        config
+            .control_requests
+            .iter()
+            .filter(|r| r.request == request)
+            .map(|r| (r.recipient, r.index, &r.response))
+            .collect()
+    }
+
+    #[test]
+    fn alternate_settings() {
+        let options = PrinterOptions::new(DEVICE_ID).protocols(&[
+            PrinterProtocol::Unidirectional,
+            PrinterProtocol::Bidirectional,
+        ]);
+        let config = build_function(&mut PrinterFunction::new(options)).unwrap();
+
+        assert_eq!(
+            config.configuration_descriptors[0],
+            [
+                0x09, 0x02, 0x30, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, // configuration
+                0x09, 0x04, 0x00, 0x00, 0x01, 0x07, 0x01, 0x01, 0x00, // unidirectional
+                0x07, 0x05, 0x01, 0x02, 0x40, 0x00, 0x00, // bulk OUT endpoint
+                0x09, 0x04, 0x00, 0x01, 0x02, 0x07, 0x01, 0x02, 0x00, // bidirectional
+                0x07, 0x05, 0x01, 0x02, 0x40, 0x00, 0x00, // bulk OUT endpoint
+                0x07, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00, // bulk IN endpoint
+            ][..]
+        );
+
+        let mut device_id = vec![0x00, 0x1f];
+        device_id.extend_from_slice(DEVICE_ID.as_bytes());
+        let get_device_id = requests(&config, PRINTER_REQUEST_GET_DEVICE_ID);
+        assert_eq!(get_device_id.len(), 2);
+        for (i, (recipient, index, response)) in get_device_id.into_iter().enumerate() {
+            assert_eq!((recipient, index), (Recipient::Interface, Some(i as u16)));
+            match response {
+                ControlResponse::Data(data) => assert_eq!(data, &device_id),
+                ControlResponse::Accept => panic!("no data"),
+            }
+        }
+
+        match requests(&config, PRINTER_REQUEST_GET_PORT_STATUS)[..] {
+            [(Recipient::Interface, Some(0), ControlResponse::Data(data))] => {
+                assert_eq!(data, &[0x18])
+            }
+            _ => panic!("unexpected GET_PORT_STATUS requests"),
+        }
+        assert!(matches!(
+            requests(&config, PRINTER_REQUEST_SOFT_RESET)[..],
+            [
+                (Recipient::Other, Some(0), ControlResponse::Accept),
+                (Recipient::Interface, Some(0), ControlResponse::Accept),
+            ]
+        ));
+    }
+
+    #[test]
+    fn invalid_protocols() {
+        let read_ep = endpoint(1, UsbDirection::Out, EndpointType::Bulk, 64, 0);
+
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let options = PrinterOptions::new(DEVICE_ID).protocols(&[]);
+        create_printer_function(&mut device, &options, &read_ep, None);
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::InvalidPrinterProtocols {
+                interface: 0,
+                reason: "at least one protocol is required",
+            }
+        );
+
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        create_printer_function(&mut device, &PrinterOptions::new(DEVICE_ID), &read_ep, None);
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::InvalidPrinterProtocols {
+                interface: 0,
+                reason: "bidirectional protocols require a bulk IN endpoint",
+            }
+        );
+    }
+}
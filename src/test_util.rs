@@ -0,0 +1,56 @@
+//! Fixtures shared by the unit tests.
+
+use crate::builder::{DeviceBuilder, DeviceConfig, EndpointBuilder, UsbVidPid};
+use crate::control::{ControlRequest, ControlResponse};
+use crate::endpoint::{DeviceAllocator, DeviceBuilderEx};
+use crate::error::BuilderError;
+use crate::function::{try_add_functions, UsbFunction};
+use crate::usb::UsbEndpointDescriptor;
+use usb_device::endpoint::EndpointType;
+use usb_device::UsbDirection;
+
+/// Returns an endpoint descriptor to pass to a builder.
+pub fn endpoint(
+    number: u8,
+    direction: UsbDirection,
+    ep_type: EndpointType,
+    max_packet_size: u16,
+    interval: u8,
+) -> UsbEndpointDescriptor {
+    EndpointBuilder::new()
+        .number(number)
+        .direction(direction)
+        .ep_type(ep_type)
+        .max_packet_size(max_packet_size)
+        .interval(interval)
+        .build()
+}
+
+/// Adds `function` to a device with freshly allocated endpoints and builds it.
+pub fn build_function(function: &mut dyn UsbFunction) -> Result<DeviceConfig, BuilderError> {
+    build_device_function(DeviceBuilder::new(UsbVidPid(0x1209, 0x0001)), function)
+}
+
+/// Like `build_function`, with the device settings of `device`.
+pub fn build_device_function(
+    device: DeviceBuilder,
+    function: &mut dyn UsbFunction,
+) -> Result<DeviceConfig, BuilderError> {
+    let mut alloc = DeviceAllocator::new();
+    let mut device = device.try_allocate(&mut alloc).unwrap();
+    try_add_functions(&mut device, &mut alloc, &mut [function])?;
+    device.try_build()
+}
+
+/// Returns the data of the first control request of `config` for which `matches` is `true`.
+pub fn response(config: &DeviceConfig, matches: impl Fn(&ControlRequest) -> bool) -> &[u8] {
+    let request = config
+        .control_requests
+        .iter()
+        .find(|&r| matches(r))
+        .unwrap();
+    match &request.response {
+        ControlResponse::Data(data) => data,
+        ControlResponse::Accept => panic!("no data"),
+    }
+}
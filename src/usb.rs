@@ -1,8 +1,30 @@
+use bit_field::BitField;
 use usb_device::endpoint::EndpointAddress;
 
 /// Maximum number of endpoints in one direction. Specified by the USB specification.
 pub const USB_MAX_ENDPOINTS: usize = 16;
 
+/// Commonly used language IDs for string descriptors
+pub mod lang_id {
+    pub const ENGLISH_US: u16 = 0x0409;
+    pub const ENGLISH_UK: u16 = 0x0809;
+    pub const GERMAN: u16 = 0x0407;
+    pub const FRENCH: u16 = 0x040c;
+    pub const SPANISH: u16 = 0x0c0a;
+    pub const ITALIAN: u16 = 0x0410;
+    pub const JAPANESE: u16 = 0x0411;
+    pub const KOREAN: u16 = 0x0412;
+    pub const CHINESE_SIMPLIFIED: u16 = 0x0804;
+    pub const CHINESE_TRADITIONAL: u16 = 0x0404;
+    pub const RUSSIAN: u16 = 0x0419;
+}
+
+/// String descriptor index reserved for the Microsoft OS string descriptor
+pub const MS_OS_STRING_INDEX: u8 = 0xee;
+
+/// Interface class code of application specific interfaces, like DFU and USBTMC
+pub const USB_CLASS_APPLICATION_SPECIFIC: u8 = 0xfe;
+
 /// Standard descriptor types
 pub enum UsbDescriptorType {
     Device = 1,
@@ -10,10 +32,22 @@ pub enum UsbDescriptorType {
     String = 3,
     Interface = 4,
     Endpoint = 5,
+    InterfaceAssociation = 11,
+    Bos = 15,
+    DeviceCapability = 16,
+    SuperSpeedEndpointCompanion = 48,
+}
+
+/// Device capability types used in the BOS descriptor
+pub enum UsbDeviceCapabilityType {
+    Usb20Extension = 2,
+    ContainerId = 4,
+    Platform = 5,
 }
 
 #[derive(Clone, Debug)]
 pub struct UsbDeviceDescriptor {
+    pub usb_release: u16,
     pub device_class: u8,
     pub device_sub_class: u8,
     pub device_protocol: u8,
@@ -44,18 +78,107 @@ pub struct UsbInterfaceDescriptor {
     pub interface_string: UsbString,
 }
 
+#[derive(Clone, Debug)]
+pub struct UsbInterfaceAssociationDescriptor {
+    pub first_interface: u8,
+    pub interface_count: u8,
+    pub function_class: u8,
+    pub function_sub_class: u8,
+    pub function_protocol: u8,
+    pub function_string: UsbString,
+}
+
 #[derive(Clone, Debug)]
 pub struct UsbEndpointDescriptor {
     pub address: EndpointAddress,
     pub attributes: u8,
     pub max_packet_size: u16,
     pub interval: u8,
+    /// Fields appended to the standard 7 byte descriptor, like `bRefresh` and `bSynchAddress` of
+    /// USB Audio Class 1.0 endpoints.
+    pub extra_fields: Vec<u8>,
+    /// Class-specific descriptors written right after the endpoint descriptor.
+    pub custom_descriptors: Vec<UsbCustomDescriptor>,
 }
 
+impl UsbEndpointDescriptor {
+    /// Appends fields to the standard endpoint descriptor.
+    pub fn extra_fields(mut self, fields: &[u8]) -> Self {
+        self.extra_fields.extend_from_slice(fields);
+        self
+    }
+
+    /// Adds a class-specific descriptor written right after the endpoint descriptor.
+    pub fn descriptor(self, descriptor_type: u8, descriptor: &[u8]) -> Self {
+        self.custom_descriptor(UsbCustomDescriptor::new(descriptor_type, descriptor))
+    }
+
+    /// Adds a class-specific descriptor, that may reference string descriptors, written right
+    /// after the endpoint descriptor.
+    pub fn custom_descriptor(mut self, descriptor: UsbCustomDescriptor) -> Self {
+        self.custom_descriptors.push(descriptor);
+        self
+    }
+
+    /// Adds a SuperSpeed Endpoint Companion descriptor.
+    pub fn superspeed_companion(
+        self,
+        max_burst: u8,
+        attributes: u8,
+        bytes_per_interval: u16,
+    ) -> Self {
+        self.descriptor(
+            UsbDescriptorType::SuperSpeedEndpointCompanion as u8,
+            &[
+                max_burst,  // bMaxBurst
+                attributes, // bmAttributes
+                bytes_per_interval as u8,
+                (bytes_per_interval >> 8) as u8, // wBytesPerInterval
+            ],
+        )
+    }
+}
+
+/// A class-specific or vendor-specific descriptor.
+///
+/// `strings` holds string descriptors referenced by the descriptor: the allocated string index is
+/// written to `data` at the given offset when the descriptor is written.
 #[derive(Clone, Debug)]
 pub struct UsbCustomDescriptor {
     pub descriptor_type: u8,
     pub data: Vec<u8>,
+    pub strings: Vec<(usize, UsbString)>,
+}
+
+impl UsbCustomDescriptor {
+    pub fn new(descriptor_type: u8, data: &[u8]) -> Self {
+        Self {
+            descriptor_type,
+            data: data.to_vec(),
+            strings: Vec::new(),
+        }
+    }
+
+    /// References a string descriptor from the byte at `offset` in `data`.
+    pub fn string(mut self, offset: usize, string: UsbString) -> Self {
+        self.strings.push((offset, string));
+        self
+    }
+}
+
+/// A device capability descriptor included in the Binary Object Store descriptor.
+#[derive(Clone, Debug)]
+pub enum UsbDeviceCapability {
+    /// USB 2.0 Extension. BESL values are in the range 0..=15.
+    Usb20Extension {
+        lpm: bool,
+        baseline_besl: Option<u8>,
+        deep_besl: Option<u8>,
+    },
+    /// Container ID, a UUID in wire byte order that is unique to the device instance.
+    ContainerId([u8; 16]),
+    /// Platform capability identified by a UUID in wire byte order.
+    Platform { uuid: [u8; 16], data: Vec<u8> },
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -67,19 +190,40 @@ pub enum UsbString {
 
 pub struct UsbStringAllocator {
     strings: Vec<UsbString>,
+    reserve_ms_os_string: bool,
+}
+
+impl Default for UsbStringAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl UsbStringAllocator {
     pub fn new() -> Self {
         Self {
             strings: vec![UsbString::None],
+            reserve_ms_os_string: false,
         }
     }
 
+    /// Skips `MS_OS_STRING_INDEX` when allocating strings, which is required when MS OS 1.0
+    /// descriptors are enabled.
+    ///
+    /// Default: `false`
+    pub fn reserve_ms_os_string(mut self, reserve_ms_os_string: bool) -> Self {
+        self.reserve_ms_os_string = reserve_ms_os_string;
+        self
+    }
+
     pub fn alloc(&mut self, string: &UsbString) -> u8 {
         if let Some(index) = self.get_index(string) {
             index
         } else {
+            if self.reserve_ms_os_string && self.strings.len() == MS_OS_STRING_INDEX as usize {
+                // Never hand out the index reserved for the Microsoft OS string descriptor
+                self.strings.push(UsbString::None);
+            }
             let index = self.strings.len() as u8;
             self.strings.push(string.clone());
             index
@@ -106,6 +250,12 @@ pub struct UsbDescriptorWriter {
     num_endpoints_mark: Option<usize>,
 }
 
+impl Default for UsbDescriptorWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl UsbDescriptorWriter {
     pub fn new() -> Self {
         Self {
@@ -127,8 +277,16 @@ impl UsbDescriptorWriter {
         self.buf.len()
     }
 
-    pub fn custom_descriptor(&mut self, descriptor: &UsbCustomDescriptor) {
-        self.write(descriptor.descriptor_type, &descriptor.data);
+    pub fn custom_descriptor(
+        &mut self,
+        descriptor: &UsbCustomDescriptor,
+        alloc: &UsbStringAllocator,
+    ) {
+        let mut data = descriptor.data.clone();
+        for (offset, string) in &descriptor.strings {
+            data[*offset] = alloc.get_index(string).unwrap();
+        }
+        self.write(descriptor.descriptor_type, &data);
     }
 
     pub fn device(
@@ -140,12 +298,12 @@ impl UsbDescriptorWriter {
         self.write(
             UsbDescriptorType::Device as u8,
             &[
-                0x00,
-                0x02,                     // bcdUSB
-                device.device_class,      // bDeviceClass
-                device.device_sub_class,  // bDeviceSubClass
-                device.device_protocol,   // bDeviceProtocol
-                device.max_packet_size_0, // bMaxPacketSize0
+                device.usb_release as u8,
+                (device.usb_release >> 8) as u8, // bcdUSB
+                device.device_class,             // bDeviceClass
+                device.device_sub_class,         // bDeviceSubClass
+                device.device_protocol,          // bDeviceProtocol
+                device.max_packet_size_0,        // bMaxPacketSize0
                 device.vendor_id as u8,
                 (device.vendor_id >> 8) as u8, // idVendor
                 device.product_id as u8,
@@ -187,7 +345,10 @@ impl UsbDescriptorWriter {
     }
 
     pub fn interface(&mut self, interface: &UsbInterfaceDescriptor, alloc: &UsbStringAllocator) {
-        self.buf[self.num_interfaces_mark.unwrap()] += 1;
+        // Alternate settings share the interface number and are not counted separately
+        if interface.alternate_setting == 0 {
+            self.buf[self.num_interfaces_mark.unwrap()] += 1;
+        }
 
         self.num_endpoints_mark = Some(self.position() + 4);
 
@@ -205,21 +366,98 @@ impl UsbDescriptorWriter {
         );
     }
 
-    pub fn endpoint(&mut self, endpoint: &UsbEndpointDescriptor) {
+    pub fn interface_association(
+        &mut self,
+        iad: &UsbInterfaceAssociationDescriptor,
+        alloc: &UsbStringAllocator,
+    ) {
+        self.write(
+            UsbDescriptorType::InterfaceAssociation as u8,
+            &[
+                iad.first_interface,                            // bFirstInterface
+                iad.interface_count,                            // bInterfaceCount
+                iad.function_class,                             // bFunctionClass
+                iad.function_sub_class,                         // bFunctionSubClass
+                iad.function_protocol,                          // bFunctionProtocol
+                alloc.get_index(&iad.function_string).unwrap(), // iFunction
+            ],
+        );
+    }
+
+    pub fn endpoint(&mut self, endpoint: &UsbEndpointDescriptor, alloc: &UsbStringAllocator) {
         self.buf[self.num_endpoints_mark.unwrap()] += 1;
 
         let mps = endpoint.max_packet_size;
 
+        let mut data = vec![
+            endpoint.address.into(), // bEndpointAddress
+            endpoint.attributes,     // bmAttributes
+            mps as u8,
+            (mps >> 8) as u8,  // wMaxPacketSize
+            endpoint.interval, // bInterval
+        ];
+        data.extend_from_slice(&endpoint.extra_fields);
+        self.write(UsbDescriptorType::Endpoint as u8, &data);
+
+        for custom in &endpoint.custom_descriptors {
+            self.custom_descriptor(custom, alloc);
+        }
+    }
+
+    pub fn bos(&mut self, capabilities: &[UsbDeviceCapability]) {
+        let offset = self.position();
         self.write(
-            UsbDescriptorType::Endpoint as u8,
+            UsbDescriptorType::Bos as u8,
             &[
-                endpoint.address.into(), // bEndpointAddress
-                endpoint.attributes, // bmAttributes
-                mps as u8,
-                (mps >> 8) as u8,  // wMaxPacketSize
-                endpoint.interval, // bInterval
+                0,
+                0,                        // wTotalLength
+                capabilities.len() as u8, // bNumDeviceCaps
             ],
         );
+
+        for capability in capabilities {
+            self.device_capability(capability);
+        }
+
+        let length = (self.position() - offset) as u16;
+        self.buf[offset + 2..offset + 4].copy_from_slice(&length.to_le_bytes());
+    }
+
+    fn device_capability(&mut self, capability: &UsbDeviceCapability) {
+        let mut buf = Vec::new();
+        match capability {
+            UsbDeviceCapability::Usb20Extension {
+                lpm,
+                baseline_besl,
+                deep_besl,
+            } => {
+                let mut attributes = 0u32;
+                attributes.set_bit(1, *lpm);
+                attributes.set_bit(2, baseline_besl.is_some() || deep_besl.is_some());
+                if let Some(besl) = baseline_besl {
+                    attributes.set_bit(3, true);
+                    attributes.set_bits(8..12, u32::from(*besl));
+                }
+                if let Some(besl) = deep_besl {
+                    attributes.set_bit(4, true);
+                    attributes.set_bits(12..16, u32::from(*besl));
+                }
+                buf.push(UsbDeviceCapabilityType::Usb20Extension as u8);
+                buf.extend_from_slice(&attributes.to_le_bytes()); // bmAttributes
+            }
+            UsbDeviceCapability::ContainerId(container_id) => {
+                buf.push(UsbDeviceCapabilityType::ContainerId as u8);
+                buf.push(0); // bReserved
+                buf.extend_from_slice(container_id); // ContainerID
+            }
+            UsbDeviceCapability::Platform { uuid, data } => {
+                buf.push(UsbDeviceCapabilityType::Platform as u8);
+                buf.push(0); // bReserved
+                buf.extend_from_slice(uuid); // PlatformCapabilityUUID
+                buf.extend_from_slice(data); // CapabilityData
+            }
+        }
+        self.write(UsbDescriptorType::DeviceCapability as u8, &buf);
     }
 
     pub fn string(&mut self, string: &str) {
@@ -235,3 +473,29 @@ impl UsbDescriptorWriter {
         self.buf
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alloc_strings(alloc: &mut UsbStringAllocator) -> Vec<u8> {
+        (0..MS_OS_STRING_INDEX)
+            .map(|i| alloc.alloc(&UsbString::Const(i.to_string())))
+            .collect()
+    }
+
+    #[test]
+    fn ms_os_string_index() {
+        let indices = alloc_strings(&mut UsbStringAllocator::new());
+        assert_eq!(
+            indices[usize::from(MS_OS_STRING_INDEX) - 1],
+            MS_OS_STRING_INDEX
+        );
+
+        let indices = alloc_strings(&mut UsbStringAllocator::new().reserve_ms_os_string(true));
+        assert_eq!(
+            indices[usize::from(MS_OS_STRING_INDEX) - 1],
+            MS_OS_STRING_INDEX + 1
+        );
+    }
+}
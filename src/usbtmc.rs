@@ -0,0 +1,457 @@
+use crate::builder::DeviceBuilder;
+use crate::control::{ControlRequest, ControlResponse, Recipient, RequestType};
+use crate::endpoint::DeviceAllocator;
+use crate::error::BuilderError;
+use crate::function::{FunctionEndpoints, UsbFunction};
+use crate::usb::USB_CLASS_APPLICATION_SPECIFIC;
+use crate::EndpointInfo;
+use bit_field::BitField;
+use usb_device::endpoint::EndpointType;
+use usb_device::UsbDirection;
+
+pub const USBTMC_SUBCLASS: u8 = 0x03;
+pub const USBTMC_PROTOCOL: u8 = 0x00;
+pub const USBTMC_PROTOCOL_USB488: u8 = 0x01;
+
+pub const USBTMC_REQUEST_INITIATE_ABORT_BULK_OUT: u8 = 1;
+pub const USBTMC_REQUEST_CHECK_ABORT_BULK_OUT_STATUS: u8 = 2;
+pub const USBTMC_REQUEST_INITIATE_ABORT_BULK_IN: u8 = 3;
+pub const USBTMC_REQUEST_CHECK_ABORT_BULK_IN_STATUS: u8 = 4;
+pub const USBTMC_REQUEST_INITIATE_CLEAR: u8 = 5;
+pub const USBTMC_REQUEST_CHECK_CLEAR_STATUS: u8 = 6;
+pub const USBTMC_REQUEST_GET_CAPABILITIES: u8 = 7;
+pub const USBTMC_REQUEST_INDICATOR_PULSE: u8 = 64;
+pub const USB488_REQUEST_READ_STATUS_BYTE: u8 = 128;
+pub const USB488_REQUEST_REN_CONTROL: u8 = 160;
+pub const USB488_REQUEST_GO_TO_LOCAL: u8 = 161;
+pub const USB488_REQUEST_LOCAL_LOCKOUT: u8 = 162;
+
+const STATUS_SUCCESS: u8 = 0x01;
+const STATUS_TRANSFER_NOT_IN_PROGRESS: u8 = 0x81;
+
+/// Settings of a USBTMC interface.
+#[derive(Clone, Debug, Default)]
+pub struct UsbtmcOptions {
+    pub usb488: bool,
+    pub interface_capabilities: u8,
+    pub device_capabilities: u8,
+    pub usb488_interface_capabilities: u8,
+    pub usb488_device_capabilities: u8,
+    pub interface_string: Option<String>,
+}
+
+impl UsbtmcOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Uses the USB488 subclass protocol, declaring a IEEE 488.2 interface.
+    ///
+    /// Default: `false`
+    pub fn usb488(mut self, usb488: bool) -> Self {
+        self.usb488 = usb488;
+        self.usb488_interface_capabilities.set_bit(2, usb488);
+        self
+    }
+
+    /// Sets whether the interface supports INDICATOR_PULSE.
+    ///
+    /// Default: `false`
+    pub fn indicator_pulse(mut self, indicator_pulse: bool) -> Self {
+        self.interface_capabilities.set_bit(2, indicator_pulse);
+        self
+    }
+
+    /// Sets whether the interface is talk-only, i.e. never receives device-dependent messages.
+    ///
+    /// Default: `false`
+    pub fn talk_only(mut self, talk_only: bool) -> Self {
+        self.interface_capabilities.set_bit(1, talk_only);
+        self
+    }
+
+    /// Sets whether the interface is listen-only, i.e. never sends device-dependent messages.
+    ///
+    /// Default: `false`
+    pub fn listen_only(mut self, listen_only: bool) -> Self {
+        self.interface_capabilities.set_bit(0, listen_only);
+        self
+    }
+
+    /// Sets whether the device supports ending bulk IN transfers on a termination character.
+    ///
+    /// Default: `false`
+    pub fn term_char(mut self, term_char: bool) -> Self {
+        self.device_capabilities.set_bit(0, term_char);
+        self
+    }
+
+    /// Sets whether the USB488 interface supports REN_CONTROL, GO_TO_LOCAL and LOCAL_LOCKOUT,
+    /// and the device the RL1 remote/local capability.
+    ///
+    /// Default: `false`
+    pub fn ren_control(mut self, ren_control: bool) -> Self {
+        self.usb488_interface_capabilities.set_bit(1, ren_control);
+        self.usb488_device_capabilities.set_bit(1, ren_control);
+        self
+    }
+
+    /// Sets whether the USB488 interface accepts TRIGGER messages, and the device has the DT1
+    /// device trigger capability.
+    ///
+    /// Default: `false`
+    pub fn trigger(mut self, trigger: bool) -> Self {
+        self.usb488_interface_capabilities.set_bit(0, trigger);
+        self.usb488_device_capabilities.set_bit(0, trigger);
+        self
+    }
+
+    /// Sets whether the USB488 device understands all mandatory SCPI commands.
+    ///
+    /// Default: `false`
+    pub fn scpi(mut self, scpi: bool) -> Self {
+        self.usb488_device_capabilities.set_bit(3, scpi);
+        self
+    }
+
+    /// Sets the interface string descriptor.
+    ///
+    /// Default: (none)
+    pub fn interface_string(mut self, interface_string: impl Into<String>) -> Self {
+        self.interface_string = Some(interface_string.into());
+        self
+    }
+
+    fn validate(&self, interface: u8) -> Result<(), BuilderError> {
+        if self.interface_capabilities.get_bits(0..2) == 0b11 {
+            return Err(BuilderError::InvalidUsbtmcCapabilities {
+                interface,
+                reason: "an interface cannot be both talk-only and listen-only",
+            });
+        }
+        if !self.usb488
+            && (self.usb488_interface_capabilities != 0 || self.usb488_device_capabilities != 0)
+        {
+            return Err(BuilderError::InvalidUsbtmcCapabilities {
+                interface,
+                reason: "USB488 capabilities require the USB488 protocol",
+            });
+        }
+        Ok(())
+    }
+
+    fn capabilities(&self, service_request: bool) -> Vec<u8> {
+        let mut capabilities = vec![
+            STATUS_SUCCESS,
+            0,
+            0x00,
+            0x01,                        // bcdUSBTMC
+            self.interface_capabilities, // USBTMC interface capabilities
+            self.device_capabilities,    // USBTMC device capabilities
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        if self.usb488 {
+            let mut device_capabilities = self.usb488_device_capabilities;
+            device_capabilities.set_bit(2, service_request);
+            capabilities.extend_from_slice(&[
+                0x00,
+                0x01,                               // bcdUSB488
+                self.usb488_interface_capabilities, // USB488 interface capabilities
+                device_capabilities,                // USB488 device capabilities
+            ]);
+        }
+        capabilities.resize(0x18, 0);
+        capabilities
+    }
+}
+
+/// Adds a USBTMC interface with bulk endpoints and an optional interrupt IN endpoint. With the
+/// USB488 protocol, the interrupt endpoint gives the device the SR1 service request capability.
+///
+/// The generated code answers GET_CAPABILITIES from the declared capabilities. The abort and
+/// clear requests are answered as if no transfer was in progress, READ_STATUS_BYTE of USB488
+/// with a zero status byte, and INDICATOR_PULSE, REN_CONTROL, GO_TO_LOCAL and LOCAL_LOCKOUT with
+/// success if they are supported.
+pub fn create_usbtmc_function(
+    device: &mut DeviceBuilder,
+    options: &UsbtmcOptions,
+    read_ep: impl EndpointInfo,
+    write_ep: impl EndpointInfo,
+    interrupt_ep: Option<&dyn EndpointInfo>,
+) {
+    if let Err(e) = options.validate(device.next_interface_number()) {
+        device.defer_error(e);
+        return;
+    }
+    device.check_endpoint(&read_ep, EndpointType::Bulk, UsbDirection::Out);
+    device.check_endpoint(&write_ep, EndpointType::Bulk, UsbDirection::In);
+    if let Some(interrupt_ep) = interrupt_ep {
+        device.check_endpoint(interrupt_ep, EndpointType::Interrupt, UsbDirection::In);
+    }
+
+    let protocol = if options.usb488 {
+        USBTMC_PROTOCOL_USB488
+    } else {
+        USBTMC_PROTOCOL
+    };
+    let mut interface = device
+        .alloc_interface()
+        .interface_class(USB_CLASS_APPLICATION_SPECIFIC)
+        .interface_sub_class(USBTMC_SUBCLASS)
+        .interface_protocol(protocol)
+        .endpoint(read_ep.descriptor().clone())
+        .endpoint(write_ep.descriptor().clone());
+    if let Some(interrupt_ep) = interrupt_ep {
+        interface = interface.endpoint(interrupt_ep.descriptor().clone());
+    }
+    if let Some(interface_string) = &options.interface_string {
+        interface = interface.interface_string(interface_string.as_str());
+    }
+    let interface_number = u16::from(interface.descriptor.interface_number);
+    interface.save(device);
+
+    let read_address = u16::from(u8::from(read_ep.address()));
+    let write_address = u16::from(u8::from(write_ep.address()));
+    let endpoint_requests = [
+        (
+            USBTMC_REQUEST_INITIATE_ABORT_BULK_OUT,
+            read_address,
+            vec![STATUS_TRANSFER_NOT_IN_PROGRESS, 0],
+        ),
+        (
+            USBTMC_REQUEST_CHECK_ABORT_BULK_OUT_STATUS,
+            read_address,
+            vec![STATUS_SUCCESS, 0, 0, 0, 0, 0, 0, 0],
+        ),
+        (
+            USBTMC_REQUEST_INITIATE_ABORT_BULK_IN,
+            write_address,
+            vec![STATUS_TRANSFER_NOT_IN_PROGRESS, 0],
+        ),
+        (
+            USBTMC_REQUEST_CHECK_ABORT_BULK_IN_STATUS,
+            write_address,
+            vec![STATUS_SUCCESS, 0, 0, 0, 0, 0, 0, 0],
+        ),
+    ];
+    for (request, address, data) in endpoint_requests.iter().cloned() {
+        device.add_control_request(
+            ControlRequest::new(
+                RequestType::Class,
+                Recipient::Endpoint,
+                request,
+                ControlResponse::Data(data),
+            )
+            .index(address),
+        );
+    }
+
+    let mut interface_requests = vec![
+        (USBTMC_REQUEST_INITIATE_CLEAR, vec![STATUS_SUCCESS]),
+        (USBTMC_REQUEST_CHECK_CLEAR_STATUS, vec![STATUS_SUCCESS, 0]),
+        (
+            USBTMC_REQUEST_GET_CAPABILITIES,
+            options.capabilities(interrupt_ep.is_some()),
+        ),
+    ];
+    if options.usb488 {
+        // USBTMC status, bTag and status byte
+        interface_requests.push((USB488_REQUEST_READ_STATUS_BYTE, vec![STATUS_SUCCESS, 0, 0]));
+    }
+    if options.interface_capabilities.get_bit(2) {
+        interface_requests.push((USBTMC_REQUEST_INDICATOR_PULSE, vec![STATUS_SUCCESS]));
+    }
+    if options.usb488_interface_capabilities.get_bit(1) {
+        interface_requests.push((USB488_REQUEST_REN_CONTROL, vec![STATUS_SUCCESS]));
+        interface_requests.push((USB488_REQUEST_GO_TO_LOCAL, vec![STATUS_SUCCESS]));
+        interface_requests.push((USB488_REQUEST_LOCAL_LOCKOUT, vec![STATUS_SUCCESS]));
+    }
+    for (request, data) in interface_requests {
+        device.add_control_request(
+            ControlRequest::new(
+                RequestType::Class,
+                Recipient::Interface,
+                request,
+                ControlResponse::Data(data),
+            )
+            .index(interface_number),
+        );
+    }
+}
+
+/// USBTMC function. The interrupt IN endpoint for USB488 notifications is only allocated if
+/// `interrupt_endpoint` is set.
+pub struct UsbtmcFunction {
+    pub options: UsbtmcOptions,
+    pub max_packet_size: u16,
+    pub interrupt_endpoint: bool,
+    endpoints: FunctionEndpoints,
+}
+
+impl UsbtmcFunction {
+    pub fn new(options: UsbtmcOptions) -> Self {
+        Self {
+            options,
+            max_packet_size: 64,
+            interrupt_endpoint: false,
+            endpoints: FunctionEndpoints::default(),
+        }
+    }
+
+    /// Sets the max packet size of the bulk endpoints.
+    ///
+    /// Default: 64
+    pub fn max_packet_size(mut self, max_packet_size: u16) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+
+    /// Sets whether the interface has an interrupt IN endpoint.
+    ///
+    /// Default: `false`
+    pub fn interrupt_endpoint(mut self, interrupt_endpoint: bool) -> Self {
+        self.interrupt_endpoint = interrupt_endpoint;
+        self
+    }
+}
+
+impl UsbFunction for UsbtmcFunction {
+    fn allocate_endpoints(&mut self, allocator: &mut DeviceAllocator) -> Result<(), BuilderError> {
+        let mps = self.max_packet_size;
+        let mut specs = vec![
+            (UsbDirection::Out, EndpointType::Bulk, mps, 0),
+            (UsbDirection::In, EndpointType::Bulk, mps, 0),
+        ];
+        if self.interrupt_endpoint {
+            // USB488 notifications are 2 bytes long
+            specs.push((UsbDirection::In, EndpointType::Interrupt, 2, 1));
+        }
+        self.endpoints.allocate(allocator, &specs)
+    }
+
+    fn add_to_device(&self, device: &mut DeviceBuilder) -> Result<(), BuilderError> {
+        let count = if self.interrupt_endpoint { 3 } else { 2 };
+        let eps = self.endpoints.get(device, count)?;
+        let interrupt_ep = eps.get(2).map(|ep| ep as &dyn EndpointInfo);
+        create_usbtmc_function(device, &self.options, &eps[0], &eps[1], interrupt_ep);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{build_function, response};
+
+    #[test]
+    fn usb488_function() {
+        let options = UsbtmcOptions::new()
+            .usb488(true)
+            .indicator_pulse(true)
+            .ren_control(true)
+            .trigger(true)
+            .scpi(true);
+        let mut usbtmc = UsbtmcFunction::new(options).interrupt_endpoint(true);
+        let config = build_function(&mut usbtmc).unwrap();
+
+        let expected: &[u8] = &[
+            0x09, 0x02, 0x27, 0x00, 0x01, 0x01, 0x00, 0x80, 0x32, // configuration
+            0x09, 0x04, 0x00, 0x00, 0x03, 0xfe, 0x03, 0x01, 0x00, // USB488 interface
+            0x07, 0x05, 0x01, 0x02, 0x40, 0x00, 0x00, // bulk OUT endpoint
+            0x07, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00, // bulk IN endpoint
+            0x07, 0x05, 0x82, 0x03, 0x02, 0x00, 0x01, // interrupt IN endpoint
+        ];
+        assert_eq!(config.configuration_descriptors[0], expected);
+
+        let capabilities: &[u8] = &[
+            0x01, 0x00, 0x00, 0x01, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // USBTMC
+            0x00, 0x01, 0x07, 0x0f, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // USB488
+        ];
+        assert_eq!(
+            response(&config, |r| r.request == USBTMC_REQUEST_GET_CAPABILITIES),
+            capabilities
+        );
+        for &request in &[
+            USBTMC_REQUEST_INITIATE_CLEAR,
+            USBTMC_REQUEST_INDICATOR_PULSE,
+            USB488_REQUEST_REN_CONTROL,
+            USB488_REQUEST_GO_TO_LOCAL,
+            USB488_REQUEST_LOCAL_LOCKOUT,
+        ] {
+            assert_eq!(
+                response(&config, |r| r.request == request),
+                [STATUS_SUCCESS]
+            );
+        }
+        assert_eq!(
+            response(&config, |r| r.request == USB488_REQUEST_READ_STATUS_BYTE),
+            [STATUS_SUCCESS, 0, 0]
+        );
+    }
+
+    #[test]
+    fn abort_and_clear_requests() {
+        let mut usbtmc = UsbtmcFunction::new(UsbtmcOptions::new());
+        let config = build_function(&mut usbtmc).unwrap();
+
+        assert_eq!(config.configuration_descriptors[0][2], 0x20);
+        let aborts: Vec<_> = config
+            .control_requests
+            .iter()
+            .filter(|r| r.recipient == Recipient::Endpoint)
+            .map(|r| (r.request, r.index))
+            .collect();
+        assert_eq!(
+            aborts,
+            [
+                (USBTMC_REQUEST_INITIATE_ABORT_BULK_OUT, Some(0x01)),
+                (USBTMC_REQUEST_CHECK_ABORT_BULK_OUT_STATUS, Some(0x01)),
+                (USBTMC_REQUEST_INITIATE_ABORT_BULK_IN, Some(0x81)),
+                (USBTMC_REQUEST_CHECK_ABORT_BULK_IN_STATUS, Some(0x81)),
+            ]
+        );
+        assert_eq!(
+            response(&config, |r| r.request
+                == USBTMC_REQUEST_INITIATE_ABORT_BULK_IN),
+            [STATUS_TRANSFER_NOT_IN_PROGRESS, 0]
+        );
+        assert_eq!(
+            response(&config, |r| r.request == USBTMC_REQUEST_CHECK_CLEAR_STATUS),
+            [STATUS_SUCCESS, 0]
+        );
+        // READ_STATUS_BYTE is only answered with the USB488 protocol
+        assert!(!config
+            .control_requests
+            .iter()
+            .any(|r| r.request == USB488_REQUEST_READ_STATUS_BYTE));
+        let capabilities = response(&config, |r| r.request == USBTMC_REQUEST_GET_CAPABILITIES);
+        assert_eq!(capabilities.len(), 0x18);
+        assert!(capabilities[12..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn invalid_capabilities() {
+        let options = UsbtmcOptions::new().talk_only(true).listen_only(true);
+        assert_eq!(
+            build_function(&mut UsbtmcFunction::new(options)).unwrap_err(),
+            BuilderError::InvalidUsbtmcCapabilities {
+                interface: 0,
+                reason: "an interface cannot be both talk-only and listen-only",
+            }
+        );
+
+        let options = UsbtmcOptions::new().scpi(true);
+        assert_eq!(
+            build_function(&mut UsbtmcFunction::new(options)).unwrap_err(),
+            BuilderError::InvalidUsbtmcCapabilities {
+                interface: 0,
+                reason: "USB488 capabilities require the USB488 protocol",
+            }
+        );
+    }
+}
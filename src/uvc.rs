@@ -0,0 +1,768 @@
+use crate::builder::DeviceBuilder;
+use crate::endpoint::DeviceAllocator;
+use crate::error::BuilderError;
+use crate::function::{FunctionEndpoints, UsbFunction};
+use crate::usb::UsbEndpointDescriptor;
+use crate::EndpointInfo;
+use std::convert::TryFrom;
+use usb_device::endpoint::EndpointType;
+use usb_device::UsbDirection;
+
+pub const USB_CLASS_VIDEO: u8 = 0x0e;
+pub const VIDEO_SUBCLASS_CONTROL: u8 = 0x01;
+pub const VIDEO_SUBCLASS_STREAMING: u8 = 0x02;
+const VIDEO_SUBCLASS_INTERFACE_COLLECTION: u8 = 0x03;
+const VIDEO_PROTOCOL_UNDEFINED: u8 = 0x00;
+
+const CS_INTERFACE: u8 = 0x24;
+const VC_HEADER: u8 = 0x01;
+const VC_INPUT_TERMINAL: u8 = 0x02;
+const VC_OUTPUT_TERMINAL: u8 = 0x03;
+const VC_PROCESSING_UNIT: u8 = 0x05;
+const VS_INPUT_HEADER: u8 = 0x01;
+const VS_FORMAT_UNCOMPRESSED: u8 = 0x04;
+const VS_FRAME_UNCOMPRESSED: u8 = 0x05;
+const VS_FORMAT_MJPEG: u8 = 0x06;
+const VS_FRAME_MJPEG: u8 = 0x07;
+const VS_COLORFORMAT: u8 = 0x0d;
+
+const ITT_CAMERA: u16 = 0x0201;
+const TT_STREAMING: u16 = 0x0101;
+
+const ISO_SYNC_ASYNCHRONOUS: u8 = 0b01 << 2;
+
+/// Bits per pixel assumed for the bit rates and frame buffer size of MJPEG frames
+const MJPEG_BITS_PER_PIXEL: u8 = 16;
+
+/// Entity ID of the camera terminal
+pub const CAMERA_TERMINAL_ID: u8 = 1;
+/// Entity ID of the processing unit
+pub const PROCESSING_UNIT_ID: u8 = 2;
+/// Entity ID of the output terminal linked to the video streaming interface
+pub const OUTPUT_TERMINAL_ID: u8 = 3;
+
+/// Controls of the camera terminal, used in `bmControls`.
+pub mod camera_control {
+    pub const SCANNING_MODE: u32 = 1;
+    pub const AUTO_EXPOSURE_MODE: u32 = 1 << 1;
+    pub const AUTO_EXPOSURE_PRIORITY: u32 = 1 << 2;
+    pub const EXPOSURE_TIME_ABSOLUTE: u32 = 1 << 3;
+    pub const EXPOSURE_TIME_RELATIVE: u32 = 1 << 4;
+    pub const FOCUS_ABSOLUTE: u32 = 1 << 5;
+    pub const FOCUS_RELATIVE: u32 = 1 << 6;
+    pub const IRIS_ABSOLUTE: u32 = 1 << 7;
+    pub const IRIS_RELATIVE: u32 = 1 << 8;
+    pub const ZOOM_ABSOLUTE: u32 = 1 << 9;
+    pub const ZOOM_RELATIVE: u32 = 1 << 10;
+    pub const PAN_TILT_ABSOLUTE: u32 = 1 << 11;
+    pub const PAN_TILT_RELATIVE: u32 = 1 << 12;
+    pub const ROLL_ABSOLUTE: u32 = 1 << 13;
+    pub const ROLL_RELATIVE: u32 = 1 << 14;
+    pub const FOCUS_AUTO: u32 = 1 << 17;
+    pub const PRIVACY: u32 = 1 << 18;
+}
+
+/// Controls of the processing unit, used in `bmControls`.
+pub mod processing_control {
+    pub const BRIGHTNESS: u16 = 1;
+    pub const CONTRAST: u16 = 1 << 1;
+    pub const HUE: u16 = 1 << 2;
+    pub const SATURATION: u16 = 1 << 3;
+    pub const SHARPNESS: u16 = 1 << 4;
+    pub const GAMMA: u16 = 1 << 5;
+    pub const WHITE_BALANCE_TEMPERATURE: u16 = 1 << 6;
+    pub const WHITE_BALANCE_COMPONENT: u16 = 1 << 7;
+    pub const BACKLIGHT_COMPENSATION: u16 = 1 << 8;
+    pub const GAIN: u16 = 1 << 9;
+    pub const POWER_LINE_FREQUENCY: u16 = 1 << 10;
+    pub const HUE_AUTO: u16 = 1 << 11;
+    pub const WHITE_BALANCE_TEMPERATURE_AUTO: u16 = 1 << 12;
+    pub const WHITE_BALANCE_COMPONENT_AUTO: u16 = 1 << 13;
+    pub const DIGITAL_MULTIPLIER: u16 = 1 << 14;
+    pub const DIGITAL_MULTIPLIER_LIMIT: u16 = 1 << 15;
+}
+
+/// GUIDs of uncompressed video formats, in the byte order of `guidFormat`.
+pub mod format_guid {
+    pub const YUY2: [u8; 16] = [
+        b'Y', b'U', b'Y', b'2', 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b,
+        0x71,
+    ];
+    pub const NV12: [u8; 16] = [
+        b'N', b'V', b'1', b'2', 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b,
+        0x71,
+    ];
+}
+
+/// A frame size with its discrete frame intervals.
+#[derive(Clone, Debug)]
+pub struct VideoFrame {
+    pub width: u16,
+    pub height: u16,
+    pub intervals: Vec<u32>,
+}
+
+impl VideoFrame {
+    pub fn new(width: u16, height: u16) -> Self {
+        Self {
+            width,
+            height,
+            intervals: Vec::new(),
+        }
+    }
+
+    /// Adds a frame interval in 100 ns units. The first interval is the default one.
+    pub fn interval(mut self, interval: u32) -> Self {
+        self.intervals.push(interval);
+        self
+    }
+
+    /// Adds the frame interval of a frame rate in frames per second. A frame rate of 0 adds an
+    /// invalid interval of 0.
+    pub fn frame_rate(self, frames_per_second: u32) -> Self {
+        let interval = 10_000_000u32.checked_div(frames_per_second).unwrap_or(0);
+        self.interval(interval)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VideoFormatKind {
+    Uncompressed { guid: [u8; 16], bits_per_pixel: u8 },
+    Mjpeg,
+}
+
+/// A video format with its frame sizes. The first frame is the default one.
+#[derive(Clone, Debug)]
+pub struct VideoFormat {
+    pub kind: VideoFormatKind,
+    pub frames: Vec<VideoFrame>,
+}
+
+impl VideoFormat {
+    pub fn uncompressed(guid: [u8; 16], bits_per_pixel: u8) -> Self {
+        Self {
+            kind: VideoFormatKind::Uncompressed {
+                guid,
+                bits_per_pixel,
+            },
+            frames: Vec::new(),
+        }
+    }
+
+    /// Packed YUV 4:2:2
+    pub fn yuy2() -> Self {
+        Self::uncompressed(format_guid::YUY2, 16)
+    }
+
+    /// Planar YUV 4:2:0
+    pub fn nv12() -> Self {
+        Self::uncompressed(format_guid::NV12, 12)
+    }
+
+    pub fn mjpeg() -> Self {
+        Self {
+            kind: VideoFormatKind::Mjpeg,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn frame(mut self, frame: VideoFrame) -> Self {
+        self.frames.push(frame);
+        self
+    }
+
+    fn bits_per_pixel(&self) -> u8 {
+        match self.kind {
+            VideoFormatKind::Uncompressed { bits_per_pixel, .. } => bits_per_pixel,
+            VideoFormatKind::Mjpeg => MJPEG_BITS_PER_PIXEL,
+        }
+    }
+
+    /// Returns the format descriptor followed by its frame descriptors and the color matching
+    /// descriptor, or the reason why the format is invalid.
+    fn descriptors(&self, format_index: u8) -> Result<Vec<Vec<u8>>, &'static str> {
+        if self.frames.is_empty() || self.frames.len() > 255 {
+            return Err("a format must have 1 to 255 frames");
+        }
+
+        let mut format = match self.kind {
+            VideoFormatKind::Uncompressed {
+                guid,
+                bits_per_pixel,
+            } => {
+                let mut format = vec![
+                    VS_FORMAT_UNCOMPRESSED,
+                    format_index,
+                    self.frames.len() as u8, // bNumFrameDescriptors
+                ];
+                format.extend_from_slice(&guid); // guidFormat
+                format.push(bits_per_pixel); // bBitsPerPixel
+                format
+            }
+            VideoFormatKind::Mjpeg => vec![
+                VS_FORMAT_MJPEG,
+                format_index,
+                self.frames.len() as u8, // bNumFrameDescriptors
+                0x01,                    // bmFlags: fixed size samples
+            ],
+        };
+        format.extend_from_slice(&[
+            1, // bDefaultFrameIndex
+            0, // bAspectRatioX
+            0, // bAspectRatioY
+            0, // bmInterlaceFlags
+            0, // bCopyProtect
+        ]);
+
+        let frame_subtype = match self.kind {
+            VideoFormatKind::Uncompressed { .. } => VS_FRAME_UNCOMPRESSED,
+            VideoFormatKind::Mjpeg => VS_FRAME_MJPEG,
+        };
+        let mut descriptors = vec![format];
+        for (i, frame) in self.frames.iter().enumerate() {
+            descriptors.push(self.frame_descriptor(frame_subtype, i as u8 + 1, frame)?);
+        }
+        descriptors.push(vec![
+            VS_COLORFORMAT,
+            1, // bColorPrimaries: BT.709, sRGB
+            1, // bTransferCharacteristics: BT.709
+            4, // bMatrixCoefficients: SMPTE 170M
+        ]);
+        Ok(descriptors)
+    }
+
+    fn frame_descriptor(
+        &self,
+        subtype: u8,
+        frame_index: u8,
+        frame: &VideoFrame,
+    ) -> Result<Vec<u8>, &'static str> {
+        if frame.intervals.is_empty() || frame.intervals.len() > 255 {
+            return Err("a frame must have 1 to 255 frame intervals");
+        }
+        if frame.intervals.contains(&0) {
+            return Err("frame intervals must not be zero");
+        }
+
+        let frame_bits =
+            u64::from(frame.width) * u64::from(frame.height) * u64::from(self.bits_per_pixel());
+        let to_u32 = |value: u64, reason| u32::try_from(value).map_err(|_| reason);
+        let bit_rate = |interval: u32| {
+            to_u32(
+                frame_bits * 10_000_000 / u64::from(interval),
+                "bit rate doesn't fit in 32 bits",
+            )
+        };
+        let max_interval = *frame.intervals.iter().max().unwrap();
+        let min_interval = *frame.intervals.iter().min().unwrap();
+        let buffer_size = to_u32(frame_bits / 8, "frame size doesn't fit in 32 bits")?;
+
+        let mut descriptor = vec![
+            subtype,
+            frame_index,
+            0, // bmCapabilities
+        ];
+        descriptor.extend_from_slice(&frame.width.to_le_bytes()); // wWidth
+        descriptor.extend_from_slice(&frame.height.to_le_bytes()); // wHeight
+        descriptor.extend_from_slice(&bit_rate(max_interval)?.to_le_bytes()); // dwMinBitRate
+        descriptor.extend_from_slice(&bit_rate(min_interval)?.to_le_bytes()); // dwMaxBitRate
+        descriptor.extend_from_slice(&buffer_size.to_le_bytes()); // dwMaxVideoFrameBufferSize
+        descriptor.extend_from_slice(&frame.intervals[0].to_le_bytes()); // dwDefaultFrameInterval
+        descriptor.push(frame.intervals.len() as u8); // bFrameIntervalType
+        for interval in &frame.intervals {
+            descriptor.extend_from_slice(&interval.to_le_bytes()); // dwFrameInterval
+        }
+        Ok(descriptor)
+    }
+}
+
+/// Settings of a USB Video Class function.
+#[derive(Clone, Debug)]
+pub struct UvcOptions {
+    pub camera_controls: u32,
+    pub processing_controls: u16,
+    pub clock_frequency: u32,
+    pub formats: Vec<VideoFormat>,
+    pub iso_max_packet_sizes: Vec<u16>,
+    pub function_string: Option<String>,
+}
+
+impl Default for UvcOptions {
+    fn default() -> Self {
+        Self {
+            camera_controls: 0,
+            processing_controls: 0,
+            clock_frequency: 48_000_000,
+            formats: Vec::new(),
+            iso_max_packet_sizes: Vec::new(),
+            function_string: None,
+        }
+    }
+}
+
+impl UvcOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the supported `camera_control` bits of the camera terminal.
+    ///
+    /// Default: 0
+    pub fn camera_controls(mut self, controls: u32) -> Self {
+        self.camera_controls = controls;
+        self
+    }
+
+    /// Sets the supported `processing_control` bits of the processing unit.
+    ///
+    /// Default: 0
+    pub fn processing_controls(mut self, controls: u16) -> Self {
+        self.processing_controls = controls;
+        self
+    }
+
+    /// Sets the device clock frequency in Hz used in the VideoControl header.
+    ///
+    /// Default: 48 MHz
+    pub fn clock_frequency(mut self, clock_frequency: u32) -> Self {
+        self.clock_frequency = clock_frequency;
+        self
+    }
+
+    /// Adds a video format. Format indexes are assigned in order, starting with 1.
+    pub fn format(mut self, format: VideoFormat) -> Self {
+        self.formats.push(format);
+        self
+    }
+
+    /// Sets the max packet sizes of the alternate settings of an isochronous streaming interface,
+    /// so that the host can choose one that fits the available bandwidth. None of them may exceed
+    /// the max packet size of the endpoint.
+    ///
+    /// Default: (none), a single alternate setting with the max packet size of the endpoint
+    pub fn iso_max_packet_sizes(mut self, max_packet_sizes: &[u16]) -> Self {
+        self.iso_max_packet_sizes = max_packet_sizes.to_vec();
+        self
+    }
+
+    /// Sets the string descriptor of the interface association.
+    ///
+    /// Default: (none)
+    pub fn function_string(mut self, function_string: impl Into<String>) -> Self {
+        self.function_string = Some(function_string.into());
+        self
+    }
+}
+
+/// Adds a USB Video Class function: an interface association, a VideoControl interface with a
+/// camera terminal, a processing unit and an output terminal, and a VideoStreaming interface with
+/// all `formats` of `options`.
+///
+/// With an isochronous `ep`, the streaming interface has an empty alternate setting 0 followed by
+/// an alternate setting with the endpoint for each of the `iso_max_packet_sizes` of `options`.
+/// With a bulk `ep`, the endpoint is in alternate setting 0. The probe and commit controls have
+/// to be handled by the application.
+pub fn create_uvc_function(
+    device: &mut DeviceBuilder,
+    options: &UvcOptions,
+    ep: impl EndpointInfo,
+) {
+    if let Err(e) = add_uvc_function(device, options, ep.descriptor()) {
+        device.defer_error(e);
+    }
+}
+
+fn add_uvc_function(
+    device: &mut DeviceBuilder,
+    options: &UvcOptions,
+    ep: &UsbEndpointDescriptor,
+) -> Result<(), BuilderError> {
+    // The formats are described in the streaming interface, allocated after the control interface
+    let streaming_interface = device.next_interface_number() + 1;
+    let invalid_format = |reason| BuilderError::InvalidVideoFormat {
+        interface: streaming_interface,
+        reason,
+    };
+    if options.formats.is_empty() || options.formats.len() > 255 {
+        return Err(invalid_format(
+            "a video function must have 1 to 255 formats",
+        ));
+    }
+    let isochronous = ep.ep_type() == EndpointType::Isochronous;
+    let ep_type = if isochronous {
+        EndpointType::Isochronous
+    } else {
+        EndpointType::Bulk
+    };
+    device.check_endpoint(ep, ep_type, UsbDirection::In);
+
+    if !isochronous && !options.iso_max_packet_sizes.is_empty() {
+        return Err(invalid_format(
+            "max packet sizes of alternate settings require an isochronous endpoint",
+        ));
+    }
+    let packet_sizes = if options.iso_max_packet_sizes.is_empty() {
+        vec![ep.max_packet_size]
+    } else {
+        options.iso_max_packet_sizes.clone()
+    };
+    if let Some(&size) = packet_sizes
+        .iter()
+        .find(|&&size| size == 0 || size > ep.max_packet_size)
+    {
+        return Err(BuilderError::InvalidMaxPacketSize {
+            address: ep.address.into(),
+            max_packet_size: size,
+        });
+    }
+
+    let mut format_descriptors = Vec::new();
+    for (i, format) in options.formats.iter().enumerate() {
+        format_descriptors.extend(format.descriptors(i as u8 + 1).map_err(invalid_format)?);
+    }
+
+    let function = device.alloc_function();
+    let control_if = device.alloc_interface();
+    let streaming_if = device.alloc_interface();
+    let streaming_if_id = streaming_if.descriptor.interface_number;
+
+    let camera_controls = options.camera_controls.to_le_bytes();
+    let processing_controls = options.processing_controls.to_le_bytes();
+    let entities = [
+        vec![
+            VC_INPUT_TERMINAL,
+            CAMERA_TERMINAL_ID,
+            ITT_CAMERA as u8,
+            (ITT_CAMERA >> 8) as u8, // wTerminalType
+            0,                       // bAssocTerminal
+            0,                       // iTerminal
+            0,
+            0, // wObjectiveFocalLengthMin
+            0,
+            0, // wObjectiveFocalLengthMax
+            0,
+            0, // wOcularFocalLength
+            3, // bControlSize
+            camera_controls[0],
+            camera_controls[1],
+            camera_controls[2], // bmControls
+        ],
+        vec![
+            VC_PROCESSING_UNIT,
+            PROCESSING_UNIT_ID,
+            CAMERA_TERMINAL_ID, // bSourceID
+            0,
+            0, // wMaxMultiplier
+            2, // bControlSize
+            processing_controls[0],
+            processing_controls[1], // bmControls
+            0,                      // iProcessing
+            0,                      // bmVideoStandards
+        ],
+        vec![
+            VC_OUTPUT_TERMINAL,
+            OUTPUT_TERMINAL_ID,
+            TT_STREAMING as u8,
+            (TT_STREAMING >> 8) as u8, // wTerminalType
+            0,                         // bAssocTerminal
+            PROCESSING_UNIT_ID,        // bSourceID
+            0,                         // iTerminal
+        ],
+    ];
+
+    // The total lengths include the header and the 2 byte prefix of each descriptor
+    let vc_total_length = 13 + entities.iter().map(|d| d.len() + 2).sum::<usize>();
+    let mut vc_header = vec![
+        VC_HEADER,
+        0x10,
+        0x01, // bcdUVC
+        vc_total_length as u8,
+        (vc_total_length >> 8) as u8, // wTotalLength
+    ];
+    vc_header.extend_from_slice(&options.clock_frequency.to_le_bytes()); // dwClockFrequency
+    vc_header.extend_from_slice(&[
+        1,               // bInCollection
+        streaming_if_id, // baInterfaceNr
+    ]);
+
+    let mut control_if = control_if
+        .interface_class(USB_CLASS_VIDEO)
+        .interface_sub_class(VIDEO_SUBCLASS_CONTROL)
+        .interface_protocol(VIDEO_PROTOCOL_UNDEFINED)
+        .descriptor(CS_INTERFACE, &vc_header);
+    for entity in &entities {
+        control_if = control_if.descriptor(CS_INTERFACE, entity);
+    }
+    control_if.save(device);
+
+    let format_count = options.formats.len();
+    let vs_total_length = 13
+        + format_count
+        + format_descriptors
+            .iter()
+            .map(|d| d.len() + 2)
+            .sum::<usize>();
+    let mut input_header = vec![
+        VS_INPUT_HEADER,
+        format_count as u8, // bNumFormats
+        vs_total_length as u8,
+        (vs_total_length >> 8) as u8, // wTotalLength
+        u8::from(ep.address),         // bEndpointAddress
+        0,                            // bmInfo
+        OUTPUT_TERMINAL_ID,           // bTerminalLink
+        0,                            // bStillCaptureMethod
+        0,                            // bTriggerSupport
+        0,                            // bTriggerUsage
+        1,                            // bControlSize
+    ];
+    input_header.resize(input_header.len() + format_count, 0); // bmaControls
+
+    let mut endpoint = ep.clone();
+    if isochronous {
+        endpoint.attributes = (endpoint.attributes & 0b11) | ISO_SYNC_ASYNCHRONOUS;
+    }
+
+    // The class-specific descriptors are part of alternate setting 0
+    let alternate_ifs: Vec<_> = if isochronous {
+        packet_sizes
+            .iter()
+            .map(|_| device.alloc_alternate_setting(&streaming_if))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let mut streaming_if = streaming_if
+        .interface_class(USB_CLASS_VIDEO)
+        .interface_sub_class(VIDEO_SUBCLASS_STREAMING)
+        .interface_protocol(VIDEO_PROTOCOL_UNDEFINED)
+        .descriptor(CS_INTERFACE, &input_header);
+    for descriptor in &format_descriptors {
+        streaming_if = streaming_if.descriptor(CS_INTERFACE, descriptor);
+    }
+    if isochronous {
+        streaming_if.save(device);
+        for (alternate_if, &max_packet_size) in alternate_ifs.into_iter().zip(&packet_sizes) {
+            let mut endpoint = endpoint.clone();
+            endpoint.max_packet_size = max_packet_size;
+            alternate_if
+                .interface_class(USB_CLASS_VIDEO)
+                .interface_sub_class(VIDEO_SUBCLASS_STREAMING)
+                .interface_protocol(VIDEO_PROTOCOL_UNDEFINED)
+                .endpoint(endpoint)
+                .save(device);
+        }
+    } else {
+        streaming_if.endpoint(endpoint).save(device);
+    }
+
+    let mut function = function
+        .function_class(USB_CLASS_VIDEO)
+        .function_sub_class(VIDEO_SUBCLASS_INTERFACE_COLLECTION)
+        .function_protocol(VIDEO_PROTOCOL_UNDEFINED);
+    if let Some(function_string) = &options.function_string {
+        function = function.function_string(function_string.as_str());
+    }
+    function.save(device);
+
+    Ok(())
+}
+
+/// USB Video Class function with a single video streaming IN endpoint. The isochronous endpoint
+/// is sized for the largest of `iso_max_packet_sizes`.
+pub struct UvcFunction {
+    pub options: UvcOptions,
+    pub ep_type: EndpointType,
+    pub max_packet_size: u16,
+    endpoints: FunctionEndpoints,
+}
+
+impl UvcFunction {
+    pub fn new(options: UvcOptions) -> Self {
+        Self {
+            options,
+            ep_type: EndpointType::Isochronous,
+            max_packet_size: 256,
+            endpoints: FunctionEndpoints::default(),
+        }
+    }
+
+    /// Uses a bulk endpoint instead of an isochronous one.
+    ///
+    /// Default: `false`
+    pub fn bulk(mut self, bulk: bool) -> Self {
+        self.ep_type = if bulk {
+            EndpointType::Bulk
+        } else {
+            EndpointType::Isochronous
+        };
+        self
+    }
+
+    /// Sets the max packet size of the video endpoint. An isochronous endpoint is allocated with
+    /// the largest of the `iso_max_packet_sizes` of the options instead, if there are any.
+    ///
+    /// Default: 256
+    pub fn max_packet_size(mut self, max_packet_size: u16) -> Self {
+        self.max_packet_size = max_packet_size;
+        self
+    }
+}
+
+impl UsbFunction for UvcFunction {
+    fn allocate_endpoints(&mut self, allocator: &mut DeviceAllocator) -> Result<(), BuilderError> {
+        let (max_packet_size, interval) = if self.ep_type == EndpointType::Isochronous {
+            let largest = self.options.iso_max_packet_sizes.iter().copied().max();
+            (largest.unwrap_or(self.max_packet_size), 1)
+        } else {
+            (self.max_packet_size, 0)
+        };
+        self.endpoints.allocate(
+            allocator,
+            &[(UsbDirection::In, self.ep_type, max_packet_size, interval)],
+        )
+    }
+
+    fn add_to_device(&self, device: &mut DeviceBuilder) -> Result<(), BuilderError> {
+        let eps = self.endpoints.get(device, 1)?;
+        create_uvc_function(device, &self.options, &eps[0]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::UsbVidPid;
+    use crate::test_util::endpoint;
+
+    fn options() -> UvcOptions {
+        UvcOptions::new()
+            .format(VideoFormat::mjpeg().frame(VideoFrame::new(320, 240).frame_rate(30)))
+    }
+
+    #[test]
+    fn iso_alternate_settings() {
+        let options = options().iso_max_packet_sizes(&[512, 1024]);
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        create_uvc_function(
+            &mut device,
+            &options,
+            endpoint(1, UsbDirection::In, EndpointType::Isochronous, 1024, 1),
+        );
+        let config = device.build();
+
+        let expected: &[u8] = &[
+            0x09, 0x02, 0xb4, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32, // configuration
+            0x08, 0x0b, 0x00, 0x02, 0x0e, 0x03, 0x00, 0x00, // interface association
+            0x09, 0x04, 0x00, 0x00, 0x00, 0x0e, 0x01, 0x00, 0x00, // video control interface
+            0x0d, 0x24, 0x01, 0x10, 0x01, 0x34, 0x00, // header
+            0x00, 0x6c, 0xdc, 0x02, 0x01, 0x01, // clock frequency, streaming interfaces
+            0x12, 0x24, 0x02, 0x01, 0x01, 0x02, 0x00, 0x00, 0x00, 0x00, // camera terminal
+            0x00, 0x00, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, // camera terminal controls
+            0x0c, 0x24, 0x05, 0x02, 0x01, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x00, // unit
+            0x09, 0x24, 0x03, 0x03, 0x01, 0x01, 0x00, 0x02, 0x00, // output terminal
+            0x09, 0x04, 0x01, 0x00, 0x00, 0x0e, 0x02, 0x00, 0x00, // streaming interface
+            0x0e, 0x24, 0x01, 0x01, 0x3d, 0x00, 0x81, 0x00, 0x03, 0x00, 0x00, 0x00, 0x01,
+            0x00, // input header
+            0x0b, 0x24, 0x06, 0x01, 0x01, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, // format
+            0x1e, 0x24, 0x07, 0x01, 0x00, 0x40, 0x01, 0xf0, 0x00, // frame, 320x240
+            0x24, 0x80, 0x32, 0x02, 0x24, 0x80, 0x32, 0x02, // bit rates
+            0x00, 0x58, 0x02, 0x00, 0x15, 0x16, 0x05, 0x00, // buffer size, default interval
+            0x01, 0x15, 0x16, 0x05, 0x00, // frame intervals
+            0x06, 0x24, 0x0d, 0x01, 0x01, 0x04, // color matching
+            0x09, 0x04, 0x01, 0x01, 0x01, 0x0e, 0x02, 0x00, 0x00, // alternate setting 1
+            0x07, 0x05, 0x81, 0x05, 0x00, 0x02, 0x01, // endpoint, 512 bytes
+            0x09, 0x04, 0x01, 0x02, 0x01, 0x0e, 0x02, 0x00, 0x00, // alternate setting 2
+            0x07, 0x05, 0x81, 0x05, 0x00, 0x04, 0x01, // endpoint, 1024 bytes
+        ];
+        assert_eq!(config.configuration_descriptors[0], expected);
+
+        // The endpoint is configured for the largest alternate setting
+        let ep = config.endpoints.iter().find(|ep| ep.address == 0x81.into());
+        assert_eq!(ep.map(|ep| ep.max_packet_size), Some(1024));
+    }
+
+    #[test]
+    fn bulk_streaming() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        create_uvc_function(
+            &mut device,
+            &options(),
+            endpoint(1, UsbDirection::In, EndpointType::Bulk, 64, 1),
+        );
+        let config = device.build();
+
+        let descriptor = &config.configuration_descriptors[0];
+        assert_eq!(descriptor[2..4], [0x9b, 0x00]);
+        assert_eq!(
+            descriptor[descriptor.len() - 7..],
+            [0x07, 0x05, 0x81, 0x02, 0x40, 0x00, 0x01]
+        );
+    }
+
+    #[test]
+    fn invalid_packet_sizes() {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        let options = options().iso_max_packet_sizes(&[512, 1024]);
+        create_uvc_function(
+            &mut device,
+            &options,
+            endpoint(1, UsbDirection::In, EndpointType::Isochronous, 512, 1),
+        );
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::InvalidMaxPacketSize {
+                address: 0x81,
+                max_packet_size: 1024,
+            }
+        );
+
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+        create_uvc_function(
+            &mut device,
+            &options,
+            endpoint(1, UsbDirection::In, EndpointType::Bulk, 64, 1),
+        );
+        assert_eq!(
+            device.try_build().unwrap_err(),
+            BuilderError::InvalidVideoFormat {
+                interface: 1,
+                reason: "max packet sizes of alternate settings require an isochronous endpoint",
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_frames() {
+        let frames = [
+            (
+                VideoFrame::new(320, 240).frame_rate(0),
+                "frame intervals must not be zero",
+            ),
+            (
+                VideoFrame::new(1920, 1080).interval(1),
+                "bit rate doesn't fit in 32 bits",
+            ),
+            (
+                VideoFrame::new(320, 240),
+                "a frame must have 1 to 255 frame intervals",
+            ),
+        ];
+        for (frame, reason) in frames.iter().cloned() {
+            let options = UvcOptions::new().format(VideoFormat::yuy2().frame(frame));
+            let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001));
+            create_uvc_function(
+                &mut device,
+                &options,
+                endpoint(1, UsbDirection::In, EndpointType::Isochronous, 1024, 1),
+            );
+            assert_eq!(
+                device.try_build().unwrap_err(),
+                BuilderError::InvalidVideoFormat {
+                    interface: 1,
+                    reason
+                }
+            );
+        }
+    }
+}
@@ -0,0 +1,370 @@
+use crate::builder::ConfigurationBuilder;
+use crate::control::{ControlRequest, ControlResponse, Recipient, RequestType};
+use crate::error::BuilderError;
+use crate::usb::UsbDeviceCapability;
+
+/// WebUSB platform capability UUID {3408B638-09A9-47A0-8BFD-A0768815B665} in wire byte order
+const WEBUSB_PLATFORM_UUID: [u8; 16] = [
+    0x38, 0xb6, 0x08, 0x34, 0xa9, 0x09, 0xa0, 0x47, 0x8b, 0xfd, 0xa0, 0x76, 0x88, 0x15, 0xb6, 0x65,
+];
+
+/// `wIndex` of the vendor request that returns the allowed origins
+pub const WEBUSB_GET_ALLOWED_ORIGINS: u16 = 0x01;
+
+/// `wIndex` of the vendor request that returns a URL descriptor
+pub const WEBUSB_GET_URL: u16 = 0x02;
+
+const WEBUSB_DESCRIPTOR_SET_HEADER: u8 = 0x00;
+const WEBUSB_CONFIGURATION_SUBSET_HEADER: u8 = 0x01;
+const WEBUSB_FUNCTION_SUBSET_HEADER: u8 = 0x02;
+const WEBUSB_URL: u8 = 0x03;
+
+/// Maximum length of the URL in a URL descriptor, limited by `bLength`
+const MAX_URL_LENGTH: usize = 255 - 3;
+
+/// Maximum number of origins in a function subset header, limited by `bLength`
+const MAX_FUNCTION_ORIGINS: usize = 255 - 3;
+
+/// WebUSB options: the vendor request code, the landing page and the allowed origins.
+///
+/// URLs are numbered from 1 in the order: landing page, allowed origins.
+#[derive(Clone, Debug)]
+pub struct WebUsbOptions {
+    pub vendor_code: u8,
+    pub landing_page: Option<String>,
+    pub allowed_origins: Vec<String>,
+}
+
+impl WebUsbOptions {
+    pub fn new(vendor_code: u8) -> Self {
+        Self {
+            vendor_code,
+            landing_page: None,
+            allowed_origins: Vec::new(),
+        }
+    }
+
+    /// Sets the landing page that the browser suggests when the device is connected.
+    ///
+    /// Default: (none)
+    pub fn landing_page(mut self, url: impl Into<String>) -> Self {
+        self.landing_page = Some(url.into());
+        self
+    }
+
+    /// Adds an origin, like `https://example.com:8080`, that is allowed to access the device.
+    ///
+    /// The allowed origins are listed for every function of every configuration.
+    pub fn allowed_origin(mut self, origin: impl Into<String>) -> Self {
+        self.allowed_origins.push(origin.into());
+        self
+    }
+}
+
+/// Encodes a URL as a WebUSB URL descriptor, replacing a `http://` or `https://` prefix with the
+/// scheme code.
+fn url_descriptor(url: &str) -> Result<Vec<u8>, BuilderError> {
+    let (scheme, rest) = if let Some(rest) = url.strip_prefix("http://") {
+        (0, rest)
+    } else if let Some(rest) = url.strip_prefix("https://") {
+        (1, rest)
+    } else {
+        (255, url)
+    };
+
+    let invalid = |reason| BuilderError::InvalidWebUsbUrl {
+        url: url.to_string(),
+        reason,
+    };
+    if rest.is_empty() {
+        return Err(invalid("URL is empty"));
+    }
+    if rest.len() > MAX_URL_LENGTH {
+        return Err(invalid("URL is too long"));
+    }
+
+    let mut buf = vec![(rest.len() + 3) as u8, WEBUSB_URL, scheme];
+    buf.extend_from_slice(rest.as_bytes());
+    Ok(buf)
+}
+
+fn validate_origin(origin: &str, previous: &[String]) -> Result<(), BuilderError> {
+    let invalid = |reason| BuilderError::InvalidWebUsbOrigin {
+        origin: origin.to_string(),
+        reason,
+    };
+
+    let authority = origin
+        .strip_prefix("https://")
+        .or_else(|| origin.strip_prefix("http://"))
+        .ok_or_else(|| invalid("scheme must be http or https"))?;
+    if authority.contains(['/', '?', '#']) {
+        return Err(invalid("origin can't have a path, query or fragment"));
+    }
+    if authority.contains('@') {
+        return Err(invalid("origin can't have user information"));
+    }
+    let host = match authority.rfind(':') {
+        Some(i) if !authority.ends_with(']') => {
+            let port = &authority[i + 1..];
+            if port.parse::<u16>().is_err() {
+                return Err(invalid("invalid port"));
+            }
+            &authority[..i]
+        }
+        _ => authority,
+    };
+    if host.is_empty() {
+        return Err(invalid("host is empty"));
+    }
+    if previous.iter().any(|o| o == origin) {
+        return Err(invalid("origin is listed twice"));
+    }
+    Ok(())
+}
+
+/// Returns the first interface of each function of a configuration: the first interface of each
+/// interface association, and each interface that is not part of one.
+fn function_interfaces(configuration: &ConfigurationBuilder) -> Vec<u8> {
+    configuration
+        .interfaces
+        .iter()
+        .filter(|i| i.descriptor.alternate_setting == 0)
+        .map(|i| i.descriptor.interface_number)
+        .filter(|&number| {
+            !configuration.functions.iter().any(|f| {
+                number > f.first_interface && number - f.first_interface < f.interface_count
+            })
+        })
+        .collect()
+}
+
+/// Returns the WebUSB descriptor set returned by GET_ALLOWED_ORIGINS: a configuration subset for
+/// each configuration, with a function subset listing `origins` for each function.
+fn allowed_origins(configurations: &[ConfigurationBuilder], origins: &[u8]) -> Vec<u8> {
+    let mut set = vec![
+        5,                            // bLength
+        WEBUSB_DESCRIPTOR_SET_HEADER, // bDescriptorType
+        0,
+        0,                          // wTotalLength
+        configurations.len() as u8, // bNumConfigurations
+    ];
+    for configuration in configurations {
+        let functions = function_interfaces(configuration);
+        set.extend_from_slice(&[
+            4,                                            // bLength
+            WEBUSB_CONFIGURATION_SUBSET_HEADER,           // bDescriptorType
+            configuration.descriptor.configuration_value, // bConfigurationValue
+            functions.len() as u8,                        // bNumFunctions
+        ]);
+        for first_interface in functions {
+            set.extend_from_slice(&[
+                (3 + origins.len()) as u8,     // bLength
+                WEBUSB_FUNCTION_SUBSET_HEADER, // bDescriptorType
+                first_interface,               // bFirstInterfaceNumber
+            ]);
+            set.extend_from_slice(origins); // iOrigin
+        }
+    }
+    let total_length = set.len() as u16;
+    set[2..4].copy_from_slice(&total_length.to_le_bytes());
+    set
+}
+
+/// Returns the BOS platform capability and the vendor requests that serve the URLs and the
+/// allowed origins.
+pub(crate) fn webusb_descriptors(
+    options: &WebUsbOptions,
+    configurations: &[ConfigurationBuilder],
+) -> Result<(UsbDeviceCapability, Vec<ControlRequest>), BuilderError> {
+    for (i, origin) in options.allowed_origins.iter().enumerate() {
+        validate_origin(origin, &options.allowed_origins[..i])?;
+    }
+
+    let urls: Vec<&String> = options
+        .landing_page
+        .iter()
+        .chain(options.allowed_origins.iter())
+        .collect();
+    if urls.len() > 255 {
+        return Err(BuilderError::InvalidWebUsbOrigin {
+            origin: urls[255].clone(),
+            reason: "too many URLs",
+        });
+    }
+
+    let mut requests = Vec::new();
+    for (i, url) in urls.iter().enumerate() {
+        let index = (i + 1) as u16;
+        requests.push(
+            ControlRequest::new(
+                RequestType::Vendor,
+                Recipient::Device,
+                options.vendor_code,
+                ControlResponse::Data(url_descriptor(url)?),
+            )
+            .value(index)
+            .index(WEBUSB_GET_URL),
+        );
+    }
+
+    if !options.allowed_origins.is_empty() {
+        let first_origin = urls.len() - options.allowed_origins.len() + 1;
+        let origins: Vec<u8> = (first_origin..=urls.len()).map(|i| i as u8).collect();
+        if origins.len() > MAX_FUNCTION_ORIGINS {
+            return Err(BuilderError::InvalidWebUsbOrigin {
+                origin: options.allowed_origins[MAX_FUNCTION_ORIGINS].clone(),
+                reason: "too many origins",
+            });
+        }
+        requests.push(
+            ControlRequest::new(
+                RequestType::Vendor,
+                Recipient::Device,
+                options.vendor_code,
+                ControlResponse::Data(allowed_origins(configurations, &origins)),
+            )
+            .index(WEBUSB_GET_ALLOWED_ORIGINS),
+        );
+    }
+
+    let landing_page = if options.landing_page.is_some() { 1 } else { 0 };
+    let capability = UsbDeviceCapability::Platform {
+        uuid: WEBUSB_PLATFORM_UUID,
+        data: vec![
+            0x00,
+            0x01,                // bcdVersion
+            options.vendor_code, // bVendorCode
+            landing_page,        // iLandingPage
+        ],
+    };
+
+    Ok((capability, requests))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::builder::{DeviceBuilder, DeviceConfig, UsbVidPid};
+    use crate::test_util::response;
+
+    fn build_device(options: WebUsbOptions) -> Result<DeviceConfig, BuilderError> {
+        let mut device = DeviceBuilder::new(UsbVidPid(0x1209, 0x0001)).webusb(options);
+        let function = device.alloc_function();
+        device
+            .alloc_interface()
+            .interface_class(0xff)
+            .save(&mut device);
+        device
+            .alloc_interface()
+            .interface_class(0xff)
+            .save(&mut device);
+        function.function_class(0xff).save(&mut device);
+        device
+            .alloc_interface()
+            .interface_class(0xff)
+            .save(&mut device);
+        device.try_build()
+    }
+
+    /// Matches the WebUSB requests for the descriptor with the given `wValue` and `wIndex`.
+    fn webusb_request(value: Option<u16>, index: u16) -> impl Fn(&ControlRequest) -> bool {
+        move |r| r.request == 0x42 && r.value == value && r.index == Some(index)
+    }
+
+    #[test]
+    fn landing_page() {
+        let options = WebUsbOptions::new(0x42).landing_page("https://example.com");
+        let config = build_device(options).unwrap();
+
+        assert_eq!(config.device_descriptor[2..4], [0x10, 0x02]);
+        let mut bos = vec![
+            0x05, 0x0f, 0x1d, 0x00, 0x01, // BOS
+            0x18, 0x10, 0x05, 0x00, // platform capability
+        ];
+        bos.extend_from_slice(&WEBUSB_PLATFORM_UUID);
+        bos.extend_from_slice(&[0x00, 0x01, 0x42, 0x01]);
+        assert_eq!(config.bos_descriptor, Some(bos));
+        assert_eq!(
+            response(&config, webusb_request(Some(1), WEBUSB_GET_URL)),
+            b"\x0e\x03\x01example.com"
+        );
+    }
+
+    #[test]
+    fn url_schemes() {
+        assert_eq!(
+            url_descriptor("http://localhost:8000").unwrap(),
+            b"\x11\x03\x00localhost:8000"
+        );
+        assert_eq!(
+            url_descriptor("file:///index.html").unwrap(),
+            b"\x15\x03\xfffile:///index.html"
+        );
+        assert!(url_descriptor("https://").is_err());
+    }
+
+    #[test]
+    fn allowed_origins() {
+        let options = WebUsbOptions::new(0x42)
+            .landing_page("https://example.com")
+            .allowed_origin("https://example.com")
+            .allowed_origin("http://localhost:8000");
+        let config = build_device(options).unwrap();
+
+        assert_eq!(
+            response(&config, webusb_request(Some(2), WEBUSB_GET_URL)),
+            b"\x0e\x03\x01example.com"
+        );
+        assert_eq!(
+            response(&config, webusb_request(Some(3), WEBUSB_GET_URL)),
+            b"\x11\x03\x00localhost:8000"
+        );
+        assert_eq!(
+            response(&config, webusb_request(None, WEBUSB_GET_ALLOWED_ORIGINS)),
+            [
+                0x05, 0x00, 0x13, 0x00, 0x01, // descriptor set header
+                0x04, 0x01, 0x01, 0x02, // configuration subset header, configuration 1
+                0x05, 0x02, 0x00, 0x02, 0x03, // function subset header, interfaces 0-1
+                0x05, 0x02, 0x02, 0x02, 0x03, // function subset header, interface 2
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_origins() {
+        for &(origin, reason) in &[
+            ("example.com", "scheme must be http or https"),
+            (
+                "https://example.com/",
+                "origin can't have a path, query or fragment",
+            ),
+            (
+                "https://user@example.com",
+                "origin can't have user information",
+            ),
+            ("https://example.com:http", "invalid port"),
+            ("https://:80", "host is empty"),
+        ] {
+            let options = WebUsbOptions::new(0x42).allowed_origin(origin);
+            assert_eq!(
+                build_device(options).unwrap_err(),
+                BuilderError::InvalidWebUsbOrigin {
+                    origin: origin.to_string(),
+                    reason,
+                }
+            );
+        }
+
+        let options = WebUsbOptions::new(0x42)
+            .allowed_origin("https://example.com")
+            .allowed_origin("https://example.com");
+        assert_eq!(
+            build_device(options).unwrap_err(),
+            BuilderError::InvalidWebUsbOrigin {
+                origin: "https://example.com".to_string(),
+                reason: "origin is listed twice",
+            }
+        );
+    }
+}